@@ -0,0 +1,27 @@
+//! Records which commit the server was built from, and when, for the
+//! `/version` route. Builds without the git history, such as in a container,
+//! can pass `GIT_HASH` in instead.
+
+use std::process::Command;
+
+fn main() {
+	let hash = std::env::var("GIT_HASH").ok().or_else(|| {
+		Command::new("git")
+			.args(&["rev-parse", "--short", "HEAD"])
+			.output()
+			.ok()
+			.filter(|output| output.status.success())
+			.and_then(|output| String::from_utf8(output.stdout).ok())
+	});
+
+	println!(
+		"cargo:rustc-env=GIT_HASH={}",
+		hash.as_deref().unwrap_or("unknown").trim()
+	);
+	println!(
+		"cargo:rustc-env=BUILD_TIME={}",
+		chrono::Utc::now().to_rfc3339()
+	);
+	println!("cargo:rerun-if-env-changed=GIT_HASH");
+	println!("cargo:rerun-if-changed=../.git/logs/HEAD");
+}
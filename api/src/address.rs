@@ -0,0 +1,252 @@
+use juniper::{FieldError, GraphQLEnum, Object, Value};
+use std::fmt;
+
+/// Offline postcode/locality dataset in the same `postcode,locality,state`
+/// layout as the Australia Post postcode file. Only postcodes that appear in
+/// the dataset are checked for suburb consistency, so it can be swapped for
+/// the full file without any code changes.
+const POSTCODES : &str = include_str!("../data/postcodes.csv");
+
+/// ISO 3166-1 alpha-2 code for addresses that are posted domestically
+pub const DOMESTIC_COUNTRY : &str = "AU";
+
+/// Normalise a country to its uppercase ISO 3166-1 alpha-2 code
+pub fn parse_country(country : &str) -> Result<String, AddressError> {
+	let country = country.trim().to_uppercase();
+
+	if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+		Ok(country)
+	} else {
+		Err(AddressError::new(
+			"country",
+			AddressErrorCode::InvalidCountry,
+			"Country must be a 2 letter ISO country code",
+		))
+	}
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AustralianState {
+	Nsw,
+	Vic,
+	Qld,
+	Sa,
+	Wa,
+	Tas,
+	Nt,
+	Act,
+}
+
+impl AustralianState {
+	/// Parse either the abbreviation or the full name of a state, ignoring
+	/// case, spacing and full stops. "NSW", "nsw", "N.S.W." and "New South
+	/// Wales" are all the same state.
+	pub fn parse(state : &str) -> Option<Self> {
+		let normalised = state
+			.chars()
+			.filter(|c| c.is_alphanumeric())
+			.collect::<String>()
+			.to_uppercase();
+
+		match normalised.as_str() {
+			"NSW" | "NEWSOUTHWALES" => Some(AustralianState::Nsw),
+			"VIC" | "VICTORIA" => Some(AustralianState::Vic),
+			"QLD" | "QUEENSLAND" => Some(AustralianState::Qld),
+			"SA" | "SOUTHAUSTRALIA" => Some(AustralianState::Sa),
+			"WA" | "WESTERNAUSTRALIA" => Some(AustralianState::Wa),
+			"TAS" | "TASMANIA" => Some(AustralianState::Tas),
+			"NT" | "NORTHERNTERRITORY" => Some(AustralianState::Nt),
+			"ACT" | "AUSTRALIANCAPITALTERRITORY" => Some(AustralianState::Act),
+			_ => None,
+		}
+	}
+
+	/// The abbreviation that is stored in the database
+	pub fn code(&self) -> &'static str {
+		match self {
+			AustralianState::Nsw => "NSW",
+			AustralianState::Vic => "VIC",
+			AustralianState::Qld => "QLD",
+			AustralianState::Sa => "SA",
+			AustralianState::Wa => "WA",
+			AustralianState::Tas => "TAS",
+			AustralianState::Nt => "NT",
+			AustralianState::Act => "ACT",
+		}
+	}
+
+	/// Postcode ranges allocated to the state by Australia Post, including
+	/// PO box and large volume receiver ranges
+	fn post_code_ranges(&self) -> &'static [(u16, u16)] {
+		match self {
+			AustralianState::Nsw => &[(1000, 1999), (2000, 2599), (2619, 2899), (2921, 2999)],
+			AustralianState::Vic => &[(3000, 3999), (8000, 8999)],
+			AustralianState::Qld => &[(4000, 4999), (9000, 9999)],
+			AustralianState::Sa => &[(5000, 5999)],
+			AustralianState::Wa => &[(6000, 6999)],
+			AustralianState::Tas => &[(7000, 7999)],
+			AustralianState::Nt => &[(800, 999)],
+			AustralianState::Act => &[(200, 299), (2600, 2618), (2900, 2920)],
+		}
+	}
+
+	pub fn contains(&self, post_code : &PostCode) -> bool {
+		let value = post_code.value();
+		self.post_code_ranges()
+			.iter()
+			.any(|(from, to)| *from <= value && value <= *to)
+	}
+}
+
+impl fmt::Display for AustralianState {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.code()) }
+}
+
+/// A four digit Australian postcode, or an overseas postal code. Kept as a
+/// string so leading zeros (NT and some ACT postcodes) survive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostCode(String);
+
+impl PostCode {
+	pub fn parse(post_code : &str) -> Result<Self, AddressError> {
+		let post_code = post_code.trim();
+
+		if post_code.len() == 4 && post_code.chars().all(|c| c.is_ascii_digit()) {
+			Ok(PostCode(post_code.to_string()))
+		} else {
+			Err(AddressError::new(
+				"postCode",
+				AddressErrorCode::InvalidPostCode,
+				"Postcode must be 4 digits",
+			))
+		}
+	}
+
+	/// Overseas postal codes vary in format, so only check they are short and
+	/// made up of letters, digits, spaces and dashes. Some countries do not
+	/// use postal codes at all.
+	pub fn international(post_code : &str) -> Result<Self, AddressError> {
+		let post_code = post_code.trim().to_uppercase();
+
+		if post_code.len() <= 10
+			&& post_code
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-')
+		{
+			Ok(PostCode(post_code))
+		} else {
+			Err(AddressError::new(
+				"postCode",
+				AddressErrorCode::InvalidPostCode,
+				"Postal code is not valid",
+			))
+		}
+	}
+
+	/// Postcodes stored before they were strings lost their leading zeros
+	pub fn from_legacy(post_code : i32) -> Self { PostCode(format!("{:04}", post_code)) }
+
+	pub fn as_str(&self) -> &str { &self.0 }
+
+	fn value(&self) -> u16 { self.0.parse().unwrap_or(0) }
+
+	/// Localities the dataset lists for this postcode, if it is known
+	fn localities(&self) -> Vec<&'static str> {
+		POSTCODES
+			.lines()
+			.skip(1)
+			.filter_map(|line| {
+				let mut fields = line.split(',');
+				match (fields.next(), fields.next()) {
+					(Some(code), Some(locality)) if code == self.0 => Some(locality),
+					_ => None,
+				}
+			})
+			.collect()
+	}
+}
+
+impl fmt::Display for PostCode {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressErrorCode {
+	Required,
+	InvalidCountry,
+	InvalidState,
+	InvalidPostCode,
+	PostCodeNotInState,
+	TownNotInPostCode,
+}
+
+impl AddressErrorCode {
+	pub fn code(&self) -> &'static str {
+		match self {
+			AddressErrorCode::Required => "REQUIRED",
+			AddressErrorCode::InvalidCountry => "INVALID_COUNTRY",
+			AddressErrorCode::InvalidState => "INVALID_STATE",
+			AddressErrorCode::InvalidPostCode => "INVALID_POST_CODE",
+			AddressErrorCode::PostCodeNotInState => "POST_CODE_NOT_IN_STATE",
+			AddressErrorCode::TownNotInPostCode => "TOWN_NOT_IN_POST_CODE",
+		}
+	}
+}
+
+/// A single problem with one field of an address
+#[derive(Clone, Debug)]
+pub struct AddressError {
+	pub field :   &'static str,
+	pub code :    AddressErrorCode,
+	pub message : String,
+}
+
+impl AddressError {
+	pub fn new(field : &'static str, code : AddressErrorCode, message : &str) -> Self {
+		Self {
+			field,
+			code,
+			message : message.to_string(),
+		}
+	}
+
+	/// All of the problems with an address as a single GraphQL error, so the
+	/// form can highlight every field at once
+	pub fn to_field_error(errors : &[AddressError]) -> FieldError {
+		let errors = errors
+			.iter()
+			.map(|error| {
+				let mut e = Object::with_capacity(3);
+				e.add_field("field", Value::scalar(error.field));
+				e.add_field("code", Value::scalar(error.code.code()));
+				e.add_field("message", Value::scalar(error.message.as_str()));
+				Value::object(e)
+			})
+			.collect();
+
+		let mut extensions = Object::with_capacity(2);
+		extensions.add_field("type", Value::scalar("INVALID_ADDRESS"));
+		extensions.add_field("errors", Value::list(errors));
+
+		FieldError::new("The address is not valid", Value::object(extensions))
+	}
+}
+
+/// Check that the locality is one the dataset lists for the postcode.
+/// Postcodes missing from the dataset can not be checked and are accepted.
+pub fn town_matches_post_code(town : &str, post_code : &PostCode) -> bool {
+	let localities = post_code.localities();
+
+	localities.is_empty()
+		|| localities
+			.iter()
+			.any(|locality| normalise_locality(locality) == normalise_locality(town))
+}
+
+fn normalise_locality(locality : &str) -> String {
+	locality
+		.split_whitespace()
+		.collect::<Vec<&str>>()
+		.join(" ")
+		.to_uppercase()
+}
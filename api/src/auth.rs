@@ -0,0 +1,39 @@
+use rocket::{
+	http::Status,
+	outcome::Outcome,
+	request::{self, FromRequest, Request},
+};
+
+/// Request guard for committee members running the sale. Requests must send
+/// `Authorization: Bearer <token>` matching the `ADMIN_TOKEN` environment
+/// variable. When no token is configured nobody is an admin.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+	type Error = ();
+
+	async fn from_request(request : &'r Request<'_>) -> request::Outcome<Self, ()> {
+		let expected = match std::env::var("ADMIN_TOKEN") {
+			Ok(token) if !token.is_empty() => token,
+			_ => return Outcome::Error((Status::Unauthorized, ())),
+		};
+
+		let token = request
+			.headers()
+			.get_one("Authorization")
+			.and_then(|header| header.strip_prefix("Bearer "));
+
+		match token {
+			Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+				Outcome::Success(Admin)
+			},
+			_ => Outcome::Error((Status::Unauthorized, ())),
+		}
+	}
+}
+
+/// Compare tokens without leaking how much of them matched through timing
+fn constant_time_eq(a : &[u8], b : &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,14 +1,33 @@
-#![feature(decl_macro, proc_macro_hygiene)]
-
-use rocket::routes;
+use rocket::{fairing::AdHoc, routes, Build, Rocket};
 
 use librainbowapi::{
-	db::PrimaryDb,
-	graphql::{mutation_root::MutationRoot, query_root::QueryRoot},
-	routes::{self, Schema},
+	cli, config,
+	db::{orders, PrimaryDb},
+	expiry, idempotency,
+	logging::{self, RequestIds},
+	routes,
 };
 
-fn main() {
+/// Runs the server, or a command if one is given
+#[rocket::main]
+async fn main() {
+	let args : Vec<String> = std::env::args().skip(1).collect();
+	if !args.is_empty() {
+		if let Err(e) = cli::run(&args).await {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+		return;
+	}
+
+	logging::init();
+	if let Err(e) = rocket().launch().await {
+		tracing::error!(error = %e, "server failed");
+		std::process::exit(1);
+	}
+}
+
+fn rocket() -> Rocket<Build> {
 	// let allowed_origins = AllowedOrigins::some_exact(&["http://localhost:8080"]);
 
 	// You can also deserialize this
@@ -26,18 +45,49 @@ fn main() {
 		_ => panic!("Cors header not set up"),
 	};
 
-	rocket::ignite()
+	rocket::build()
 		.attach(cors)
+		.attach(RequestIds)
 		.attach(PrimaryDb::fairing())
-		.manage(Schema::new(QueryRoot, MutationRoot))
+		.attach(AdHoc::try_on_ignite(
+			"Database indexes",
+			|rocket| async move {
+				match rocket.state::<PrimaryDb>().cloned() {
+					Some(db) => {
+						orders::backfill_email_keys(&db.collection("orders")).await;
+						orders::create_indexes(&db.collection("orders")).await;
+						idempotency::create_indexes(&db.collection("idempotency_keys")).await;
+						Ok(rocket)
+					},
+					None => Err(rocket),
+				}
+			},
+		))
+		.attach(AdHoc::on_liftoff("Order expiry", |rocket| {
+			Box::pin(async move {
+				if let Some(db) = rocket.state::<PrimaryDb>() {
+					expiry::spawn(db.clone(), config::expiry());
+				}
+			})
+		}))
+		.manage(routes::schema())
 		.mount(
 			"/",
 			routes![
 				routes::index,
+				routes::healthz,
+				routes::readyz,
+				routes::version,
+				routes::get_metrics,
 				routes::get_graphql_handler,
-				routes::post_graphql_handler
+				routes::post_graphql_handler,
+				routes::get_labels,
+				routes::get_roster,
+				routes::get_batch_manifest,
+				routes::get_orders_export,
+				routes::post_orders_import,
+				routes::post_stripe_webhook
 			],
 		)
 		.mount("/graphiql", routes![routes::graphiql])
-		.launch();
 }
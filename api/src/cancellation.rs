@@ -0,0 +1,229 @@
+//! Cancelling orders before they are paid and refunding them after. Scarves
+//! are not counted as stock, so the only thing an order holds on to is its
+//! place in a fulfilment batch, which is released if it has not been packed.
+
+use crate::{
+	checkout,
+	db::helpers as DBHelper,
+	graphql::context::Context,
+	history::{self, EventKind, OrderEvent},
+	idempotency, metrics,
+	models::{Order, OrderStatus, Refund},
+	stripe::{amount_received, cancel_payment, get_idempotent_stripe, get_stripe, refund},
+};
+use chrono::Utc;
+use juniper::{graphql_value, FieldError};
+use mongodb::bson::{oid::ObjectId, Bson, Document};
+
+pub enum CancelError {
+	InvalidId,
+	NotFound,
+	AlreadyPaid,
+	AlreadyCancelled,
+	NotPaid,
+	OfflinePayment,
+	InvalidAmount,
+	PaymentError,
+	DatabaseError,
+}
+
+impl CancelError {
+	pub fn to_field_error(&self) -> FieldError {
+		let (message, code) = match self {
+			CancelError::InvalidId => ("UID is not valid", "INVALID_UID"),
+			CancelError::NotFound => ("The requested order was not found", "NOT_FOUND"),
+			CancelError::AlreadyPaid => (
+				"This order has been paid for, please contact us for a refund",
+				"ALREADY_PAID",
+			),
+			CancelError::AlreadyCancelled => {
+				("This order has already been cancelled", "ALREADY_CANCELLED")
+			},
+			CancelError::NotPaid => ("Only paid orders can be refunded", "NOT_PAID"),
+			CancelError::OfflinePayment => (
+				"This order was not paid for on the site, so it has to be refunded by hand",
+				"OFFLINE_PAYMENT",
+			),
+			CancelError::InvalidAmount => (
+				"The amount must be more than 0 and no more than what is left to refund",
+				"INVALID_AMOUNT",
+			),
+			CancelError::PaymentError => {
+				("Stripe rejected the change to the payment", "PAYMENT_ERROR")
+			},
+			CancelError::DatabaseError => ("Failed to update the order", "DATABASE_ERROR"),
+		};
+
+		FieldError::new(message, graphql_value!({ "type": code }))
+	}
+}
+
+async fn load(context : &Context, id : &str) -> Result<Order, CancelError> {
+	let id = ObjectId::parse_str(id).map_err(|_| CancelError::InvalidId)?;
+	let mut order : Order = DBHelper::get(context.orders_handel(), id)
+		.await
+		.ok_or(CancelError::NotFound)?;
+
+	order.refresh_status(&context.orders_handel()).await;
+	Ok(order)
+}
+
+fn pi(order : &Order) -> Option<String> {
+	order
+		.payment
+		.as_ref()
+		.and_then(|payment| payment.stripe.as_ref())
+		.map(|stripe| stripe.pi.to_owned())
+		.filter(|pi| !pi.is_empty())
+}
+
+/// Cancel an order that has not been paid for, so the payment intent can no
+/// longer be paid
+pub async fn cancel_order(
+	context : &Context,
+	id : &str,
+	idempotency_key : Option<&str>,
+) -> Result<Order, CancelError> {
+	let mut order = load(context, id).await?;
+	match order.status {
+		OrderStatus::Unpaid => {},
+		OrderStatus::Cancelled | OrderStatus::Expired | OrderStatus::Refunded => {
+			return Err(CancelError::AlreadyCancelled)
+		},
+		_ => return Err(CancelError::AlreadyPaid),
+	}
+
+	if let Some(pi) = pi(&order) {
+		let client = get_idempotent_stripe(idempotency::stripe_key(idempotency_key, "cancel"));
+		if !cancel_payment(&client, &pi).await {
+			return Err(CancelError::PaymentError);
+		}
+	}
+	if !checkout::close_session(&order).await {
+		return Err(CancelError::PaymentError);
+	}
+
+	let event = OrderEvent::change(
+		EventKind::Cancelled,
+		context.actor(),
+		"status",
+		Some(order.status.code().to_string()),
+		Some(OrderStatus::Cancelled.code().to_string()),
+	);
+
+	let updated = context
+		.orders_handel()
+		.update_one(
+			doc! {
+				"_id": ObjectId::parse_str(&*order.id).map_err(|_| CancelError::InvalidId)?,
+				// Orders from before statuses were stored have none
+				"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+			},
+			history::update(
+				doc! {
+					"status": OrderStatus::Cancelled.code(),
+					"batch_id": Bson::Null,
+				},
+				&[event.clone()],
+			),
+			None,
+		)
+		.await
+		.map_err(|_| CancelError::DatabaseError)?;
+
+	// Paid for between checking Stripe and cancelling the intent
+	if updated.matched_count == 0 {
+		return Err(CancelError::AlreadyPaid);
+	}
+
+	order.status = OrderStatus::Cancelled;
+	order.batch_id = None;
+	order.history.push(event);
+	Ok(order)
+}
+
+/// Refund part of a paid order, or all of what is left if no amount is given.
+/// Once the whole payment has been refunded the order is marked as refunded
+/// and taken out of its batch unless it has already been packed.
+pub async fn refund_order(
+	context : &Context,
+	id : &str,
+	amount : Option<i64>,
+	reason : String,
+	idempotency_key : Option<&str>,
+) -> Result<Order, CancelError> {
+	let mut order = load(context, id).await?;
+	if !order.status.is_paid() {
+		return Err(CancelError::NotPaid);
+	}
+
+	// Money taken outside the site can not be given back through Stripe
+	let pi = pi(&order).ok_or(CancelError::OfflinePayment)?;
+	let remaining = amount_received(&get_stripe(), &pi)
+		.await
+		.ok_or(CancelError::PaymentError)?
+		- order.refunded();
+
+	let amount = amount.unwrap_or(remaining);
+	if amount <= 0 || amount > remaining {
+		return Err(CancelError::InvalidAmount);
+	}
+
+	let client = get_idempotent_stripe(idempotency::stripe_key(idempotency_key, "refund"));
+	let (refund_id, refunded) = refund(&client, &pi, Some(amount), &reason)
+		.await
+		.ok_or(CancelError::PaymentError)?;
+	let entry = Refund {
+		id : refund_id,
+		amount : refunded,
+		reason,
+		created_at : Utc::now(),
+	};
+
+	let mut set = Document::new();
+	let mut events = vec![OrderEvent::change(
+		EventKind::Refunded,
+		context.actor(),
+		"refunds",
+		Some(order.refunded().to_string()),
+		Some((order.refunded() + refunded).to_string()),
+	)];
+
+	if refunded == remaining {
+		events.push(OrderEvent::change(
+			EventKind::StatusChanged,
+			context.actor(),
+			"status",
+			Some(order.status.code().to_string()),
+			Some(OrderStatus::Refunded.code().to_string()),
+		));
+		set.insert("status", OrderStatus::Refunded.code());
+		if order.status == OrderStatus::Paid {
+			set.insert("batch_id", Bson::Null);
+			order.batch_id = None;
+		}
+		order.status = OrderStatus::Refunded;
+	}
+
+	let mut update = history::update(set, &events);
+	if let Some(Bson::Document(push)) = update.get_mut("$push") {
+		push.insert("refunds", Bson::Document(entry.to_doc()));
+	}
+
+	// The refund has already gone through, so the order has to be updated
+	// even if it changed in the meantime
+	context
+		.orders_handel()
+		.update_one(
+			doc! {"_id": ObjectId::parse_str(&*order.id).map_err(|_| CancelError::InvalidId)?},
+			update,
+			None,
+		)
+		.await
+		.map_err(|_| CancelError::DatabaseError)?;
+
+	metrics::refunded(refunded);
+	order.refunds.push(entry);
+	order.history.extend(events);
+	Ok(order)
+}
@@ -0,0 +1,71 @@
+/// All prices are charged in Australian dollars, including for overseas
+/// orders
+pub const CURRENCY : &str = "AUD";
+
+/// A particular version of a product that is picked separately, such as a
+/// colour or size
+#[derive(Clone, Debug)]
+pub struct Variant {
+	pub code : &'static str,
+	pub name : &'static str,
+}
+
+/// Something we sell, along with what customs needs to know about it when it
+/// is posted overseas
+#[derive(Clone, Debug)]
+pub struct Product {
+	pub code :                &'static str,
+	pub name :                &'static str,
+	/// The first variant is used when an order does not pick one
+	pub variants :            &'static [Variant],
+	/// Price in cents
+	pub price :               u64,
+	/// Packed weight of a single item
+	pub weight_kg :           f64,
+	pub customs_description : &'static str,
+	/// Harmonized System tariff code for customs declarations
+	pub hs_tariff_code :      &'static str,
+	/// ISO 3166-1 alpha-2 code of the country the item was made in
+	pub origin_country :      &'static str,
+}
+
+pub const SCARF : Product = Product {
+	code :                "SCARF",
+	name :                "Rainbow Scarf",
+	variants :            &[Variant {
+		code : "STANDARD",
+		name : "Standard",
+	}],
+	price :               1500,
+	weight_kg :           0.1,
+	customs_description : "Woven polyester scout neckerchief",
+	hs_tariff_code :      "6214.30",
+	origin_country :      "AU",
+};
+
+impl Product {
+	pub fn default_variant(&self) -> &'static Variant { &self.variants[0] }
+
+	pub fn variant(&self, code : &str) -> Option<&'static Variant> {
+		self.variants.iter().find(|variant| variant.code == code)
+	}
+
+	/// Name of the product and variant, as written on pick lists and packing
+	/// slips
+	pub fn item_name(&self, variant : &str) -> String {
+		match self.variant(variant) {
+			Some(v) => format!("{} - {}", self.name, v.name),
+			None => format!("{} - {}", self.name, variant),
+		}
+	}
+}
+
+pub fn products() -> Vec<Product> { vec![SCARF] }
+
+/// Format a price in cents for display, making the currency clear to
+/// overseas customers. Negative amounts are shown as `-A$0.50`.
+pub fn format_price(cents : i64) -> String {
+	let sign = if cents < 0 { "-" } else { "" };
+	let cents = cents.unsigned_abs();
+	format!("{}A${}.{:02}", sign, cents / 100, cents % 100)
+}
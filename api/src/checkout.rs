@@ -0,0 +1,490 @@
+//! Paying on Stripe's hosted Checkout page instead of with Stripe Elements
+//! on our own. A Checkout Session lists the scarves and postage as separate
+//! lines, and makes its own payment intent when the customer pays. Once the
+//! session is paid that intent replaces the order's original one, which is
+//! cancelled, so everything else keeps looking at a single payment intent.
+//! Should both have been paid, the second payment is refunded.
+//!
+//! Stripe tells us a session or payment intent was paid, or a payment was
+//! declined, through the webhook, so stored statuses and the payment counts
+//! keep up with payments as they happen. Orders are also
+//! checked against their latest session whenever their status is refreshed,
+//! in case the webhook is late or missed.
+
+use crate::{
+	catalogue::SCARF,
+	config,
+	db::helpers as DBHelper,
+	graphql::context::Context,
+	history::{self, Actor, EventKind, OrderEvent},
+	idempotency, metrics,
+	models::{Order, OrderStatus, PaymentMethod},
+	stripe::{
+		call, cancel_payment, checkout_session, expire_checkout, get_idempotent_stripe, get_stripe,
+		payment_description, payment_outcome, refund, PaymentOutcome,
+	},
+};
+use juniper::{graphql_value, FieldError};
+use mongodb::{
+	bson::{oid::ObjectId, Bson, Document},
+	Collection,
+};
+use rocket::{
+	http::Status,
+	outcome::Outcome,
+	request::{self, FromRequest, Request},
+};
+use stripe::{
+	CheckoutSession, CheckoutSessionMode, CheckoutSessionPaymentStatus, CheckoutSessionStatus,
+	CreateCheckoutSession, CreateCheckoutSessionLineItems, CreateCheckoutSessionLineItemsPriceData,
+	CreateCheckoutSessionLineItemsPriceDataProductData, CreateCheckoutSessionPaymentIntentData,
+	Currency, EventObject, EventType, Metadata, PaymentIntent, Webhook,
+};
+use tracing::{error, info, warn};
+
+/// Where to send the customer to pay
+#[derive(Clone, Debug)]
+pub struct CheckoutLink {
+	pub session : String,
+	pub url :     String,
+}
+
+pub enum CheckoutError {
+	InvalidId,
+	NotFound,
+	AlreadyPaid,
+	NotCardPayment,
+	Unavailable,
+	PaymentError,
+	DatabaseError,
+}
+
+impl CheckoutError {
+	pub fn to_field_error(&self) -> FieldError {
+		let (message, code) = match self {
+			CheckoutError::InvalidId => ("UID is not valid", "INVALID_UID"),
+			CheckoutError::NotFound => ("The requested order was not found", "NOT_FOUND"),
+			CheckoutError::AlreadyPaid => (
+				"This order is no longer waiting for payment",
+				"ALREADY_PAID",
+			),
+			CheckoutError::NotCardPayment => (
+				"This order is not being paid for by card",
+				"NOT_CARD_PAYMENT",
+			),
+			CheckoutError::Unavailable => (
+				"Checkout is not available at the moment",
+				"CHECKOUT_UNAVAILABLE",
+			),
+			CheckoutError::PaymentError => ("Failed to create checkout session", "PAYMENT_ERROR"),
+			CheckoutError::DatabaseError => ("Failed to update the order", "DATABASE_ERROR"),
+		};
+
+		FieldError::new(message, graphql_value!({ "type": code }))
+	}
+}
+
+/// Make a Checkout Session for the whole of an unpaid order. Any session made
+/// for it before is expired, so only the latest price can be paid.
+pub async fn create_session(
+	context : &Context,
+	id : &str,
+	idempotency_key : Option<&str>,
+) -> Result<CheckoutLink, CheckoutError> {
+	let urls = config::checkout().ok_or(CheckoutError::Unavailable)?;
+	let oid = ObjectId::parse_str(id).map_err(|_| CheckoutError::InvalidId)?;
+	let mut order : Order = DBHelper::get(context.orders_handel(), oid)
+		.await
+		.ok_or(CheckoutError::NotFound)?;
+
+	order.refresh_status(&context.orders_handel()).await;
+	if order.status != OrderStatus::Unpaid {
+		return Err(CheckoutError::AlreadyPaid);
+	}
+	if order.payment_method() != PaymentMethod::Card {
+		return Err(CheckoutError::NotCardPayment);
+	}
+
+	let order_id = order.id.to_string();
+	let success_url = urls.success_url.replace("{ORDER_ID}", &order_id);
+	let cancel_url = urls.cancel_url.replace("{ORDER_ID}", &order_id);
+	let description = payment_description(
+		&order.reference(),
+		&order.user.name,
+		order.quantity,
+		order.method,
+	);
+
+	let mut metadata = Metadata::new();
+	metadata.insert("order_number".to_string(), order.reference());
+	metadata.insert("order_id".to_string(), order_id.to_owned());
+
+	let mut params = CreateCheckoutSession::new(&success_url);
+	params.cancel_url = Some(&cancel_url);
+	params.mode = Some(CheckoutSessionMode::Payment);
+	params.client_reference_id = Some(&order_id);
+	params.customer_email = Some(&order.user.email);
+	params.line_items = Some(line_items(&order));
+	params.metadata = Some(metadata.clone());
+	params.payment_intent_data = Some(CreateCheckoutSessionPaymentIntentData {
+		description : Some(description),
+		metadata,
+		..Default::default()
+	});
+
+	let client = get_idempotent_stripe(idempotency::stripe_key(idempotency_key, "checkout"));
+	let session = call(idempotency_key.is_some(), || {
+		CheckoutSession::create(&client, params.clone())
+	})
+	.await
+	.ok_or(CheckoutError::PaymentError)?;
+	let link = CheckoutLink {
+		session : session.id.to_string(),
+		url :     session.url.clone().ok_or(CheckoutError::PaymentError)?,
+	};
+
+	// A retry with the same key gets the same session back from Stripe
+	let previous = session_id(&order);
+	if previous.as_deref() == Some(session.id.as_str()) {
+		return Ok(link);
+	}
+	if let Some(previous) = &previous {
+		if !expire_checkout(&get_stripe(), previous).await {
+			return Err(CheckoutError::PaymentError);
+		}
+	}
+
+	let event = OrderEvent::change(
+		EventKind::PaymentStarted,
+		context.actor(),
+		"checkout_session",
+		previous,
+		Some(session.id.to_string()),
+	);
+	context
+		.orders_handel()
+		.update_one(
+			doc! { "_id": oid },
+			history::update(
+				doc! { "payment.stripe.checkout_session": session.id.as_str() },
+				&[event],
+			),
+			None,
+		)
+		.await
+		.map_err(|_| CheckoutError::DatabaseError)?;
+
+	Ok(link)
+}
+
+fn session_id(order : &Order) -> Option<String> {
+	order
+		.payment
+		.as_ref()
+		.and_then(|payment| payment.stripe.as_ref())
+		.and_then(|stripe| stripe.checkout_session.clone())
+}
+
+/// One line for each variant of scarf, and one for postage
+fn line_items(order : &Order) -> Vec<CreateCheckoutSessionLineItems> {
+	let line = |name : String, amount : i64, quantity : u64| CreateCheckoutSessionLineItems {
+		price_data : Some(CreateCheckoutSessionLineItemsPriceData {
+			currency : Currency::AUD,
+			product_data : Some(CreateCheckoutSessionLineItemsPriceDataProductData {
+				name,
+				..Default::default()
+			}),
+			unit_amount : Some(amount),
+			..Default::default()
+		}),
+		quantity : Some(quantity),
+		..Default::default()
+	};
+
+	let mut lines : Vec<CreateCheckoutSessionLineItems> = order
+		.items
+		.iter()
+		.filter(|item| item.quantity > 0)
+		.map(|item| {
+			line(
+				SCARF.item_name(&item.variant),
+				SCARF.price as i64,
+				item.quantity as u64,
+			)
+		})
+		.collect();
+
+	if let Some(postage) = &order.postage {
+		lines.push(line(format!("Postage: {}", postage.name), postage.price, 1));
+	}
+
+	lines
+}
+
+/// Expire the order's latest Checkout Session, if it has one that is still
+/// open, because the order can no longer be paid for at its price
+pub async fn close_session(order : &Order) -> bool {
+	match session_id(order) {
+		Some(session) => expire_checkout(&get_stripe(), &session).await,
+		None => true,
+	}
+}
+
+/// Check the order's latest Checkout Session, and link it to the order if it
+/// has been paid
+pub async fn refresh_session(orders : &Collection<Document>, order : &mut Order) {
+	if let Some(session) = session_id(order) {
+		if let Some(session) = checkout_session(&get_stripe(), &session).await {
+			link(orders, order, &session).await;
+		}
+	}
+}
+
+/// Whether the order's latest Checkout Session has been paid, without linking
+/// it to the order
+pub async fn session_paid(order : &Order) -> bool {
+	match session_id(order) {
+		Some(session) => checkout_session(&get_stripe(), &session)
+			.await
+			.and_then(|session| paid_intent(&session))
+			.is_some(),
+		None => false,
+	}
+}
+
+/// The payment intent a session was paid with, once it has been paid
+fn paid_intent(session : &CheckoutSession) -> Option<String> {
+	let paid = session.status == Some(CheckoutSessionStatus::Complete)
+		&& session.payment_status == CheckoutSessionPaymentStatus::Paid;
+	match (&session.payment_intent, paid) {
+		(Some(pi), true) => Some(pi.id().to_string()),
+		_ => None,
+	}
+}
+
+/// Make a paid session's payment intent the order's payment intent, and
+/// cancel the one the order was made with so it can not be paid as well. If
+/// the customer has managed to pay twice, the extra payment is refunded.
+async fn link(orders : &Collection<Document>, order : &mut Order, session : &CheckoutSession) {
+	let pi = match paid_intent(session) {
+		Some(pi) => pi,
+		None => return,
+	};
+
+	let previous = match order.payment.as_ref().and_then(|p| p.stripe.as_ref()) {
+		Some(stripe) if stripe.pi != pi => stripe.pi.to_owned(),
+		_ => return,
+	};
+	let id = match ObjectId::parse_str(&*order.id) {
+		Ok(id) => id,
+		Err(_) => return,
+	};
+
+	let event = OrderEvent::change(
+		EventKind::PaymentStarted,
+		Actor::Stripe,
+		"payment",
+		Some(previous.to_owned()),
+		Some(pi.to_owned()),
+	);
+	let linked = orders
+		.update_one(
+			doc! {
+				"_id": id,
+				// Orders from before statuses were stored have none
+				"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+				"payment.stripe.pi": &previous,
+			},
+			history::update(doc! { "payment.stripe.pi": &pi }, &[event.clone()]),
+			None,
+		)
+		.await
+		.map(|result| result.modified_count == 1)
+		.unwrap_or(false);
+
+	if !linked {
+		// Either linked already by another request, or the order was paid,
+		// cancelled or expired some other way while the customer was paying
+		let current : Option<Order> = DBHelper::get(orders.clone(), id).await;
+		let current = current
+			.and_then(|order| order.payment)
+			.and_then(|payment| payment.stripe)
+			.map(|stripe| stripe.pi);
+		if current.as_deref() != Some(pi.as_str()) {
+			warn!(
+				order = order.reference().as_str(),
+				session = session.id.as_str(),
+				"checkout session paid but not linked to its order",
+			);
+			refund_duplicate(orders, order, &pi).await;
+		}
+		return;
+	}
+
+	if let Some(stripe) = order.payment.as_mut().and_then(|p| p.stripe.as_mut()) {
+		stripe.pi = pi;
+	}
+	order.history.push(event);
+	info!(
+		order = order.reference().as_str(),
+		session = session.id.as_str(),
+		"checkout session linked",
+	);
+
+	if previous.is_empty() || cancel_payment(&get_stripe(), &previous).await {
+		return;
+	}
+
+	// Intents that have been paid can not be cancelled
+	match payment_outcome(&get_stripe(), &previous).await {
+		PaymentOutcome::Succeeded(_) => refund_duplicate(orders, order, &previous).await,
+		PaymentOutcome::Pending => warn!(
+			order = order.reference().as_str(),
+			pi = previous.as_str(),
+			"failed to cancel payment intent replaced by checkout",
+		),
+	}
+}
+
+/// Give back a payment for an order that has been paid for with another
+async fn refund_duplicate(orders : &Collection<Document>, order : &mut Order, pi : &str) {
+	let refunded = match refund(&get_stripe(), pi, None, "Paid twice").await {
+		Some((refund_id, _)) => refund_id,
+		None => {
+			error!(
+				order = order.reference().as_str(),
+				pi, "order paid twice and the second payment could not be refunded",
+			);
+			return;
+		},
+	};
+
+	let event = OrderEvent::change(
+		EventKind::Refunded,
+		Actor::System,
+		"payment",
+		Some(pi.to_string()),
+		Some(refunded),
+	);
+	if let Ok(id) = ObjectId::parse_str(&*order.id) {
+		let recorded = orders
+			.update_one(
+				doc! { "_id": id },
+				history::update(doc! {}, &[event.clone()]),
+				None,
+			)
+			.await;
+		if recorded.is_err() {
+			error!(
+				order = order.reference().as_str(),
+				pi, "failed to record refund"
+			);
+		}
+	}
+	order.history.push(event);
+	warn!(
+		order = order.reference().as_str(),
+		pi, "refunded second payment for order"
+	);
+}
+
+/// The `Stripe-Signature` header of a webhook request
+pub struct StripeSignature(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StripeSignature {
+	type Error = ();
+
+	async fn from_request(request : &'r Request<'_>) -> request::Outcome<Self, ()> {
+		match request.headers().get_one("Stripe-Signature") {
+			Some(signature) => Outcome::Success(StripeSignature(signature.to_string())),
+			None => Outcome::Error((Status::BadRequest, ())),
+		}
+	}
+}
+
+pub enum WebhookError {
+	Unconfigured,
+	BadSignature,
+}
+
+/// Handle an event sent to the webhook. Paid Checkout Sessions are linked to
+/// their orders, paid payment intents mark their orders as paid, and
+/// everything else is ignored.
+pub async fn handle_event(
+	orders : &Collection<Document>,
+	payload : &str,
+	signature : &StripeSignature,
+) -> Result<(), WebhookError> {
+	let secret = config::stripe_webhook_secret().ok_or(WebhookError::Unconfigured)?;
+	let event = Webhook::construct_event(payload, &signature.0, &secret)
+		.map_err(|_| WebhookError::BadSignature)?;
+
+	match (event.type_, event.data.object) {
+		(
+			EventType::CheckoutSessionCompleted | EventType::CheckoutSessionAsyncPaymentSucceeded,
+			EventObject::CheckoutSession(session),
+		) => session_completed(orders, &session).await,
+		(EventType::PaymentIntentSucceeded, EventObject::PaymentIntent(intent)) => {
+			intent_succeeded(orders, &intent).await
+		},
+		(EventType::PaymentIntentPaymentFailed, EventObject::PaymentIntent(_)) => {
+			metrics::payment_failed()
+		},
+		_ => {},
+	}
+
+	Ok(())
+}
+
+async fn session_completed(orders : &Collection<Document>, session : &CheckoutSession) {
+	let id = session
+		.client_reference_id
+		.as_deref()
+		.and_then(|id| ObjectId::parse_str(id).ok());
+	let order : Option<Order> = match id {
+		Some(id) => DBHelper::get(orders.clone(), id).await,
+		None => None,
+	};
+	let mut order = match order {
+		Some(order) => order,
+		None => {
+			warn!(
+				session = session.id.as_str(),
+				"checkout session for an unknown order"
+			);
+			return;
+		},
+	};
+
+	link(orders, &mut order, session).await;
+	order.refresh_status(orders).await;
+}
+
+/// Payment intents made for an order and by its Checkout Sessions both carry
+/// the order's ID. The order is brought up to date with Stripe rather than
+/// trusting the event, which may be old by the time it arrives.
+async fn intent_succeeded(orders : &Collection<Document>, intent : &PaymentIntent) {
+	let id = intent
+		.metadata
+		.get("order_id")
+		.and_then(|id| ObjectId::parse_str(id).ok());
+	let order : Option<Order> = match id {
+		Some(id) => DBHelper::get(orders.clone(), id).await,
+		None => {
+			DBHelper::find_one(
+				orders.clone(),
+				doc! { "payment.stripe.pi": intent.id.as_str() },
+			)
+			.await
+		},
+	};
+
+	match order {
+		Some(mut order) => order.refresh_status(orders).await,
+		None => warn!(
+			pi = intent.id.as_str(),
+			"payment intent for an unknown order"
+		),
+	}
+}
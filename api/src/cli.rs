@@ -0,0 +1,145 @@
+//! Commands for the committee to run against the database without going
+//! through the server, such as `rainbow export --format xlsx --paid true
+//! --output orders.xlsx` or `rainbow import --file troop.csv --dry_run
+//! true`. The database is the one in Rocket.toml for the current profile.
+
+use crate::{
+	db::PrimaryDb,
+	export::{self, ExportFilter, ExportFormat},
+	graphql::context::Context,
+	import,
+};
+use futures::StreamExt;
+use std::{
+	fs::{self, File},
+	io::{self, Write},
+};
+
+const USAGE : &str = "Usage:
+  rainbow                   run the server
+  rainbow export [options]  write orders as CSV or XLSX
+  rainbow import [options]  create orders paid outside the site from CSV
+
+Export options:
+  --format csv|xlsx         defaults to csv
+  --output FILE             defaults to stdout
+  --status, --method, --paid, --post_code, --state, --email,
+  --created_after, --created_before
+                            the same filters as the orders query
+
+Import options:
+  --file FILE               CSV with a header row; name, email, quantity
+                            and method are required
+  --dry_run true|false      only check the rows, defaults to false";
+
+pub async fn run(args : &[String]) -> Result<(), String> {
+	match args[0].as_str() {
+		"export" => export(&args[1..]).await,
+		"import" => import(&args[1..]).await,
+		_ => Err(USAGE.to_string()),
+	}
+}
+
+/// `--name value` pairs, in the order they were given
+fn options(args : &[String]) -> Result<Vec<(&str, String)>, String> {
+	args.chunks(2)
+		.map(|pair| match pair {
+			[name, value] if name.starts_with("--") => Ok((&name[2..], value.to_owned())),
+			_ => Err(USAGE.to_string()),
+		})
+		.collect()
+}
+
+async fn connect() -> Result<PrimaryDb, String> {
+	PrimaryDb::connect(&rocket::Config::figment())
+		.await
+		.ok_or_else(|| "Could not connect to the database".to_string())
+}
+
+async fn export(args : &[String]) -> Result<(), String> {
+	let mut format = ExportFormat::Csv;
+	let mut output = None;
+	let mut filter = ExportFilter::default();
+
+	for (name, value) in options(args)? {
+		match name {
+			"format" => {
+				format = ExportFormat::parse(&value)
+					.ok_or_else(|| format!("{} is not csv or xlsx", value))?
+			},
+			"output" => output = Some(value),
+			_ => filter.set(name, value)?,
+		}
+	}
+
+	let filter = filter.to_order_filter()?;
+	let db = connect().await?;
+	let cursor = export::orders(&db.collection("orders"), &filter).await?;
+
+	let mut out : Box<dyn Write> = match &output {
+		Some(path) => Box::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?),
+		None => Box::new(io::stdout()),
+	};
+
+	match format {
+		ExportFormat::Csv => {
+			let mut rows = export::csv(cursor).boxed();
+			while let Some(row) = rows.next().await {
+				out.write_all(&row).map_err(|e| e.to_string())?;
+			}
+		},
+		ExportFormat::Xlsx => {
+			let workbook = export::xlsx(cursor).await?;
+			out.write_all(&workbook).map_err(|e| e.to_string())?;
+		},
+	}
+
+	out.flush().map_err(|e| e.to_string())
+}
+
+async fn import(args : &[String]) -> Result<(), String> {
+	let mut file = None;
+	let mut dry_run = false;
+
+	for (name, value) in options(args)? {
+		match name {
+			"file" => file = Some(value),
+			"dry_run" => {
+				dry_run = value
+					.parse()
+					.map_err(|_| format!("dry_run must be true or false, not {}", value))?
+			},
+			_ => return Err(USAGE.to_string()),
+		}
+	}
+
+	let path = file.ok_or_else(|| USAGE.to_string())?;
+	let contents = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+	let context = Context {
+		connection : connect().await?,
+		admin :      true,
+	};
+	let report = import::import(&context, &contents, dry_run).await?;
+
+	for row in &report.rows {
+		match (&row.order, row.errors.is_empty()) {
+			(Some(order), _) => println!("line {}: created {}", row.line, order),
+			(None, true) => println!("line {}: ok", row.line),
+			(None, false) => println!("line {}: {}", row.line, row.errors.join("; ")),
+		}
+	}
+
+	if !report.ok {
+		return Err(format!(
+			"{} rows have problems, {} orders created",
+			report.failed(),
+			report.created()
+		));
+	}
+
+	match report.dry_run {
+		true => println!("{} rows ready to import", report.rows.len()),
+		false => println!("{} orders created", report.created()),
+	}
+	Ok(())
+}
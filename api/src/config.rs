@@ -0,0 +1,211 @@
+use chrono::Duration;
+
+/// The return address printed on labels, and where parcels are posted from.
+/// Set with the `SENDER_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct Sender {
+	pub name :      String,
+	pub street :    String,
+	pub town :      String,
+	pub state :     String,
+	pub post_code : String,
+}
+
+impl Sender {
+	pub fn lines(&self) -> Vec<String> {
+		vec![
+			self.name.to_owned(),
+			self.street.to_owned(),
+			format!("{} {} {}", self.town, self.state, self.post_code),
+		]
+		.into_iter()
+		.filter(|line| !line.trim().is_empty())
+		.collect()
+	}
+}
+
+pub fn sender() -> Sender {
+	Sender {
+		name :      env_or("SENDER_NAME", "Normanhurst Rover Crew"),
+		street :    env_or("SENDER_STREET", ""),
+		town :      env_or("SENDER_TOWN", "HORNSBY"),
+		state :     env_or("SENDER_STATE", "NSW"),
+		post_code : env_or("SENDER_POST_CODE", "2077"),
+	}
+}
+
+/// When abandoned unpaid orders are chased up and expired. Set with the
+/// `ORDER_EXPIRY_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct Expiry {
+	/// How long after being created an unpaid order is expired
+	pub expire_after : Duration,
+	/// How long after being created the customer is reminded to pay, if at
+	/// all. Reminders are only sent when email is set up.
+	pub remind_after : Option<Duration>,
+	/// How often the job looks for orders
+	pub interval :     std::time::Duration,
+	/// Report what would happen without changing anything
+	pub dry_run :      bool,
+}
+
+pub fn expiry() -> Expiry {
+	Expiry {
+		expire_after : Duration::hours(env_number("ORDER_EXPIRY_HOURS", 72)),
+		remind_after : std::env::var("ORDER_EXPIRY_REMINDER_HOURS")
+			.ok()
+			.and_then(|hours| hours.parse().ok())
+			.map(Duration::hours)
+			.filter(|_| smtp().is_some()),
+		interval :     std::time::Duration::from_secs(
+			60 * env_number("ORDER_EXPIRY_INTERVAL_MINUTES", 15) as u64,
+		),
+		dry_run :      match env_or("ORDER_EXPIRY_DRY_RUN", "").as_str() {
+			"1" | "true" => true,
+			_ => false,
+		},
+	}
+}
+
+/// How long to wait for each upstream service before giving up on a
+/// request. Set with the `*_TIMEOUT_SECONDS` environment variables.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+	pub auspost : std::time::Duration,
+	pub stripe :  std::time::Duration,
+	pub smtp :    std::time::Duration,
+}
+
+pub fn timeouts() -> Timeouts {
+	let seconds =
+		|key : &str, default : i64| std::time::Duration::from_secs(env_number(key, default) as u64);
+
+	Timeouts {
+		auspost : seconds("AUSPOST_TIMEOUT_SECONDS", 10),
+		stripe :  seconds("STRIPE_TIMEOUT_SECONDS", 20),
+		smtp :    seconds("SMTP_TIMEOUT_SECONDS", 30),
+	}
+}
+
+/// How requests to upstream services are retried and when they are given up
+/// on for a while. Set with the `UPSTREAM_*` environment variables.
+#[derive(Clone, Copy, Debug)]
+pub struct Upstream {
+	/// How many more times an idempotent request is tried after it fails
+	pub retries :          u32,
+	/// How long to wait before the first retry. Each retry after that waits
+	/// twice as long as the last.
+	pub backoff :          std::time::Duration,
+	/// How many requests in a row can fail before the service is treated as
+	/// down
+	pub breaker_failures : u32,
+	/// How long a service that is down is left alone before trying it again
+	pub breaker_cooldown : std::time::Duration,
+}
+
+pub fn upstream() -> Upstream {
+	Upstream {
+		retries :          env_number("UPSTREAM_RETRIES", 2) as u32,
+		backoff :          std::time::Duration::from_millis(env_number(
+			"UPSTREAM_BACKOFF_MILLIS",
+			200,
+		) as u64),
+		breaker_failures : env_number("UPSTREAM_BREAKER_FAILURES", 5) as u32,
+		breaker_cooldown : std::time::Duration::from_secs(env_number(
+			"UPSTREAM_BREAKER_COOLDOWN_SECONDS",
+			30,
+		) as u64),
+	}
+}
+
+/// The mail server used to email customers. Email is turned off unless
+/// `SMTP_HOST` is set.
+#[derive(Clone, Debug)]
+pub struct Smtp {
+	pub host :     String,
+	pub username : String,
+	pub password : String,
+	pub from :     String,
+}
+
+pub fn smtp() -> Option<Smtp> {
+	Some(Smtp {
+		host :     std::env::var("SMTP_HOST").ok()?,
+		username : env_or("SMTP_USERNAME", ""),
+		password : env_or("SMTP_PASSWORD", ""),
+		from :     env_or("SMTP_FROM", "scarves@normorovers.com.au"),
+	})
+}
+
+/// Where customers paying by bank transfer send their money. Bank transfers
+/// are turned off unless all of the `BANK_*` environment variables are set.
+#[derive(Clone, Debug)]
+pub struct BankAccount {
+	pub name :   String,
+	pub bsb :    String,
+	pub number : String,
+}
+
+pub fn bank_account() -> Option<BankAccount> {
+	Some(BankAccount {
+		name :   std::env::var("BANK_ACCOUNT_NAME").ok()?,
+		bsb :    std::env::var("BANK_BSB").ok()?,
+		number : std::env::var("BANK_ACCOUNT_NUMBER").ok()?,
+	})
+}
+
+/// Where Stripe Checkout sends customers when they finish or give up paying.
+/// `{ORDER_ID}` in either URL is replaced with the order's ID. Checkout is
+/// turned off unless both `CHECKOUT_*_URL` environment variables are set.
+#[derive(Clone, Debug)]
+pub struct Checkout {
+	pub success_url : String,
+	pub cancel_url :  String,
+}
+
+pub fn checkout() -> Option<Checkout> {
+	Some(Checkout {
+		success_url : std::env::var("CHECKOUT_SUCCESS_URL").ok()?,
+		cancel_url :  std::env::var("CHECKOUT_CANCEL_URL").ok()?,
+	})
+}
+
+/// The signing secret of our Stripe webhook endpoint, from
+/// `STRIPE_WEBHOOK_SECRET`. Webhooks are refused without it.
+pub fn stripe_webhook_secret() -> Option<String> {
+	std::env::var("STRIPE_WEBHOOK_SECRET")
+		.ok()
+		.filter(|secret| !secret.is_empty())
+}
+
+/// Where requests to Stripe are sent, from `STRIPE_API_URL`. Only changed
+/// to point the API at a stand-in server in tests.
+pub fn stripe_url() -> String { env_or("STRIPE_API_URL", "https://api.stripe.com/") }
+
+/// Where requests to AusPost are sent, from `AUSPOST_API_URL`. Only changed
+/// to point the API at a stand-in server in tests.
+pub fn auspost_url() -> String { env_or("AUSPOST_API_URL", "https://digitalapi.auspost.com.au") }
+
+/// Settings the server can not work without that have not been set
+pub fn missing() -> Vec<&'static str> {
+	["AUSPOST_PAC_API"]
+		.iter()
+		.copied()
+		.filter(|key| {
+			std::env::var(key)
+				.map(|value| value.is_empty())
+				.unwrap_or(true)
+		})
+		.collect()
+}
+
+fn env_number(key : &str, default : i64) -> i64 {
+	std::env::var(key)
+		.ok()
+		.and_then(|value| value.parse().ok())
+		.unwrap_or(default)
+}
+
+fn env_or(key : &str, default : &str) -> String {
+	std::env::var(key).unwrap_or_else(|_| default.to_string())
+}
@@ -0,0 +1,329 @@
+//! Creating an order. Everything the order needs, including its postage and
+//! payment intent, is worked out first and the order is written in a single
+//! insert, so a failure part way through never leaves half an order behind.
+//! If the insert fails after the payment intent was made, the intent is
+//! cancelled again. Orders paid for outside the site get no payment intent;
+//! bank transfers are given a reference to quote instead.
+//!
+//! A request with an idempotency key claims the key before doing anything,
+//! so a retry sent while the first request is still running is turned away
+//! rather than making a second order and payment intent.
+
+use crate::{
+	catalogue::SCARF,
+	config,
+	db::{orders::email_key, FromDoc},
+	graphql::context::Context,
+	history::{EventKind, OrderEvent},
+	idempotency,
+	logging::Redacted,
+	metrics,
+	models::{
+		Address, Allocation, CollectionMethod, Group, LineItem, Order, OrderStatus, PaymentMethod,
+		PostDeliveryOption, Postage, PostageQuote,
+	},
+	order_number,
+	stripe::{call, cancel_payment, get_idempotent_stripe, get_stripe, payment_description},
+};
+use chrono::{Datelike, Utc};
+use futures::try_join;
+use juniper::{graphql_value, FieldError};
+use mongodb::bson::{oid::ObjectId, Bson};
+use tracing::info;
+
+/// A validated newOrder request
+pub struct NewOrder {
+	pub name :            String,
+	pub email :           String,
+	pub items :           Vec<LineItem>,
+	pub group :           Option<Group>,
+	pub allocations :     Vec<Allocation>,
+	pub method :          CollectionMethod,
+	pub pickup_location : Option<String>,
+	pub address :         Option<Address>,
+	pub payment :         PaymentMethod,
+	/// Whether an offline payment has already been received. Card orders are
+	/// always paid later.
+	pub paid :            bool,
+	pub idempotency_key : Option<String>,
+}
+
+pub enum CreateError {
+	NumberUnavailable,
+	PostageUnavailable,
+	PaymentMethodUnavailable,
+	InProgress,
+	PaymentError,
+	DatabaseError,
+}
+
+impl CreateError {
+	pub fn to_field_error(&self) -> FieldError {
+		let (message, code) = self.describe();
+		FieldError::new(message, graphql_value!({ "type": code }))
+	}
+
+	pub fn message(&self) -> &'static str { self.describe().0 }
+
+	fn describe(&self) -> (&'static str, &'static str) {
+		match self {
+			CreateError::NumberUnavailable => {
+				("Failed to allocate an order number", "DATABASE_ERROR")
+			},
+			CreateError::PostageUnavailable => (
+				"Postage could not be calculated for this order",
+				"POSTAGE_UNAVAILABLE",
+			),
+			CreateError::PaymentMethodUnavailable => (
+				"This payment method can not be used for this order",
+				"PAYMENT_METHOD_UNAVAILABLE",
+			),
+			CreateError::InProgress => (
+				"This order is still being created, please try again shortly",
+				"IN_PROGRESS",
+			),
+			CreateError::PaymentError => ("Failed to create payment intent", "PAYMENT_ERROR"),
+			CreateError::DatabaseError => ("Failed to create the order", "DATABASE_ERROR"),
+		}
+	}
+}
+
+fn quantity(new : &NewOrder) -> i32 { new.items.iter().map(|item| item.quantity).sum() }
+
+/// The postage options for the order, with the default option locked in
+async fn postage(new : &NewOrder) -> Result<(Vec<PostageQuote>, Option<Postage>), CreateError> {
+	let address = match (new.method, &new.address) {
+		(CollectionMethod::Post, Some(address)) => address,
+		_ => return Ok((vec![], None)),
+	};
+
+	let options = PostDeliveryOption::get(quantity(new) as u32, address)
+		.await
+		.map_err(|_| CreateError::PostageUnavailable)?;
+	let quotes = PostageQuote::issue(options);
+
+	let default_code = PostDeliveryOption::default_code(address);
+	let postage = quotes
+		.iter()
+		.find(|quote| quote.code == default_code)
+		.map(Postage::from_quote)
+		.ok_or(CreateError::PostageUnavailable)?;
+
+	Ok((quotes, Some(postage)))
+}
+
+/// Cash is only taken at pickup, bank transfers need our account details,
+/// and anything else has to be arranged by the committee
+fn check_payment(context : &Context, new : &NewOrder) -> Result<(), CreateError> {
+	let allowed = match new.payment {
+		PaymentMethod::Card => true,
+		PaymentMethod::BankTransfer => config::bank_account().is_some(),
+		PaymentMethod::Cash => new.method == CollectionMethod::Pickup,
+		PaymentMethod::Offline => context.admin,
+	};
+
+	match allowed {
+		true => Ok(()),
+		false => Err(CreateError::PaymentMethodUnavailable),
+	}
+}
+
+pub async fn create(context : &Context, new : NewOrder) -> Result<Order, CreateError> {
+	check_payment(context, &new)?;
+	let key = match &new.idempotency_key {
+		Some(key) => key.to_owned(),
+		None => return insert(context, &new).await,
+	};
+
+	if let Some(order) = idempotency::created_order(context, &key).await {
+		return Ok(order);
+	}
+	// Each attempt gets its own order number and ID, which Stripe would refuse
+	// under the same key, so only one request with the key can make the order
+	if !idempotency::claim_new_order(context, &key).await {
+		return idempotency::created_order(context, &key)
+			.await
+			.ok_or(CreateError::InProgress);
+	}
+
+	let created = insert(context, &new).await;
+	if created.is_err() {
+		idempotency::release_new_order(context, &key).await;
+	}
+	created
+}
+
+async fn insert(context : &Context, new : &NewOrder) -> Result<Order, CreateError> {
+	// AusPost and the counter do not depend on each other
+	let counters = context.counters_handel();
+	let ((quotes, postage), number) = try_join!(postage(new), async {
+		order_number::allocate(&counters, Utc::now().year())
+			.await
+			.ok_or(CreateError::NumberUnavailable)
+	})?;
+	let id = ObjectId::new();
+	let quantity = quantity(new);
+	let amount =
+		SCARF.price as i64 * i64::from(quantity) + postage.as_ref().map(|p| p.price).unwrap_or(0);
+
+	let pi = match new.payment {
+		PaymentMethod::Card => Some(payment_intent(new, &id, &number, amount).await?),
+		_ => None,
+	};
+	let paid = new.paid && pi.is_none();
+	let status = match paid {
+		true => OrderStatus::Paid,
+		false => OrderStatus::Unpaid,
+	};
+
+	let now = Utc::now();
+	let mut events = vec![OrderEvent {
+		new : Some(number.to_owned()),
+		..OrderEvent::new(EventKind::Created, context.actor())
+	}];
+	if let Some(pi) = &pi {
+		events.push(OrderEvent::change(
+			EventKind::PaymentStarted,
+			context.actor(),
+			"payment",
+			None,
+			Some(pi.id.to_string()),
+		));
+	}
+	if paid {
+		events.push(OrderEvent::change(
+			EventKind::PaymentSucceeded,
+			context.actor(),
+			"status",
+			Some(OrderStatus::Unpaid.code().to_string()),
+			Some(OrderStatus::Paid.code().to_string()),
+		));
+	}
+	let history : Vec<Bson> = events.iter().map(|e| Bson::Document(e.to_doc())).collect();
+	let quote_docs : Vec<Bson> = quotes.iter().map(|q| Bson::Document(q.to_doc())).collect();
+	let item_docs : Vec<Bson> = new
+		.items
+		.iter()
+		.map(|i| Bson::Document(i.to_doc()))
+		.collect();
+
+	let mut payment = doc! { "method": new.payment.code() };
+	if let Some(pi) = &pi {
+		payment.insert("stripe", doc! { "pi": pi.id.as_str() });
+	}
+	if new.payment == PaymentMethod::BankTransfer {
+		payment.insert("reference", order_number::bank_reference(&number));
+	}
+	if paid {
+		payment.insert("amount", amount);
+	}
+
+	let mut order_doc = doc! {
+		"_id": id,
+		"number": &number,
+		"quantity": quantity,
+		"items": item_docs,
+		"user": {
+			"name": &new.name,
+			"email": &new.email,
+			"email_lower": email_key(&new.email),
+		},
+		"method": new.method.code(),
+		"pickup_location": match (new.method, &new.pickup_location) {
+			(CollectionMethod::Pickup, Some(l)) => Bson::String(l.to_owned()),
+			_ => Bson::Null,
+		},
+		"address": match &new.address {
+			Some(address) => Bson::Document(address.to_doc()),
+			None => Bson::Null,
+		},
+		"postage": match &postage {
+			Some(postage) => Bson::Document(postage.to_doc()),
+			None => Bson::Null,
+		},
+		"quotes": quote_docs,
+		"payment": payment,
+		"status": status.code(),
+		"created_at": Bson::DateTime(now.into()),
+		"updated_at": Bson::DateTime(now.into()),
+		"history": history,
+	};
+	if let Some(key) = &new.idempotency_key {
+		order_doc.insert("idempotency_key", key.to_owned());
+	}
+	if let Some(group) = &new.group {
+		let allocations : Vec<Bson> = new
+			.allocations
+			.iter()
+			.map(|a| Bson::Document(a.to_doc()))
+			.collect();
+		order_doc.insert("group", group.to_doc());
+		order_doc.insert("allocations", allocations);
+	}
+
+	if context
+		.orders_handel()
+		.insert_one(order_doc.clone(), None)
+		.await
+		.is_err()
+	{
+		// Nothing refers to the payment intent now, so it must never be paid
+		if let Some(pi) = &pi {
+			cancel_payment(&get_stripe(), pi.id.as_str()).await;
+		}
+		return Err(CreateError::DatabaseError);
+	}
+
+	metrics::order_created(new.method);
+	if paid {
+		metrics::payment_succeeded(amount);
+	}
+	info!(
+		order = number.as_str(),
+		name = %Redacted(&new.name),
+		email = %Redacted(&new.email),
+		quantity,
+		"order created",
+	);
+
+	let mut order = Order::from_doc(order_doc);
+	let stripe = order.payment.as_mut().and_then(|p| p.stripe.as_mut());
+	if let (Some(stripe), Some(pi)) = (stripe, pi) {
+		stripe.client_secret = pi.client_secret;
+	}
+
+	Ok(order)
+}
+
+/// A payment intent for the whole order, which the customer pays on the site
+async fn payment_intent(
+	new : &NewOrder,
+	id : &ObjectId,
+	number : &str,
+	amount : i64,
+) -> Result<stripe::PaymentIntent, CreateError> {
+	let mut params = stripe::CreatePaymentIntent::new(amount, stripe::Currency::AUD);
+	let desc = payment_description(number, &new.name, quantity(new), new.method);
+	params.description = Some(&desc);
+
+	let mut meta = stripe::Metadata::new();
+	meta.insert("email".to_string(), new.email.to_owned());
+	meta.insert("quantity".to_string(), quantity(new).to_string());
+	meta.insert("order_number".to_string(), number.to_owned());
+	meta.insert("order_id".to_string(), id.to_string());
+	params.metadata = Some(meta);
+
+	// The key only covers retries of this request. A new attempt at the order
+	// has a new ID and so makes its own payment intent.
+	let client = get_idempotent_stripe(idempotency::stripe_key(
+		new.idempotency_key.as_deref(),
+		&format!("payment-intent:{}", id),
+	));
+	// Without a key Stripe would make a second payment intent on a retry
+	let retry = new.idempotency_key.is_some();
+	call(retry, || {
+		stripe::PaymentIntent::create(&client, params.clone())
+	})
+	.await
+	.ok_or(CreateError::PaymentError)
+}
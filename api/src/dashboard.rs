@@ -0,0 +1,334 @@
+//! Live sales figures for the committee. Everything is worked out by Mongo
+//! in a single aggregation, with one facet for each way the sales are split
+//! up. An order counts as sold once it has been paid for, including if it
+//! was refunded later, and its refunds come off the net figures.
+//!
+//! Orders store what was charged for them once they are paid, and that is
+//! what is counted. Orders paid before it was stored are counted at the
+//! current catalogue price. Stripe's webhook marks card orders as paid as
+//! soon as they are, so the stored statuses are current.
+
+use crate::{
+	address::DOMESTIC_COUNTRY,
+	catalogue::SCARF,
+	db::id_at,
+	models::{CollectionMethod, OrderStatus},
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+	bson::{Bson, Document},
+	Collection,
+};
+
+/// Days are counted where the sale is run
+const TIMEZONE : &str = "Australia/Sydney";
+
+/// Orders, scarves and money, in cents, for one slice of the sales
+#[derive(Clone, Debug, Default)]
+pub struct Sales {
+	/// What the sales are grouped by, such as the day or the state
+	pub key :      String,
+	pub orders :   i64,
+	pub scarves :  i64,
+	/// Everything customers paid, including postage
+	pub gross :    i64,
+	pub postage :  i64,
+	pub refunded : i64,
+}
+
+impl Sales {
+	/// Money for the scarves that was kept, without postage or refunds
+	pub fn net(&self) -> i64 { self.gross - self.postage - self.refunded }
+
+	pub fn average_scarves(&self) -> f64 { average(self.scarves, self.orders) }
+
+	/// Average amount paid for an order, in cents
+	pub fn average_value(&self) -> f64 { average(self.gross, self.orders) }
+
+	fn from_doc(item : &Document) -> Self {
+		Self {
+			key :      match item.get("_id") {
+				Some(Bson::String(key)) => key.to_owned(),
+				_ => String::new(),
+			},
+			orders :   number(item, "orders"),
+			scarves :  number(item, "scarves"),
+			gross :    number(item, "gross"),
+			postage :  number(item, "postage"),
+			refunded : number(item, "refunded"),
+		}
+	}
+}
+
+/// Scarves sold of one variant. Postage and refunds are for whole orders so
+/// they are not split between variants.
+#[derive(Clone, Debug)]
+pub struct VariantSales {
+	pub variant : String,
+	pub orders :  i64,
+	pub scarves : i64,
+}
+
+impl VariantSales {
+	/// What the scarves sold for at the catalogue price, in cents. Orders are
+	/// charged as a whole, so what was charged is not split between variants.
+	pub fn revenue(&self) -> i64 { self.scarves * SCARF.price as i64 }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Dashboard {
+	pub totals :           Sales,
+	pub by_day :           Vec<Sales>,
+	pub by_method :        Vec<Sales>,
+	/// Australian state, or country code for overseas orders
+	pub by_state :         Vec<Sales>,
+	/// Domestic orders by the first two digits of their postcode
+	pub by_region :        Vec<Sales>,
+	pub by_variant :       Vec<VariantSales>,
+	pub unpaid_orders :    i64,
+	pub unpaid_scarves :   i64,
+	/// Orders that expired without being paid for
+	pub abandoned_orders : i64,
+}
+
+pub enum DashboardError {
+	DatabaseError,
+}
+
+/// Sales figures for orders placed in the given time, or all orders
+pub async fn dashboard(
+	coll : Collection<Document>,
+	from : Option<DateTime<Utc>>,
+	to : Option<DateTime<Utc>>,
+) -> Result<Dashboard, DashboardError> {
+	let mut cursor = coll
+		.aggregate(pipeline(from, to), None)
+		.await
+		.map_err(|_| DashboardError::DatabaseError)?;
+
+	let facets = match cursor.next().await {
+		Some(Ok(facets)) => facets,
+		Some(Err(_)) => return Err(DashboardError::DatabaseError),
+		None => return Ok(Dashboard::default()),
+	};
+
+	let statuses = facet(&facets, "statuses");
+	let status = |status : OrderStatus| {
+		statuses
+			.iter()
+			.find(|item| item.get_str("_id").ok() == Some(status.code()))
+			.cloned()
+			.unwrap_or_default()
+	};
+
+	Ok(Dashboard {
+		totals :           facet(&facets, "totals")
+			.first()
+			.map(Sales::from_doc)
+			.unwrap_or_default(),
+		by_day :           sales(&facets, "byDay"),
+		by_method :        sales(&facets, "byMethod"),
+		by_state :         sales(&facets, "byState"),
+		by_region :        sales(&facets, "byRegion"),
+		by_variant :       facet(&facets, "byVariant")
+			.iter()
+			.map(|item| VariantSales {
+				variant : item.get_str("_id").unwrap_or("").to_string(),
+				orders :  number(item, "orders"),
+				scarves : number(item, "scarves"),
+			})
+			.collect(),
+		unpaid_orders :    number(&status(OrderStatus::Unpaid), "orders"),
+		unpaid_scarves :   number(&status(OrderStatus::Unpaid), "scarves"),
+		abandoned_orders : number(&status(OrderStatus::Expired), "orders"),
+	})
+}
+
+fn pipeline(from : Option<DateTime<Utc>>, to : Option<DateTime<Utc>>) -> Vec<Document> {
+	// Order IDs start with the time they were created, so a date range is a
+	// range of IDs
+	let mut created = Document::new();
+	if let Some(from) = from {
+		created.insert("$gte", id_at(from));
+	}
+	if let Some(to) = to {
+		created.insert("$lt", id_at(to));
+	}
+	let window = match created.is_empty() {
+		true => doc! {},
+		false => doc! { "_id": created },
+	};
+
+	let sold = doc! {
+		"$match": {
+			"status": {
+				"$in": [
+					OrderStatus::Paid.code(),
+					OrderStatus::Packed.code(),
+					OrderStatus::Shipped.code(),
+					OrderStatus::Refunded.code(),
+				],
+			},
+		},
+	};
+	let sales = |key : Bson| {
+		doc! {
+			"$group": {
+				"_id": key,
+				"orders": { "$sum": 1 },
+				"scarves": { "$sum": "$_scarves" },
+				"gross": { "$sum": "$_gross" },
+				"postage": { "$sum": "$_postage" },
+				"refunded": { "$sum": "$_refunded" },
+			},
+		}
+	};
+	let by_key = doc! { "$sort": { "_id": 1 } };
+	let price = SCARF.price as i64;
+
+	// Older orders stored the postcode as an integer, which loses the leading
+	// zero of NT postcodes
+	let post_code = "$address.post_code";
+	let region = doc! {
+		"$substrCP": [
+			{
+				"$cond": [
+					{ "$and": [{ "$isNumber": post_code }, { "$lt": [post_code, 1000] }] },
+					{ "$concat": ["0", { "$toString": post_code }] },
+					{ "$toString": post_code },
+				],
+			},
+			0,
+			2,
+		],
+	};
+
+	vec![
+		doc! { "$match": window },
+		doc! {
+			"$addFields": {
+				"status": { "$ifNull": ["$status", OrderStatus::Unpaid.code()] },
+				"_scarves": { "$ifNull": ["$quantity", 0] },
+				"_postage": { "$ifNull": ["$postage.price", 0] },
+				"_refunded": { "$sum": "$refunds.amount" },
+				"_day": {
+					"$dateToString": {
+						"format": "%Y-%m-%d",
+						"date": { "$toDate": "$_id" },
+						"timezone": TIMEZONE,
+					},
+				},
+			},
+		},
+		doc! {
+			"$addFields": {
+				"_gross": {
+					"$ifNull": [
+						"$payment.amount",
+						{ "$add": [{ "$multiply": ["$_scarves", price] }, "$_postage"] },
+					],
+				},
+			},
+		},
+		doc! {
+			"$facet": {
+				"statuses": [{
+					"$group": {
+						"_id": "$status",
+						"orders": { "$sum": 1 },
+						"scarves": { "$sum": "$_scarves" },
+					},
+				}],
+				"totals": [sold.clone(), sales(Bson::Null)],
+				"byDay": [sold.clone(), sales("$_day".into()), by_key.clone()],
+				"byMethod": [
+					sold.clone(),
+					sales(Bson::Document(doc! {
+						"$cond": [
+							{ "$eq": ["$method", CollectionMethod::Pickup.code()] },
+							"PICKUP",
+							"POST",
+						],
+					})),
+					by_key.clone(),
+				],
+				"byState": [
+					sold.clone(),
+					sales(Bson::Document(doc! {
+						"$ifNull": ["$address.state", { "$ifNull": ["$address.country", "NONE"] }],
+					})),
+					by_key.clone(),
+				],
+				"byRegion": [
+					sold.clone(),
+					{
+						"$match": {
+							"address.post_code": { "$exists": true },
+							"address.country": { "$in": [DOMESTIC_COUNTRY, Bson::Null] },
+						},
+					},
+					sales(Bson::Document(region)),
+					by_key.clone(),
+				],
+				"byVariant": [
+					sold,
+					// Orders placed before variants existed are all the default variant
+					{
+						"$project": {
+							"items": {
+								"$ifNull": ["$items", [{
+									"variant": SCARF.default_variant().code,
+									"quantity": "$quantity",
+								}]],
+							},
+						},
+					},
+					{ "$unwind": "$items" },
+					{
+						"$group": {
+							"_id": "$items.variant",
+							"orders": { "$sum": 1 },
+							"scarves": { "$sum": "$items.quantity" },
+						},
+					},
+					by_key,
+				],
+			},
+		},
+	]
+}
+
+fn facet(facets : &Document, key : &str) -> Vec<Document> {
+	match facets.get_array(key) {
+		Ok(items) => items
+			.iter()
+			.filter_map(|item| match item {
+				Bson::Document(d) => Some(d.to_owned()),
+				_ => None,
+			})
+			.collect(),
+		_ => vec![],
+	}
+}
+
+fn sales(facets : &Document, key : &str) -> Vec<Sales> {
+	facet(facets, key).iter().map(Sales::from_doc).collect()
+}
+
+/// Sums come back as whichever number type fits them
+fn number(item : &Document, key : &str) -> i64 {
+	match item.get(key) {
+		Some(Bson::Int32(n)) => i64::from(*n),
+		Some(Bson::Int64(n)) => *n,
+		Some(Bson::Double(n)) => *n as i64,
+		_ => 0,
+	}
+}
+
+fn average(total : i64, count : i64) -> f64 {
+	match count {
+		0 => 0.0,
+		_ => total as f64 / count as f64,
+	}
+}
@@ -1,24 +1,34 @@
-use crate::db::FromDoc;
-use mongodb::{coll::Collection, oid::ObjectId};
+use crate::db::{self, FromDoc};
+use mongodb::{
+	bson::{oid::ObjectId, Document},
+	Collection,
+};
 
-pub fn all<T : FromDoc>(coll : Collection) -> Vec<T> {
-	coll.find(None, None)
-		.unwrap()
-		.into_iter()
-		.filter_map(|item| match item {
-			Ok(item) => Some(T::from_doc(item)),
-			Err(_) => None,
-		})
-		.collect()
+pub async fn all<T : FromDoc>(coll : Collection<Document>) -> Vec<T> {
+	db::collect(coll.find(None, None).await.unwrap()).await
 }
 
-pub fn get<T : FromDoc>(coll : Collection, id : ObjectId) -> Option<T> {
-	match coll.find_one(
-		Some(doc! {
-			"_id" => id,
-		}),
-		None,
-	) {
+pub async fn find<T : FromDoc>(coll : Collection<Document>, filter : Document) -> Vec<T> {
+	db::collect(coll.find(filter, None).await.unwrap()).await
+}
+
+pub async fn find_one<T : FromDoc>(coll : Collection<Document>, filter : Document) -> Option<T> {
+	match coll.find_one(filter, None).await {
+		Ok(Some(o)) => Some(T::from_doc(o)),
+		_ => None,
+	}
+}
+
+pub async fn get<T : FromDoc>(coll : Collection<Document>, id : ObjectId) -> Option<T> {
+	match coll
+		.find_one(
+			doc! {
+				"_id": id,
+			},
+			None,
+		)
+		.await
+	{
 		Ok(Some(o)) => Some(T::from_doc(o)),
 		_ => None,
 	}
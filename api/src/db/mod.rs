@@ -1,12 +1,93 @@
-use mongodb::Document;
-use rocket_contrib::database;
+use crate::logging::CommandLogger;
+use chrono::{DateTime, Utc};
+use futures::{future, StreamExt};
+use mongodb::{
+	bson::{oid::ObjectId, Document},
+	options::ClientOptions,
+	Client, Cursor, Database,
+};
+use rocket::{
+	fairing::{AdHoc, Fairing},
+	figment::Figment,
+	http::Status,
+	outcome::Outcome,
+	request::{self, FromRequest, Request},
+};
+use std::{ops::Deref, sync::Arc};
 
 pub mod helpers;
+pub mod orders;
 
-#[database("primary_db")]
+/// The database named in `databases.primary_db.url` in Rocket.toml. The
+/// driver keeps its own connection pool, so each request gets a clone.
+#[derive(Clone)]
+pub struct PrimaryDb(pub Database);
 
-pub struct PrimaryDb(pub mongodb::db::Database);
+impl PrimaryDb {
+	/// Connect when the server starts, failing the launch if the URL is
+	/// missing or does not name a database
+	pub fn fairing() -> impl Fairing {
+		AdHoc::try_on_ignite("Primary database", |rocket| async move {
+			match Self::connect(rocket.figment()).await {
+				Some(db) => Ok(rocket.manage(db)),
+				None => Err(rocket),
+			}
+		})
+	}
+
+	/// Connect to the database named in the configuration, for commands that
+	/// run without the server
+	pub async fn connect(figment : &Figment) -> Option<Self> {
+		let url : String = figment.extract_inner("databases.primary_db.url").ok()?;
+
+		let mut options = ClientOptions::parse(&url).await.ok()?;
+		options.command_event_handler = Some(Arc::new(CommandLogger));
+
+		Some(PrimaryDb(
+			Client::with_options(options).ok()?.default_database()?,
+		))
+	}
+}
+
+impl Deref for PrimaryDb {
+	type Target = Database;
+
+	fn deref(&self) -> &Database { &self.0 }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PrimaryDb {
+	type Error = ();
+
+	async fn from_request(request : &'r Request<'_>) -> request::Outcome<Self, ()> {
+		match request.rocket().state::<PrimaryDb>() {
+			Some(db) => Outcome::Success(db.clone()),
+			None => Outcome::Error((Status::ServiceUnavailable, ())),
+		}
+	}
+}
 
 pub trait FromDoc {
 	fn from_doc(item : Document) -> Self;
 }
+
+/// Read every document from a cursor, skipping any that fail to load
+pub async fn collect<T : FromDoc>(cursor : Cursor<Document>) -> Vec<T> {
+	cursor
+		.filter_map(|item| {
+			future::ready(match item {
+				Ok(item) => Some(T::from_doc(item)),
+				Err(_) => None,
+			})
+		})
+		.collect()
+		.await
+}
+
+/// The smallest ID of a document created at the given time. IDs start with
+/// the time they were made, so a range of times is a range of IDs.
+pub fn id_at(at : DateTime<Utc>) -> ObjectId {
+	let mut bytes = [0; 12];
+	bytes[..4].copy_from_slice(&(at.timestamp() as u32).to_be_bytes());
+	ObjectId::from_bytes(bytes)
+}
@@ -0,0 +1,354 @@
+use crate::{
+	address::AustralianState,
+	db::{self, id_at},
+	models::{CollectionMethod, Order, OrderStatus},
+};
+use chrono::{DateTime, Utc};
+use juniper::{GraphQLEnum, GraphQLInputObject};
+use mongodb::{
+	bson::{oid::ObjectId, Bson, Document},
+	options::{FindOptions, IndexOptions},
+	Collection, IndexModel,
+};
+
+/// Orders returned when a page size is not given
+pub const DEFAULT_PAGE_SIZE : i32 = 50;
+pub const MAX_PAGE_SIZE : i32 = 200;
+
+#[derive(GraphQLInputObject, Clone, Debug, Default)]
+#[graphql(description = "Only return orders matching every field that is set")]
+pub struct OrderFilter {
+	pub status :         Option<OrderStatus>,
+	pub method :         Option<CollectionMethod>,
+	/// Paid, packed and shipped orders are paid. Refunded orders are not.
+	/// Card orders are marked as paid when Stripe's webhook says so.
+	pub paid :           Option<bool>,
+	pub post_code :      Option<String>,
+	pub state :          Option<AustralianState>,
+	/// Exact email address, ignoring case
+	pub email :          Option<String>,
+	pub created_after :  Option<DateTime<Utc>>,
+	pub created_before : Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug)]
+pub enum OrderSortField {
+	CreatedAt,
+	Quantity,
+	Name,
+	Email,
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug)]
+pub enum SortDirection {
+	Asc,
+	Desc,
+}
+
+#[derive(GraphQLInputObject, Clone, Copy, Debug)]
+pub struct OrderSort {
+	pub field :     OrderSortField,
+	pub direction : SortDirection,
+}
+
+impl Default for OrderSort {
+	/// Newest orders first
+	fn default() -> Self {
+		Self {
+			field :     OrderSortField::CreatedAt,
+			direction : SortDirection::Desc,
+		}
+	}
+}
+
+impl OrderSort {
+	/// The document key being sorted on. Orders are always sorted by ID last,
+	/// which is also the order they were created in.
+	fn key(&self) -> Option<&'static str> {
+		match self.field {
+			OrderSortField::CreatedAt => None,
+			OrderSortField::Quantity => Some("quantity"),
+			OrderSortField::Name => Some("user.name"),
+			OrderSortField::Email => Some("user.email"),
+		}
+	}
+
+	fn direction(&self) -> i32 {
+		match self.direction {
+			SortDirection::Asc => 1,
+			SortDirection::Desc => -1,
+		}
+	}
+
+	fn comparison(&self) -> &'static str {
+		match self.direction {
+			SortDirection::Asc => "$gt",
+			SortDirection::Desc => "$lt",
+		}
+	}
+
+	fn sort_doc(&self) -> Document {
+		let mut sort = Document::new();
+		if let Some(key) = self.key() {
+			sort.insert(key, self.direction());
+		}
+		sort.insert("_id", self.direction());
+		sort
+	}
+
+	fn value(&self, order : &Order) -> serde_json::Value {
+		match self.field {
+			OrderSortField::CreatedAt => serde_json::Value::Null,
+			OrderSortField::Quantity => serde_json::Value::from(order.quantity),
+			OrderSortField::Name => serde_json::Value::from(order.user.name.to_owned()),
+			OrderSortField::Email => serde_json::Value::from(order.user.email.to_owned()),
+		}
+	}
+
+	/// Opaque cursor pointing just after the order in this sort
+	pub fn cursor(&self, order : &Order) -> String {
+		let cursor = serde_json::json!([self.value(order), order.id.to_string()]);
+		base64::encode_config(&cursor.to_string(), base64::URL_SAFE_NO_PAD)
+	}
+
+	/// Only match orders that come after the cursor in this sort
+	fn after(&self, cursor : &str) -> Result<Document, OrderQueryError> {
+		let cursor = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+			.map_err(|_| OrderQueryError::InvalidCursor)?;
+		let cursor : (serde_json::Value, String) =
+			serde_json::from_slice(&cursor).map_err(|_| OrderQueryError::InvalidCursor)?;
+
+		let id = ObjectId::parse_str(&cursor.1).map_err(|_| OrderQueryError::InvalidCursor)?;
+		let value = match cursor.0 {
+			serde_json::Value::Number(n) => match n.as_i64() {
+				Some(n) => Bson::Int32(n as i32),
+				None => return Err(OrderQueryError::InvalidCursor),
+			},
+			serde_json::Value::String(s) => Bson::String(s),
+			_ => Bson::Null,
+		};
+
+		let mut after_id = Document::new();
+		after_id.insert(self.comparison(), id);
+
+		Ok(match self.key() {
+			None => doc! { "_id": after_id },
+			Some(key) => {
+				let mut after_value = Document::new();
+				after_value.insert(self.comparison(), value.clone());
+
+				let mut past = Document::new();
+				past.insert(key, after_value);
+
+				let mut tied = Document::new();
+				tied.insert(key, value);
+				tied.insert("_id", after_id);
+
+				doc! { "$or": [past, tied] }
+			},
+		})
+	}
+}
+
+pub enum OrderQueryError {
+	InvalidCursor,
+	DatabaseError,
+}
+
+/// One page of orders, in the style of a Relay connection
+pub struct OrderConnection {
+	pub edges :         Vec<OrderEdge>,
+	pub has_next_page : bool,
+	pub has_previous :  bool,
+	pub total_count :   i32,
+}
+
+pub struct OrderEdge {
+	pub cursor : String,
+	pub node :   Order,
+}
+
+impl OrderFilter {
+	pub fn to_doc(&self) -> Document {
+		let mut filter = Document::new();
+
+		if let Some(status) = self.status {
+			filter.insert(
+				"status",
+				match status {
+					// Orders from before statuses were stored have not been paid
+					OrderStatus::Unpaid => Bson::Document(doc! {
+						"$in": [OrderStatus::Unpaid.code(), Bson::Null]
+					}),
+					_ => Bson::String(status.code().to_string()),
+				},
+			);
+		}
+
+		if let Some(method) = self.method {
+			filter.insert("method", method.code());
+		}
+
+		if let Some(paid) = self.paid {
+			let paid_statuses = vec![
+				Bson::String(OrderStatus::Paid.code().to_string()),
+				Bson::String(OrderStatus::Packed.code().to_string()),
+				Bson::String(OrderStatus::Shipped.code().to_string()),
+			];
+			filter.insert(
+				"$and",
+				vec![Bson::Document(match paid {
+					true => doc! { "status": { "$in": paid_statuses } },
+					false => doc! { "status": { "$nin": paid_statuses } },
+				})],
+			);
+		}
+
+		if let Some(post_code) = &self.post_code {
+			// Older orders stored the postcode as an integer
+			let mut post_codes = vec![Bson::String(post_code.trim().to_string())];
+			if let Ok(legacy) = post_code.trim().parse::<i32>() {
+				post_codes.push(Bson::Int32(legacy));
+			}
+			filter.insert("address.post_code", doc! { "$in": post_codes });
+		}
+
+		if let Some(state) = self.state {
+			filter.insert("address.state", state.code());
+		}
+
+		if let Some(email) = &self.email {
+			filter.insert("user.email_lower", email_key(email));
+		}
+
+		// Order IDs start with the time they were created, so a date range is a
+		// range of IDs
+		let mut created = Document::new();
+		if let Some(after) = self.created_after {
+			created.insert("$gte", id_at(after));
+		}
+		if let Some(before) = self.created_before {
+			created.insert("$lt", id_at(before));
+		}
+		if !created.is_empty() {
+			filter.insert("_id", created);
+		}
+
+		filter
+	}
+}
+
+/// Get a page of orders matching the filter
+pub async fn page(
+	coll : Collection<Document>,
+	filter : &OrderFilter,
+	sort : &OrderSort,
+	first : Option<i32>,
+	after : Option<String>,
+) -> Result<OrderConnection, OrderQueryError> {
+	let first = first.unwrap_or(DEFAULT_PAGE_SIZE).max(0).min(MAX_PAGE_SIZE);
+
+	let filter = filter.to_doc();
+
+	let total_count = coll
+		.count_documents(filter.clone(), None)
+		.await
+		.map_err(|_| OrderQueryError::DatabaseError)?;
+
+	let query = match &after {
+		Some(cursor) => doc! { "$and": [filter, sort.after(cursor)?] },
+		None => filter,
+	};
+
+	let options = FindOptions::builder()
+		.sort(sort.sort_doc())
+		// Fetch one extra to find out if there is another page
+		.limit(i64::from(first) + 1)
+		.build();
+
+	let cursor = coll
+		.find(query, options)
+		.await
+		.map_err(|_| OrderQueryError::DatabaseError)?;
+	let mut orders : Vec<Order> = db::collect(cursor).await;
+
+	let has_next_page = orders.len() > first as usize;
+	orders.truncate(first as usize);
+
+	Ok(OrderConnection {
+		edges : orders
+			.into_iter()
+			.map(|order| OrderEdge {
+				cursor : sort.cursor(&order),
+				node :   order,
+			})
+			.collect(),
+		has_next_page,
+		has_previous : after.is_some(),
+		total_count : total_count as i32,
+	})
+}
+
+/// Create the indexes that back the filters and sorts above
+pub async fn create_indexes(coll : &Collection<Document>) {
+	let indexes = vec![
+		doc! { "status": 1, "_id": -1 },
+		doc! { "method": 1, "_id": -1 },
+		doc! { "address.post_code": 1 },
+		doc! { "address.state": 1, "_id": -1 },
+		doc! { "user.email": 1 },
+		doc! { "user.email_lower": 1 },
+		doc! { "user.name": 1, "_id": 1 },
+		doc! { "quantity": 1, "_id": 1 },
+		doc! { "batch_id": 1 },
+	];
+
+	for keys in indexes {
+		coll.create_index(IndexModel::builder().keys(keys).build(), None)
+			.await
+			.expect("Creating order index failed");
+	}
+
+	// Orders from before numbers were allocated do not have one, and keys are
+	// optional
+	for (key, name) in &[("number", "number"), ("idempotency_key", "idempotency key")] {
+		let mut keys = Document::new();
+		keys.insert(*key, 1);
+
+		let index = IndexModel::builder()
+			.keys(keys)
+			.options(IndexOptions::builder().unique(true).sparse(true).build())
+			.build();
+		coll.create_index(index, None)
+			.await
+			.unwrap_or_else(|_| panic!("Creating order {} index failed", name));
+	}
+}
+
+/// How an email address is stored for looking orders up by it, so that
+/// matching ignores case and can still use an index
+pub fn email_key(email : &str) -> String { email.trim().to_lowercase() }
+
+/// Store the lowercased email of orders from before it was kept
+pub async fn backfill_email_keys(coll : &Collection<Document>) {
+	coll.update_many(
+		doc! { "user.email_lower": { "$exists": false } },
+		vec![doc! {
+			"$set": { "user.email_lower": { "$toLower": { "$trim": { "input": "$user.email" } } } },
+		}],
+		None,
+	)
+	.await
+	.expect("Storing lowercased emails failed");
+}
+
+pub fn escape_regex(text : &str) -> String {
+	text.chars()
+		.map(|c| match c {
+			'\\' | '^' | '$' | '.' | '|' | '?' | '*' | '+' | '(' | ')' | '[' | ']' | '{' | '}' => {
+				format!("\\{}", c)
+			},
+			_ => c.to_string(),
+		})
+		.collect()
+}
@@ -0,0 +1,449 @@
+//! Changes a customer can make to their own order before it is paid for.
+//! Every edit reprices the order, updates the payment intent to the new
+//! total and is recorded in the order history. Once an order is paid the
+//! committee has to make any changes, since the payment would no longer
+//! match.
+
+use crate::{
+	address::AddressError,
+	catalogue::SCARF,
+	checkout,
+	db::helpers as DBHelper,
+	graphql::context::Context,
+	history::{self, EventKind, OrderEvent},
+	idempotency,
+	models::{
+		Address, Allocation, CollectionMethod, LineItem, Order, OrderStatus, PaymentMethod,
+		PostDeliveryOption, Postage, PostageQuote,
+	},
+	stripe::{get_idempotent_stripe, payment_description, set_amount},
+};
+use juniper::{graphql_value, FieldError};
+use mongodb::bson::{oid::ObjectId, Bson};
+
+pub enum EditError {
+	InvalidId,
+	NotFound,
+	AlreadyPaid,
+	Closed,
+	InvalidQuantity,
+	InvalidVariant,
+	InvalidAddress(Vec<AddressError>),
+	NotPosted,
+	GroupOrder,
+	NotGroupOrder,
+	AllocationNotFound,
+	PostageUnavailable,
+	CashNeedsPickup,
+	PaymentError,
+	DatabaseError,
+}
+
+impl EditError {
+	pub fn to_field_error(&self) -> FieldError {
+		let (message, code) = match self {
+			EditError::InvalidAddress(errors) => return AddressError::to_field_error(errors),
+			EditError::InvalidId => ("UID is not valid", "INVALID_UID"),
+			EditError::NotFound => ("The requested order was not found", "NOT_FOUND"),
+			EditError::AlreadyPaid => (
+				"This order has been paid for, please contact us to change it",
+				"ALREADY_PAID",
+			),
+			EditError::Closed => (
+				"This order has been cancelled or has expired",
+				"ORDER_CLOSED",
+			),
+			EditError::InvalidQuantity => ("Quantity must be greater than 0", "INVALID_QUANTITY"),
+			EditError::InvalidVariant => (
+				"The order does not have a single item of this variant",
+				"INVALID_VARIANT",
+			),
+			EditError::NotPosted => ("This order is not being posted", "NOT_POSTED"),
+			EditError::GroupOrder => (
+				"The quantities of a group order come from its allocations",
+				"GROUP_ORDER",
+			),
+			EditError::NotGroupOrder => ("This order is not for a group", "NOT_GROUP_ORDER"),
+			EditError::AllocationNotFound => (
+				"The allocation is not part of this order",
+				"ALLOCATION_NOT_FOUND",
+			),
+			EditError::PostageUnavailable => (
+				"Postage could not be calculated for this order",
+				"POSTAGE_UNAVAILABLE",
+			),
+			EditError::CashNeedsPickup => (
+				"Orders paid in cash have to be picked up",
+				"CASH_NEEDS_PICKUP",
+			),
+			EditError::PaymentError => ("Failed to update payment intent", "PAYMENT_ERROR"),
+			EditError::DatabaseError => ("Failed to update the order", "DATABASE_ERROR"),
+		};
+
+		FieldError::new(message, graphql_value!({ "type": code }))
+	}
+}
+
+/// Get an order that can still be edited. The payment is checked first so an
+/// order that has just been paid can not be changed underneath it.
+pub async fn editable(context : &Context, id : &str) -> Result<Order, EditError> {
+	let id = ObjectId::parse_str(id).map_err(|_| EditError::InvalidId)?;
+	let mut order : Order = DBHelper::get(context.orders_handel(), id)
+		.await
+		.ok_or(EditError::NotFound)?;
+
+	order.refresh_status(&context.orders_handel()).await;
+	match order.status {
+		OrderStatus::Unpaid => Ok(order),
+		OrderStatus::Cancelled | OrderStatus::Expired => Err(EditError::Closed),
+		_ => Err(EditError::AlreadyPaid),
+	}
+}
+
+pub async fn update_quantity(
+	context : &Context,
+	id : &str,
+	variant : Option<String>,
+	quantity : i32,
+	idempotency_key : Option<&str>,
+) -> Result<Order, EditError> {
+	if quantity < 1 {
+		return Err(EditError::InvalidQuantity);
+	}
+
+	let before = editable(context, id).await?;
+	if before.group.is_some() {
+		return Err(EditError::GroupOrder);
+	}
+	let mut after = before.clone();
+
+	// Without a variant the order has to only have one item to change
+	let line : &mut LineItem = match (variant, after.items.len()) {
+		(Some(code), _) => after
+			.items
+			.iter_mut()
+			.find(|item| item.variant == code)
+			.ok_or(EditError::InvalidVariant)?,
+		(None, 1) => &mut after.items[0],
+		(None, _) => return Err(EditError::InvalidVariant),
+	};
+	line.quantity = quantity;
+	after.quantity = after.items.iter().map(|item| item.quantity).sum();
+
+	let event = OrderEvent::change(
+		EventKind::Edited,
+		context.actor(),
+		"quantity",
+		Some(before.quantity.to_string()),
+		Some(after.quantity.to_string()),
+	);
+
+	save(context, &before, after, vec![event], idempotency_key).await
+}
+
+/// Add a member's scarves to a group order
+pub async fn add_allocation(
+	context : &Context,
+	id : &str,
+	allocation : Allocation,
+	idempotency_key : Option<&str>,
+) -> Result<Order, EditError> {
+	let before = editable(context, id).await?;
+	if before.group.is_none() {
+		return Err(EditError::NotGroupOrder);
+	}
+
+	let event = OrderEvent::change(
+		EventKind::Edited,
+		context.actor(),
+		"allocations",
+		None,
+		Some(describe_allocation(&allocation)),
+	);
+
+	let mut after = before.clone();
+	after.allocations.push(allocation);
+	set_items(&mut after);
+
+	save(context, &before, after, vec![event], idempotency_key).await
+}
+
+/// Take a member's scarves out of a group order. The last allocation can not
+/// be removed; cancel the order instead.
+pub async fn remove_allocation(
+	context : &Context,
+	id : &str,
+	allocation_id : &str,
+	idempotency_key : Option<&str>,
+) -> Result<Order, EditError> {
+	let before = editable(context, id).await?;
+	if before.group.is_none() {
+		return Err(EditError::NotGroupOrder);
+	}
+
+	let mut after = before.clone();
+	let index = after
+		.allocations
+		.iter()
+		.position(|allocation| allocation.id.to_string() == allocation_id)
+		.ok_or(EditError::AllocationNotFound)?;
+	if after.allocations.len() == 1 {
+		return Err(EditError::InvalidQuantity);
+	}
+	let removed = after.allocations.remove(index);
+	set_items(&mut after);
+
+	let event = OrderEvent::change(
+		EventKind::Edited,
+		context.actor(),
+		"allocations",
+		Some(describe_allocation(&removed)),
+		None,
+	);
+
+	save(context, &before, after, vec![event], idempotency_key).await
+}
+
+fn set_items(order : &mut Order) {
+	order.items = Allocation::items(&order.allocations);
+	order.quantity = order.items.iter().map(|item| item.quantity).sum();
+}
+
+pub async fn update_address(
+	context : &Context,
+	id : &str,
+	address : Address,
+	idempotency_key : Option<&str>,
+) -> Result<Order, EditError> {
+	let before = editable(context, id).await?;
+	if before.method != CollectionMethod::Post {
+		return Err(EditError::NotPosted);
+	}
+
+	let event = OrderEvent::change(
+		EventKind::Edited,
+		context.actor(),
+		"address",
+		before.address.as_ref().map(describe_address),
+		Some(describe_address(&address)),
+	);
+
+	let mut after = before.clone();
+	after.address = Some(address);
+
+	save(context, &before, after, vec![event], idempotency_key).await
+}
+
+/// Switch between picking the order up and having it posted. Posting needs
+/// an address, picking up clears it.
+pub async fn change_method(
+	context : &Context,
+	id : &str,
+	method : CollectionMethod,
+	pickup_location : Option<String>,
+	address : Option<Address>,
+	idempotency_key : Option<&str>,
+) -> Result<Order, EditError> {
+	let before = editable(context, id).await?;
+	if method == CollectionMethod::Post && before.payment_method() == PaymentMethod::Cash {
+		return Err(EditError::CashNeedsPickup);
+	}
+	let mut after = before.clone();
+	after.method = method;
+
+	match method {
+		CollectionMethod::Post => {
+			after.pickup_location = None;
+			after.address = match address.or_else(|| before.address.clone()) {
+				Some(address) => Some(address),
+				// Reports every address field as required
+				None => Some(
+					Address::validate(None, None, None, None, None, None)
+						.map_err(EditError::InvalidAddress)?,
+				),
+			};
+		},
+		CollectionMethod::Pickup => {
+			after.pickup_location = pickup_location;
+			after.address = None;
+		},
+	}
+
+	let mut events = vec![OrderEvent::change(
+		EventKind::Edited,
+		context.actor(),
+		"method",
+		Some(describe_method(&before)),
+		Some(describe_method(&after)),
+	)];
+	if before.address.is_some() != after.address.is_some() {
+		events.push(OrderEvent::change(
+			EventKind::Edited,
+			context.actor(),
+			"address",
+			before.address.as_ref().map(describe_address),
+			after.address.as_ref().map(describe_address),
+		));
+	}
+
+	save(context, &before, after, events, idempotency_key).await
+}
+
+/// Reprice the edited order, move the payment intent to the new total and
+/// store it. The postage service the customer chose is kept if it is still
+/// offered, otherwise the default service is locked in again.
+async fn save(
+	context : &Context,
+	before : &Order,
+	mut after : Order,
+	mut events : Vec<OrderEvent>,
+	idempotency_key : Option<&str>,
+) -> Result<Order, EditError> {
+	after.quotes = vec![];
+	after.postage = match (after.method, &after.address) {
+		(CollectionMethod::Post, Some(address)) => {
+			let options = PostDeliveryOption::get(after.quantity as u32, address)
+				.await
+				.map_err(|_| EditError::PostageUnavailable)?;
+			after.quotes = PostageQuote::issue(options);
+
+			let kept = before
+				.postage
+				.as_ref()
+				.map(|postage| postage.code.to_owned());
+			let default_code = PostDeliveryOption::default_code(address);
+			let quote = after
+				.quotes
+				.iter()
+				.find(|quote| Some(&quote.code) == kept.as_ref())
+				.or_else(|| after.quotes.iter().find(|quote| quote.code == default_code))
+				.ok_or(EditError::PostageUnavailable)?;
+
+			Some(Postage::from_quote(quote))
+		},
+		_ => None,
+	};
+
+	let previous_postage = before.postage.as_ref().map(Postage::describe);
+	let new_postage = after.postage.as_ref().map(Postage::describe);
+	if previous_postage != new_postage {
+		events.push(OrderEvent::change(
+			EventKind::PostageChanged,
+			context.actor(),
+			"postage",
+			previous_postage,
+			new_postage,
+		));
+	}
+
+	// Orders paid for outside the site have no payment intent to update
+	if after.payment_method() == PaymentMethod::Card {
+		let pi = after
+			.payment
+			.as_ref()
+			.and_then(|payment| payment.stripe.as_ref())
+			.map(|stripe| stripe.pi.to_owned())
+			.ok_or(EditError::PaymentError)?;
+		let postage_price = after.postage.as_ref().map(|p| p.price as u64).unwrap_or(0);
+		let description = payment_description(
+			&after.reference(),
+			&after.user.name,
+			after.quantity,
+			after.method,
+		);
+
+		if !set_amount(
+			&get_idempotent_stripe(idempotency::stripe_key(idempotency_key, "amount")),
+			&pi,
+			SCARF.price * after.quantity as u64 + postage_price,
+			Some(&description),
+		)
+		.await
+		{
+			return Err(EditError::PaymentError);
+		}
+		// A session made before the edit would charge the old price
+		if !checkout::close_session(&after).await {
+			return Err(EditError::PaymentError);
+		}
+	}
+
+	let items : Vec<Bson> = after
+		.items
+		.iter()
+		.map(|item| Bson::Document(item.to_doc()))
+		.collect();
+	let quotes : Vec<Bson> = after
+		.quotes
+		.iter()
+		.map(|quote| Bson::Document(quote.to_doc()))
+		.collect();
+	let mut set = doc! {
+		"quantity": after.quantity,
+		"items": items,
+		"method": after.method.code(),
+		"pickup_location": match &after.pickup_location {
+			Some(l) => Bson::String(l.to_owned()),
+			None => Bson::Null,
+		},
+		"address": match &after.address {
+			Some(address) => Bson::Document(address.to_doc()),
+			None => Bson::Null,
+		},
+		"postage": match &after.postage {
+			Some(postage) => Bson::Document(postage.to_doc()),
+			None => Bson::Null,
+		},
+		"quotes": quotes,
+	};
+	if after.group.is_some() {
+		let allocations : Vec<Bson> = after
+			.allocations
+			.iter()
+			.map(|a| Bson::Document(a.to_doc()))
+			.collect();
+		set.insert("allocations", allocations);
+	}
+
+	let id = ObjectId::parse_str(&*after.id).map_err(|_| EditError::InvalidId)?;
+	let updated = context
+		.orders_handel()
+		.update_one(
+			doc! {
+				"_id": id,
+				// Orders from before statuses were stored have none
+				"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+			},
+			history::update(set, &events),
+			None,
+		)
+		.await
+		.map_err(|_| EditError::DatabaseError)?;
+
+	// The order was paid for while it was being repriced
+	if updated.matched_count == 0 {
+		return Err(EditError::AlreadyPaid);
+	}
+
+	after.history.extend(events);
+	Ok(after)
+}
+
+fn describe_address(address : &Address) -> String { address.lines().join(", ") }
+
+fn describe_allocation(allocation : &Allocation) -> String {
+	format!(
+		"{}: {} x{}",
+		allocation.member,
+		SCARF.item_name(&allocation.variant),
+		allocation.quantity
+	)
+}
+
+fn describe_method(order : &Order) -> String {
+	match (order.method, &order.pickup_location) {
+		(CollectionMethod::Pickup, Some(location)) => format!("Pickup from {}", location),
+		(CollectionMethod::Pickup, None) => String::from("Pickup"),
+		(CollectionMethod::Post, _) => String::from("Post"),
+	}
+}
@@ -0,0 +1,254 @@
+//! Cleaning up orders that were never paid for. Every newOrder leaves an
+//! order and a payment intent behind, so a job in the server periodically
+//! reminds customers who have not paid, once, and later cancels the payment
+//! intent and marks the order as expired. Orders paid for outside the site
+//! are followed up by the committee instead.
+//!
+//! The current time is always passed in so a run can be checked against any
+//! point in time.
+
+use crate::{
+	checkout,
+	config::Expiry,
+	db::{self, id_at, PrimaryDb},
+	history::{self, Actor, EventKind, OrderEvent},
+	mail,
+	models::{Order, OrderStatus, PaymentMethod},
+	stripe::{cancel_payment, get_idempotent_stripe},
+};
+use chrono::{DateTime, Utc};
+use mongodb::{
+	bson::{oid::ObjectId, Bson, Document},
+	Collection,
+};
+use rocket::tokio::{self, time::sleep};
+use tracing::{info, info_span, Instrument};
+
+/// The orders a run acted on, or would have in a dry run
+#[derive(Clone, Debug, Default)]
+pub struct ExpiryReport {
+	pub reminded : Vec<String>,
+	pub expired :  Vec<String>,
+	/// Orders that turned out to have been paid when they were checked
+	pub paid :     Vec<String>,
+}
+
+impl ExpiryReport {
+	pub fn is_empty(&self) -> bool {
+		self.reminded.is_empty() && self.expired.is_empty() && self.paid.is_empty()
+	}
+}
+
+/// Orders are found by ID rather than created_at, which also covers orders
+/// from before created_at was stored
+async fn find(orders : &Collection<Document>, filter : Document) -> Vec<Order> {
+	match orders.find(filter, None).await {
+		Ok(cursor) => db::collect(cursor).await,
+		Err(_) => vec![],
+	}
+}
+
+/// Only change the order if nothing else has changed its status first
+fn still_unpaid(order : &Order) -> Option<Document> {
+	Some(doc! {
+		"_id": ObjectId::parse_str(&*order.id).ok()?,
+		"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+	})
+}
+
+/// Whether the order turned out to have been paid. A dry run only asks
+/// Stripe, and leaves the order as it is.
+async fn paid(orders : &Collection<Document>, order : &mut Order, dry_run : bool) -> bool {
+	match dry_run {
+		true => order.paid_upstream().await,
+		false => {
+			order.refresh_status(orders).await;
+			order.status != OrderStatus::Unpaid
+		},
+	}
+}
+
+/// Look for orders that need a reminder or have expired
+pub async fn run(
+	orders : &Collection<Document>,
+	expiry : &Expiry,
+	now : DateTime<Utc>,
+) -> ExpiryReport {
+	let mut report = ExpiryReport::default();
+	let expire_cutoff = now - expiry.expire_after;
+
+	// Reminders only go to orders that are not about to be expired anyway
+	if let Some(remind_after) = expiry.remind_after {
+		let filter = doc! {
+			"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+			"payment.method": { "$in": [PaymentMethod::Card.code(), Bson::Null] },
+			"reminder_sent_at": Bson::Null,
+			"_id": {
+				"$gte": id_at(expire_cutoff),
+				"$lt": id_at(now - remind_after),
+			},
+		};
+
+		for mut order in find(orders, filter).await {
+			if paid(orders, &mut order, expiry.dry_run).await {
+				report.paid.push(order.reference());
+				continue;
+			}
+
+			let expires_at = order.created_at + expiry.expire_after;
+			if expiry.dry_run || remind(orders, &order, expires_at, now).await {
+				report.reminded.push(order.reference());
+			}
+		}
+	}
+
+	let filter = doc! {
+		"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+		"payment.method": { "$in": [PaymentMethod::Card.code(), Bson::Null] },
+		"_id": { "$lt": id_at(expire_cutoff) },
+	};
+	for mut order in find(orders, filter).await {
+		if paid(orders, &mut order, expiry.dry_run).await {
+			report.paid.push(order.reference());
+			continue;
+		}
+
+		if expiry.dry_run || expire(orders, &order, now).await {
+			report.expired.push(order.reference());
+		}
+	}
+
+	report
+}
+
+async fn remind(
+	orders : &Collection<Document>,
+	order : &Order,
+	expires_at : DateTime<Utc>,
+	now : DateTime<Utc>,
+) -> bool {
+	let filter = match still_unpaid(order) {
+		Some(filter) => filter,
+		None => return false,
+	};
+
+	let body = format!(
+		"Hi {},\n\n\
+		 Thanks for ordering {} scarves from us. We have not received your payment \
+		 yet, so order {} has been put aside until {}. If you would still like the \
+		 scarves, please head back to the shop to finish paying before then.\n\n\
+		 Normanhurst Rover Crew",
+		order.user.name,
+		order.quantity,
+		order.reference(),
+		expires_at.format("%e %B %Y"),
+	);
+
+	if !mail::send(
+		&order.user.email,
+		&format!(
+			"Your scarf order {} is waiting for payment",
+			order.reference()
+		),
+		&body,
+	)
+	.await
+	{
+		return false;
+	}
+
+	let event = OrderEvent {
+		at : now,
+		new : Some(order.user.email.to_owned()),
+		..OrderEvent::new(EventKind::ReminderSent, Actor::System)
+	};
+
+	orders
+		.update_one(
+			filter,
+			history::update(
+				doc! { "reminder_sent_at": Bson::DateTime(now.into()) },
+				&[event],
+			),
+			None,
+		)
+		.await
+		.is_ok()
+}
+
+async fn expire(orders : &Collection<Document>, order : &Order, now : DateTime<Utc>) -> bool {
+	let filter = match still_unpaid(order) {
+		Some(filter) => filter,
+		None => return false,
+	};
+
+	let pi = order
+		.payment
+		.as_ref()
+		.and_then(|payment| payment.stripe.as_ref())
+		.map(|stripe| stripe.pi.to_owned())
+		.filter(|pi| !pi.is_empty());
+
+	// Try again next run if Stripe can not be reached
+	if let Some(pi) = pi {
+		let client = get_idempotent_stripe(Some(format!("expire:{}", order.id)));
+		if !cancel_payment(&client, &pi).await {
+			return false;
+		}
+	}
+	if !checkout::close_session(order).await {
+		return false;
+	}
+
+	let event = OrderEvent {
+		at : now,
+		..OrderEvent::change(
+			EventKind::Expired,
+			Actor::System,
+			"status",
+			Some(OrderStatus::Unpaid.code().to_string()),
+			Some(OrderStatus::Expired.code().to_string()),
+		)
+	};
+
+	match orders
+		.update_one(
+			filter,
+			history::update(
+				doc! {
+					"status": OrderStatus::Expired.code(),
+					"batch_id": Bson::Null,
+				},
+				&[event],
+			),
+			None,
+		)
+		.await
+	{
+		Ok(result) => result.matched_count > 0,
+		Err(_) => false,
+	}
+}
+
+/// Run the job in the background for as long as the server is up
+pub fn spawn(db : PrimaryDb, expiry : Expiry) {
+	tokio::spawn(async move {
+		loop {
+			let report = run(&db.collection("orders"), &expiry, Utc::now())
+				.instrument(info_span!("order_expiry"))
+				.await;
+
+			if !report.is_empty() {
+				info!(
+					dry_run = expiry.dry_run,
+					reminded = ?report.reminded,
+					expired = ?report.expired,
+					paid = ?report.paid,
+					"order expiry",
+				);
+			}
+
+			sleep(expiry.interval).await;
+		}
+	});
+}
@@ -0,0 +1,321 @@
+//! Orders as a spreadsheet for the treasurer and the packing crew, from the
+//! `/orders/export` route or `rainbow export` on the command line. Both take
+//! the same filters as the orders query. Orders are read from the database
+//! one at a time: CSV rows are sent as they are made, and XLSX rows are
+//! written out to a temporary file by the spreadsheet writer until the file
+//! is finished.
+
+use crate::{
+	address::AustralianState,
+	catalogue::SCARF,
+	db::{orders::OrderFilter, FromDoc},
+	models::{Address, CollectionMethod, Order, OrderStatus},
+};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use mongodb::{bson::Document, options::FindOptions, Collection, Cursor};
+use rocket::{FromForm, FromFormField};
+use rust_xlsxwriter::{Format, Workbook};
+
+const COLUMNS : &[&str] = &[
+	"number",
+	"id",
+	"created_at",
+	"status",
+	"paid",
+	"payment_method",
+	"payment_reference",
+	"name",
+	"email",
+	"method",
+	"pickup_location",
+	"apartment",
+	"street",
+	"town",
+	"state",
+	"post_code",
+	"country",
+	"quantity",
+	"items",
+	"postage_service",
+	"postage",
+	"scarves_total",
+	"total",
+	"refunded",
+	"received",
+	"batch_id",
+];
+
+/// Columns written as numbers in a workbook. Everything else is text, so
+/// postcodes keep their leading zeros.
+const NUMBERS : &[&str] = &[
+	"quantity",
+	"postage",
+	"scarves_total",
+	"total",
+	"refunded",
+	"received",
+];
+
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+	Csv,
+	Xlsx,
+}
+
+impl ExportFormat {
+	pub fn parse(format : &str) -> Option<Self> {
+		match format.to_lowercase().as_str() {
+			"csv" => Some(ExportFormat::Csv),
+			"xlsx" => Some(ExportFormat::Xlsx),
+			_ => None,
+		}
+	}
+
+	pub fn extension(&self) -> &'static str {
+		match self {
+			ExportFormat::Csv => "csv",
+			ExportFormat::Xlsx => "xlsx",
+		}
+	}
+}
+
+/// The filters of the orders query, as they are written in a URL or on the
+/// command line. Statuses and methods are their GraphQL names, and times are
+/// RFC 3339.
+#[derive(FromForm, Clone, Debug, Default)]
+pub struct ExportFilter {
+	pub status :         Option<String>,
+	pub method :         Option<String>,
+	pub paid :           Option<bool>,
+	pub post_code :      Option<String>,
+	pub state :          Option<String>,
+	pub email :          Option<String>,
+	pub created_after :  Option<String>,
+	pub created_before : Option<String>,
+}
+
+impl ExportFilter {
+	/// Set a filter from its name, for the command line
+	pub fn set(&mut self, key : &str, value : String) -> Result<(), String> {
+		match key {
+			"status" => self.status = Some(value),
+			"method" => self.method = Some(value),
+			"paid" => {
+				self.paid = Some(
+					value
+						.parse()
+						.map_err(|_| format!("paid must be true or false, not {}", value))?,
+				)
+			},
+			"post_code" => self.post_code = Some(value),
+			"state" => self.state = Some(value),
+			"email" => self.email = Some(value),
+			"created_after" => self.created_after = Some(value),
+			"created_before" => self.created_before = Some(value),
+			_ => return Err(format!("{} is not a filter", key)),
+		}
+		Ok(())
+	}
+
+	pub fn to_order_filter(&self) -> Result<OrderFilter, String> {
+		Ok(OrderFilter {
+			status :         match &self.status {
+				Some(status) => Some(parse_status(status)?),
+				None => None,
+			},
+			method :         match self.method.as_deref().map(str::to_uppercase).as_deref() {
+				Some("PICKUP") => Some(CollectionMethod::Pickup),
+				Some("POST") => Some(CollectionMethod::Post),
+				Some(_) => return Err("method must be PICKUP or POST".to_string()),
+				None => None,
+			},
+			paid :           self.paid,
+			post_code :      self.post_code.clone(),
+			state :          match &self.state {
+				Some(state) => Some(
+					AustralianState::parse(state)
+						.ok_or_else(|| format!("{} is not an Australian state", state))?,
+				),
+				None => None,
+			},
+			email :          self.email.clone(),
+			created_after :  parse_time(&self.created_after)?,
+			created_before : parse_time(&self.created_before)?,
+		})
+	}
+}
+
+fn parse_status(status : &str) -> Result<OrderStatus, String> {
+	let parsed = OrderStatus::parse(&status.to_uppercase());
+	match parsed.code() == status.to_uppercase() {
+		true => Ok(parsed),
+		false => Err(format!("{} is not an order status", status)),
+	}
+}
+
+fn parse_time(time : &Option<String>) -> Result<Option<DateTime<Utc>>, String> {
+	match time {
+		Some(time) => DateTime::parse_from_rfc3339(time)
+			.map(|time| Some(time.with_timezone(&Utc)))
+			.map_err(|_| format!("{} is not an RFC 3339 time", time)),
+		None => Ok(None),
+	}
+}
+
+/// The matching orders, oldest first
+pub async fn orders(
+	coll : &Collection<Document>,
+	filter : &OrderFilter,
+) -> Result<Cursor<Document>, String> {
+	let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+
+	coll.find(filter.to_doc(), options)
+		.await
+		.map_err(|_| "Failed to load orders".to_string())
+}
+
+/// CSV of the orders, a row at a time. The header comes first.
+pub fn csv(cursor : Cursor<Document>) -> impl Stream<Item = Vec<u8>> {
+	let header = futures::stream::once(async { csv_line(COLUMNS) });
+	let rows = cursor.filter_map(|item| async move {
+		let order = Order::from_doc(item.ok()?);
+		let cells : Vec<String> = row(&order)
+			.into_iter()
+			.zip(COLUMNS)
+			.map(|(value, column)| match NUMBERS.contains(column) {
+				true => value,
+				false => escape_formula(value),
+			})
+			.collect();
+		Some(csv_line(&cells))
+	});
+
+	header.chain(rows)
+}
+
+/// Spreadsheets run text starting with these as a formula when they open a
+/// CSV, so customers' details are made to start with a quote instead
+fn escape_formula(value : String) -> String {
+	match value.starts_with(&['=', '+', '-', '@'][..]) {
+		true => format!("'{}", value),
+		false => value,
+	}
+}
+
+fn csv_line<T : AsRef<[u8]>>(record : &[T]) -> Vec<u8> {
+	let mut writer = csv::Writer::from_writer(vec![]);
+	writer
+		.write_record(record)
+		.expect("Writing export row failed");
+	writer.into_inner().expect("Writing export row failed")
+}
+
+/// An XLSX workbook of the orders
+pub async fn xlsx(mut cursor : Cursor<Document>) -> Result<Vec<u8>, String> {
+	let mut workbook = Workbook::new();
+	let sheet = workbook.add_worksheet_with_constant_memory();
+	sheet.set_name("Orders").map_err(|e| e.to_string())?;
+
+	let bold = Format::new().set_bold();
+	for (column, name) in COLUMNS.iter().enumerate() {
+		sheet
+			.write_string_with_format(0, column as u16, *name, &bold)
+			.map_err(|e| e.to_string())?;
+	}
+
+	let mut line = 1;
+	while let Some(item) = cursor.next().await {
+		let order = match item {
+			Ok(item) => Order::from_doc(item),
+			Err(_) => continue,
+		};
+
+		for (column, value) in row(&order).iter().enumerate() {
+			let written = match (NUMBERS.contains(&COLUMNS[column]), value.parse::<f64>()) {
+				(true, Ok(number)) => sheet.write_number(line, column as u16, number),
+				_ => sheet.write_string(line, column as u16, value),
+			};
+			written.map_err(|e| e.to_string())?;
+		}
+		line += 1;
+	}
+
+	workbook.save_to_buffer().map_err(|e| e.to_string())
+}
+
+/// An order as a spreadsheet row, in the order of `COLUMNS`. Money is in
+/// dollars without a currency sign so spreadsheets treat it as a number.
+fn row(order : &Order) -> Vec<String> {
+	let postage = order
+		.postage
+		.as_ref()
+		.map(|postage| postage.price)
+		.unwrap_or(0);
+	let scarves = i64::from(order.quantity) * SCARF.price as i64;
+	let dollars = |cents : i64| format!("{:.2}", cents as f64 / 100.0);
+
+	let mut row = vec![
+		order.number.clone().unwrap_or_default(),
+		order.id.to_string(),
+		order.created_at.to_rfc3339(),
+		order.status.code().to_string(),
+		order.status.is_paid().to_string(),
+		order.payment_method().code().to_string(),
+		order
+			.payment
+			.as_ref()
+			.and_then(|payment| payment.reference.clone())
+			.unwrap_or_default(),
+		order.user.name.to_owned(),
+		order.user.email.to_owned(),
+		match order.method {
+			CollectionMethod::Pickup => "PICKUP".to_string(),
+			CollectionMethod::Post => "POST".to_string(),
+		},
+		order.pickup_location.clone().unwrap_or_default(),
+	];
+
+	row.extend(match &order.address {
+		Some(address) => address_columns(address),
+		None => vec![String::new(); 6],
+	});
+
+	row.extend(vec![
+		order.quantity.to_string(),
+		order
+			.items
+			.iter()
+			.map(|item| format!("{} x{}", SCARF.item_name(&item.variant), item.quantity))
+			.collect::<Vec<_>>()
+			.join("; "),
+		order
+			.postage
+			.as_ref()
+			.map(|postage| postage.name.to_owned())
+			.unwrap_or_default(),
+		dollars(postage),
+		dollars(scarves),
+		dollars(scarves + postage),
+		dollars(order.refunded()),
+		dollars(order.received()),
+		order.batch_id.clone().unwrap_or_default(),
+	]);
+
+	row
+}
+
+fn address_columns(address : &Address) -> Vec<String> {
+	vec![
+		address.apartment.clone().unwrap_or_default(),
+		address.street.to_owned(),
+		address.town.to_owned(),
+		match (address.state, &address.region) {
+			(Some(state), _) => state.code().to_string(),
+			(None, Some(region)) => region.to_owned(),
+			(None, None) => String::new(),
+		},
+		address.post_code.as_str().to_string(),
+		address.country.to_owned(),
+	]
+}
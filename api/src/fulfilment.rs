@@ -0,0 +1,257 @@
+use crate::{
+	catalogue::SCARF,
+	db::helpers as DBHelper,
+	graphql::context::Context,
+	history::{self, EventKind, OrderEvent},
+	models::{Address, BatchStatus, CollectionMethod, FulfilmentBatch, Order, OrderStatus},
+};
+use chrono::Utc;
+use futures::future::join_all;
+use mongodb::bson::{oid::ObjectId, Bson};
+use std::collections::BTreeMap;
+
+pub enum BatchError {
+	NoOrders,
+	DatabaseError,
+}
+
+/// Total number of scarves of one variant to pick for a batch
+#[derive(Clone, Debug)]
+pub struct PickListLine {
+	pub variant :  String,
+	pub name :     String,
+	pub quantity : i32,
+}
+
+/// Group every paid order that is not already in a batch and matches the
+/// method, pickup location and postage service into a new batch
+pub async fn create_batch(
+	context : &Context,
+	name : String,
+	method : CollectionMethod,
+	pickup_location : Option<String>,
+	postage_code : Option<String>,
+) -> Result<FulfilmentBatch, BatchError> {
+	let mut filter = doc! {
+		"method": method.code(),
+		"batch_id": Bson::Null,
+		"status": { "$nin": ["PACKED", "SHIPPED", "CANCELLED", "EXPIRED", "REFUNDED"] },
+	};
+	if let Some(location) = &pickup_location {
+		filter.insert("pickup_location", location.to_owned());
+	}
+	if let Some(code) = &postage_code {
+		filter.insert("postage.code", code.to_owned());
+	}
+
+	// Orders are only marked as paid when they are checked against Stripe, so
+	// check any that are still waiting, all at once
+	let orders = context.orders_handel();
+	let mut candidates : Vec<Order> = DBHelper::find(context.orders_handel(), filter).await;
+	join_all(
+		candidates
+			.iter_mut()
+			.map(|order| order.refresh_status(&orders)),
+	)
+	.await;
+
+	let paid : Vec<Bson> = candidates
+		.into_iter()
+		.filter_map(|order| match order.status {
+			OrderStatus::Paid => ObjectId::parse_str(&*order.id).ok().map(Bson::ObjectId),
+			_ => None,
+		})
+		.collect();
+
+	if paid.is_empty() {
+		return Err(BatchError::NoOrders);
+	}
+
+	let batch_id = ObjectId::new();
+	let created_at = Utc::now();
+
+	let claimed = orders
+		.update_many(
+			doc! {
+				"_id": { "$in": paid },
+				"batch_id": Bson::Null,
+			},
+			history::update(
+				doc! { "batch_id": batch_id },
+				&[OrderEvent::change(
+					EventKind::BatchAssigned,
+					context.actor(),
+					"batch_id",
+					None,
+					Some(name.to_owned()),
+				)],
+			),
+			None,
+		)
+		.await
+		.map_err(|_| BatchError::DatabaseError)?;
+
+	if claimed.modified_count == 0 {
+		return Err(BatchError::NoOrders);
+	}
+
+	context
+		.batches_handel()
+		.insert_one(
+			doc! {
+				"_id": batch_id,
+				"name": &name,
+				"method": method.code(),
+				"pickup_location": match &pickup_location {
+					Some(l) => Bson::String(l.to_owned()),
+					None => Bson::Null,
+				},
+				"postage_code": match &postage_code {
+					Some(c) => Bson::String(c.to_owned()),
+					None => Bson::Null,
+				},
+				"status": BatchStatus::Open.code(),
+				"created_at": Bson::DateTime(created_at.into()),
+			},
+			None,
+		)
+		.await
+		.map_err(|_| BatchError::DatabaseError)?;
+
+	Ok(FulfilmentBatch {
+		id : batch_id.to_string().into(),
+		name,
+		method,
+		pickup_location,
+		postage_code,
+		status : BatchStatus::Open,
+		created_at,
+	})
+}
+
+/// Move a batch, and every order in it, to a new status
+pub async fn set_status(
+	context : &Context,
+	batch : &mut FulfilmentBatch,
+	status : BatchStatus,
+) -> Result<(), BatchError> {
+	let batch_id = ObjectId::parse_str(&*batch.id).map_err(|_| BatchError::DatabaseError)?;
+
+	context
+		.batches_handel()
+		.update_one(
+			doc! {"_id": batch_id},
+			doc! {
+				"$set": {
+					"status": status.code(),
+				}
+			},
+			None,
+		)
+		.await
+		.map_err(|_| BatchError::DatabaseError)?;
+
+	context
+		.orders_handel()
+		.update_many(
+			doc! {"batch_id": batch_id},
+			history::update(
+				doc! { "status": status.order_status().code() },
+				&[OrderEvent::change(
+					EventKind::StatusChanged,
+					context.actor(),
+					"status",
+					Some(batch.status.order_status().code().to_string()),
+					Some(status.order_status().code().to_string()),
+				)],
+			),
+			None,
+		)
+		.await
+		.map_err(|_| BatchError::DatabaseError)?;
+
+	batch.status = status;
+
+	Ok(())
+}
+
+pub async fn orders(context : &Context, batch : &FulfilmentBatch) -> Vec<Order> {
+	match ObjectId::parse_str(&*batch.id) {
+		Ok(batch_id) => DBHelper::find(context.orders_handel(), doc! {"batch_id": batch_id}).await,
+		Err(_) => vec![],
+	}
+}
+
+/// Total scarves of each variant across the orders, in variant order
+pub fn pick_list(orders : &[Order]) -> Vec<PickListLine> {
+	let mut totals : BTreeMap<String, i32> = BTreeMap::new();
+
+	for order in orders {
+		for item in &order.items {
+			*totals.entry(item.variant.to_owned()).or_insert(0) += item.quantity;
+		}
+	}
+
+	totals
+		.into_iter()
+		.map(|(variant, quantity)| PickListLine {
+			name : SCARF.item_name(&variant),
+			variant,
+			quantity,
+		})
+		.collect()
+}
+
+/// A CSV manifest of the consignments in a batch, for lodging at the post
+/// office
+pub fn manifest(orders : &[Order]) -> Vec<u8> {
+	let mut writer = csv::Writer::from_writer(vec![]);
+
+	writer
+		.write_record(&[
+			"consignment",
+			"service",
+			"name",
+			"email",
+			"apartment",
+			"street",
+			"town",
+			"state",
+			"post_code",
+			"country",
+			"quantity",
+			"weight_kg",
+		])
+		.expect("Writing manifest header failed");
+
+	for order in orders {
+		let address = order.address.clone().unwrap_or_else(Address::default);
+
+		writer
+			.write_record(&[
+				order.reference(),
+				order
+					.postage
+					.as_ref()
+					.map(|postage| postage.code.to_owned())
+					.unwrap_or_default(),
+				order.user.name.to_owned(),
+				order.user.email.to_owned(),
+				address.apartment.unwrap_or_default(),
+				address.street,
+				address.town,
+				match (address.state, address.region) {
+					(Some(state), _) => state.code().to_string(),
+					(None, Some(region)) => region,
+					(None, None) => String::from(""),
+				},
+				address.post_code.to_string(),
+				address.country,
+				order.quantity.to_string(),
+				format!("{:.2}", f64::from(order.quantity) * SCARF.weight_kg),
+			])
+			.expect("Writing manifest row failed");
+	}
+
+	writer.into_inner().expect("Flushing manifest failed")
+}
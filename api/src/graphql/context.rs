@@ -1,13 +1,44 @@
-use crate::db::PrimaryDb;
-use juniper::Context as JuniperContext;
-use mongodb::{coll::Collection, db::ThreadedDatabase};
+use crate::{db::PrimaryDb, history::Actor};
+use juniper::{graphql_value, Context as JuniperContext, FieldError, FieldResult};
+use mongodb::{bson::Document, Collection};
 
 pub struct Context {
 	pub connection : PrimaryDb,
+	/// Whether the request was authenticated as a committee member
+	pub admin :      bool,
 }
 
 impl Context {
-	pub fn orders_handel(&self) -> Collection { self.connection.collection("orders") }
+	pub fn orders_handel(&self) -> Collection<Document> { self.connection.collection("orders") }
+
+	pub fn batches_handel(&self) -> Collection<Document> { self.connection.collection("batches") }
+
+	pub fn counters_handel(&self) -> Collection<Document> { self.connection.collection("counters") }
+
+	pub fn idempotency_keys_handel(&self) -> Collection<Document> {
+		self.connection.collection("idempotency_keys")
+	}
+
+	/// Who is making changes in this request, for the order history
+	pub fn actor(&self) -> Actor {
+		match self.admin {
+			true => Actor::Admin,
+			false => Actor::Customer,
+		}
+	}
+
+	/// Fail the field unless the request was made by an admin
+	pub fn require_admin(&self) -> FieldResult<()> {
+		match self.admin {
+			true => Ok(()),
+			false => Err(FieldError::new(
+				"You must be logged in as an admin",
+				graphql_value!({
+					"type": "UNAUTHORISED"
+				}),
+			)),
+		}
+	}
 }
 
 impl JuniperContext for Context {}
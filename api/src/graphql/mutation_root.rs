@@ -1,334 +1,637 @@
 use crate::{
+	address::AddressError,
+	cancellation,
+	catalogue::SCARF,
+	checkout::{self, CheckoutLink},
+	creation::{self, NewOrder},
 	db::helpers as DBHelper,
+	editing,
+	fulfilment::{self, BatchError},
 	graphql::context::Context,
-	models::{CollectionMethod, Order, PostDeliveryOption},
-	stripe::get_stripe,
+	history::{self, EventKind, OrderEvent},
+	idempotency, metrics,
+	models::{
+		Address, Allocation, AllocationInput, BatchStatus, CollectionMethod, FulfilmentBatch,
+		Group, GroupKind, LineItem, Order, OrderStatus, PaymentMethod, Postage, PostageQuote,
+	},
+	offline,
+	stripe::{get_idempotent_stripe, get_stripe, set_amount},
 };
-use juniper::{graphql_value, FieldResult};
-use mongodb::{oid::ObjectId, Bson};
-
-const POST_OPTION : &str = "AUS_PARCEL_REGULAR_PACKAGE_SMALL";
-const SCARVE_PRICE : u64 = 1500;
+use juniper::{graphql_object, graphql_value, FieldResult};
+use mongodb::bson::Bson;
 
 pub struct MutationRoot;
-#[juniper::object(
-    Context = Context
-)]
+#[graphql_object(context = Context)]
 impl MutationRoot {
 	/// Take in the details of a user, how they would like to receive their
-	/// order and possibly their address.
-	fn newOrder(
+	/// order and possibly their address. Addresses without a country are
+	/// Australian; for overseas addresses the state is a free text region.
+	/// Retrying with the same idempotency key returns the order that was
+	/// created the first time. Orders are paid by card unless another payment
+	/// method is given; cash is only taken at pickup.
+	async fn newOrder(
 		context : &Context,
 		name : String,
 		quantity : i32,
+		variant : Option<String>,
 		email : String,
 		address_apt : Option<String>,
 		address_street : Option<String>,
 		address_town : Option<String>,
 		address_state : Option<String>,
-		address_post_code : Option<i32>,
+		address_post_code : Option<String>,
+		address_country : Option<String>,
 		delivery_method : CollectionMethod,
+		pickup_location : Option<String>,
+		payment_method : Option<PaymentMethod>,
+		idempotency_key : Option<String>,
 	) -> FieldResult<Option<Order>> {
-		let stripe_client = get_stripe();
+		metrics::operation("newOrder", async move {
+			if quantity < 1 {
+				return Err(juniper::FieldError::new(
+					"Quantity must be greater than 0",
+					graphql_value!({
+						"type": "NO_WHATEVER"
+					}),
+				));
+			};
 
-		if quantity < 1 {
-			return Err(juniper::FieldError::new(
-				"Quantity must be greater than 0",
-				graphql_value!({
-					"type": "NO_WHATEVER"
-				}),
-			));
-		};
-		let orders = context.orders_handel();
-
-		let result = orders
-			.insert_one(
-				doc! {
-				"quantity" => quantity,
-				"user" => {
-					"name" => &name,
-					"email" => &email,
+			let variant = match variant {
+				Some(code) => match SCARF.variant(&code) {
+					Some(v) => v,
+					None => {
+						return Err(juniper::FieldError::new(
+							"The requested variant does not exist",
+							graphql_value!({
+								"type": "INVALID_VARIANT"
+							}),
+						))
+					},
 				},
-				"method" => match delivery_method {
-					CollectionMethod::Pickup => 1,
-					CollectionMethod::Post => 0,
-				} as i32,
+				None => SCARF.default_variant(),
+			};
+
+			let items = vec![LineItem {
+				variant : variant.code.to_string(),
+				quantity,
+			}];
+
+			let address = match delivery_method {
+				CollectionMethod::Post => {
+					match Address::validate(
+						address_apt,
+						address_street,
+						address_town,
+						address_state,
+						address_post_code,
+						address_country,
+					) {
+						Ok(address) => Some(address),
+						Err(errors) => return Err(AddressError::to_field_error(&errors)),
+					}
 				},
-				None,
-			)
-			.unwrap();
+				CollectionMethod::Pickup => None,
+			};
+
+			let new = NewOrder {
+				name,
+				email,
+				items,
+				group : None,
+				allocations : vec![],
+				method : delivery_method,
+				pickup_location,
+				address,
+				payment : payment_method.unwrap_or(PaymentMethod::Card),
+				paid : false,
+				idempotency_key,
+			};
+
+			match creation::create(context, new).await {
+				Ok(order) => Ok(Some(order)),
+				Err(e) => Err(e.to_field_error()),
+			}
+		})
+		.await
+	}
 
-		match delivery_method {
-			CollectionMethod::Post => {
-				orders
-					.update_one(
-						doc! {"_id" => result.inserted_id.clone().expect("Inserted ID not found") },
-						doc! {
-							"$set" => {
-								"address" => {
-									"apartment" => match address_apt {Some(a) => a, None => "".to_string()},
-									"street" => match address_street{Some(a) => a, None => "".to_string()},
-									"town" => match address_town{Some(a) => a, None => "".to_string()},
-									"state" => match address_state{Some(a) => a, None => "".to_string()},
-									"post_code" => match address_post_code{Some(a) => a, None => 0},
-								}
-							}
-						},
-						None,
-					)
-					.expect("Update of delivery method failed");
-			},
-			_ => {},
-		};
-
-		let id = result.inserted_id.unwrap_or(Bson::ObjectId(
-			ObjectId::new().expect("Converting mongoID to juniperID failed"),
-		));
-
-		let id = id.as_object_id().expect("Unwrap string").to_string();
-
-		let post_price : u64 = match delivery_method {
-			CollectionMethod::Post => {
-				let dopts = match PostDeliveryOption::get(
-					quantity as u32,
-					address_post_code.unwrap() as u32,
-				) {
-					Ok(opts) => opts,
-					Err(_) => {
+	/// Order for a whole scout group, unit or section. Each allocation is the
+	/// scarves for one member, and the order is for all of them added up.
+	async fn newGroupOrder(
+		context : &Context,
+		name : String,
+		email : String,
+		group_name : String,
+		group_kind : GroupKind,
+		allocations : Vec<AllocationInput>,
+		address_apt : Option<String>,
+		address_street : Option<String>,
+		address_town : Option<String>,
+		address_state : Option<String>,
+		address_post_code : Option<String>,
+		address_country : Option<String>,
+		delivery_method : CollectionMethod,
+		pickup_location : Option<String>,
+		payment_method : Option<PaymentMethod>,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("newGroupOrder", async move {
+			if group_name.trim().is_empty() || allocations.is_empty() {
+				return Err(juniper::FieldError::new(
+					"A group order needs a group name and at least one allocation",
+					graphql_value!({
+						"type": "INVALID_ALLOCATION"
+					}),
+				));
+			}
+
+			let mut validated = vec![];
+			for allocation in allocations {
+				match Allocation::validate(allocation) {
+					Ok(allocation) => validated.push(allocation),
+					Err(message) => {
 						return Err(juniper::FieldError::new(
-							"Quantity must be greater than 0",
+							message,
 							graphql_value!({
-								"type": "INVALID_QUANTITY"
+								"type": "INVALID_ALLOCATION"
 							}),
 						))
 					},
-				};
+				}
+			}
 
-				let opt = dopts
-					.into_iter()
-					.filter(|opt : &PostDeliveryOption| opt.code == POST_OPTION)
-					.collect::<Vec<PostDeliveryOption>>();
+			let address = match delivery_method {
+				CollectionMethod::Post => {
+					match Address::validate(
+						address_apt,
+						address_street,
+						address_town,
+						address_state,
+						address_post_code,
+						address_country,
+					) {
+						Ok(address) => Some(address),
+						Err(errors) => return Err(AddressError::to_field_error(&errors)),
+					}
+				},
+				CollectionMethod::Pickup => None,
+			};
+
+			let new = NewOrder {
+				name,
+				email,
+				items : Allocation::items(&validated),
+				group : Some(Group {
+					name : group_name.trim().to_string(),
+					kind : group_kind,
+				}),
+				allocations : validated,
+				method : delivery_method,
+				pickup_location,
+				address,
+				payment : payment_method.unwrap_or(PaymentMethod::Card),
+				paid : false,
+				idempotency_key,
+			};
+
+			creation::create(context, new)
+				.await
+				.map_err(|e| e.to_field_error())
+		})
+		.await
+	}
 
-				let opt : &PostDeliveryOption = match opt.first() {
-					Some(o) => o,
-					_ => {
+	/// Add a member's scarves to an unpaid group order
+	async fn addAllocation(
+		context : &Context,
+		id : String,
+		allocation : AllocationInput,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("addAllocation", async move {
+			let allocation = match Allocation::validate(allocation) {
+				Ok(allocation) => allocation,
+				Err(message) => {
+					return Err(juniper::FieldError::new(
+						message,
+						graphql_value!({
+							"type": "INVALID_ALLOCATION"
+						}),
+					))
+				},
+			};
+
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "addAllocation", &id, key, async {
+				editing::add_allocation(context, &id, allocation, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
+
+	/// Take a member's scarves out of an unpaid group order
+	async fn removeAllocation(
+		context : &Context,
+		id : String,
+		allocation_id : String,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("removeAllocation", async move {
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "removeAllocation", &id, key, async {
+				editing::remove_allocation(context, &id, &allocation_id, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
+
+	/// Accept a postage quote issued by calculatePostage. The order is charged
+	/// exactly the quoted price. Once this is done the order is practically
+	/// finalized and just needs to be paid for.
+	async fn setPostage(
+		context : &Context,
+		id : String,
+		quote_id : String,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("setPostage", async move {
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "setPostage", &id, key, async {
+				let stripe_client = get_idempotent_stripe(idempotency::stripe_key(key, "amount"));
+
+				// Paid, cancelled and expired orders can not be repriced
+				let mut order = editing::editable(context, &id)
+					.await
+					.map_err(|e| e.to_field_error())?;
+				let id = mongodb::bson::oid::ObjectId::parse_str(&*order.id)?;
+
+				let quote : PostageQuote = match order.quote(&quote_id) {
+					Some(q) => q.clone(),
+					None => {
 						return Err(juniper::FieldError::new(
-							"User has not selected a valid postage option",
+							"This quote was not issued for this order",
 							graphql_value!({
-								"type": "INVALID_QUANTITY"
+								"type": "INVALID_QUOTE"
 							}),
 						))
 					},
 				};
 
-				(opt.price * f64::from(100)) as u64
-			},
-			_ => 0,
-		};
-
-		let mut params = stripe::PaymentIntentCreateParams::new(
-			SCARVE_PRICE * quantity as u64 + post_price,
-			stripe::Currency::AUD,
-		);
-
-		let desc = format!(
-			"{}: Scarves x{} for {}",
-			name,
-			&quantity,
-			match delivery_method {
-				CollectionMethod::Pickup => "Pickup",
-				CollectionMethod::Post => "Postage",
-			}
-		);
-
-		params.description = Some(&desc);
-
-		let cus = String::from(&email);
-		let mut meta = stripe::Metadata::new();
-		meta.insert("email".to_string(), cus);
-		meta.insert("quantity".to_string(), quantity.to_string());
-		params.metadata = Some(meta);
-
-		let pi = match stripe::PaymentIntent::create(&stripe_client, params) {
-			Ok(pi) => {
-				orders
+				if quote.is_expired() {
+					return Err(juniper::FieldError::new(
+						"This quote has expired, please calculate postage again",
+						graphql_value!({
+							"type": "QUOTE_EXPIRED"
+						}),
+					));
+				}
+
+				// Checked again here in case it was paid since it was loaded, so a
+				// paid order's payment intent is never repriced. Orders from before
+				// statuses were stored have none.
+				let unpaid = doc! {
+					"_id": id,
+					"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+				};
+				let still_unpaid = context
+					.orders_handel()
+					.count_documents(unpaid.clone(), None)
+					.await
+					.map_err(|_| editing::EditError::DatabaseError.to_field_error())?;
+				if still_unpaid == 0 {
+					return Err(editing::EditError::AlreadyPaid.to_field_error());
+				}
+
+				// Orders paid for outside the site have no payment intent to update
+				let stripe = order.payment.clone().and_then(|p| p.stripe);
+				if let Some(stripe) = &stripe {
+					let amount = SCARF.price * order.quantity as u64 + quote.price as u64;
+					if !set_amount(&stripe_client, &stripe.pi, amount, None).await
+						|| !checkout::close_session(&order).await
+					{
+						return Err(juniper::FieldError::new(
+							"Failed to update payment intent",
+							graphql_value!({
+								"type": "PAYMENT_ERROR"
+							}),
+						));
+					}
+				}
+
+				let postage = Postage::from_quote(&quote);
+				let changed = OrderEvent::change(
+					EventKind::PostageChanged,
+					context.actor(),
+					"postage",
+					order.postage.as_ref().map(Postage::describe),
+					Some(postage.describe()),
+				);
+
+				let updated = context
+					.orders_handel()
 					.update_one(
-						doc! {"_id" => ObjectId::with_string(&id).unwrap()},
-						doc! {
-							"$set" => {
-								"payment" => {
-									"stripe" => {
-										"pi" => pi.id.as_str(),
-									}
-								}
-							}
-						},
+						unpaid,
+						history::update(
+							doc! { "postage": Bson::Document(postage.to_doc()) },
+							&[changed],
+						),
 						None,
 					)
-					.expect("Updating Order failed");
-				pi
-			},
-			_ => {
-				return Err(juniper::FieldError::new(
-					"Failed to create payment intent",
-					graphql_value!({
-						"type": "NO_WHATEVER"
-					}),
-				))
-			},
-		};
+					.await;
+
+				// Paid for while the payment intent was being updated, or not
+				// saved, so the payment intent goes back to the old price
+				let error = match updated {
+					Ok(updated) if updated.matched_count == 1 => None,
+					Ok(_) => Some(editing::EditError::AlreadyPaid),
+					Err(_) => Some(editing::EditError::DatabaseError),
+				};
+				if let Some(error) = error {
+					if let Some(stripe) = &stripe {
+						set_amount(&get_stripe(), &stripe.pi, order.total() as u64, None).await;
+					}
+					return Err(error.to_field_error());
+				}
 
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => {
-				return Err(juniper::FieldError::new(
-					"UID is not valid",
-					graphql_value!({
-						"type": "INVALID_UID"
-					}),
-				))
-			},
-		};
+				order.postage = Some(postage);
 
-		let mut order = match DBHelper::get::<Order>(orders, id) {
-			Some(order) => order,
-			_ => {
-				return Err(juniper::FieldError::new(
-					"Order dissapeared after creation",
-					graphql_value!({
-						"type": "NO_WHATEVER"
-					}),
-				))
-			},
-		};
+				Ok(order)
+			})
+			.await
+		})
+		.await
+	}
 
-		let mut payment = &mut order
-			.payment
-			.as_mut()
-			.expect("Unwrapping mutable payment failed");
+	/// Change how many scarves are in an unpaid order. The variant only needs
+	/// to be given if the order has more than one item. Postage and the
+	/// payment are repriced.
+	async fn updateOrderQuantity(
+		context : &Context,
+		id : String,
+		quantity : i32,
+		variant : Option<String>,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("updateOrderQuantity", async move {
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "updateOrderQuantity", &id, key, async {
+				editing::update_quantity(context, &id, variant, quantity, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
 
-		let mut stripe = &mut payment
-			.stripe
-			.as_mut()
-			.expect("Unwrapping mutable stripe payment failed");
+	/// Change where an unpaid postal order is sent. Postage and the payment
+	/// are repriced.
+	async fn updateOrderAddress(
+		context : &Context,
+		id : String,
+		address_apt : Option<String>,
+		address_street : Option<String>,
+		address_town : Option<String>,
+		address_state : Option<String>,
+		address_post_code : Option<String>,
+		address_country : Option<String>,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("updateOrderAddress", async move {
+			let address = match Address::validate(
+				address_apt,
+				address_street,
+				address_town,
+				address_state,
+				address_post_code,
+				address_country,
+			) {
+				Ok(address) => address,
+				Err(errors) => return Err(AddressError::to_field_error(&errors)),
+			};
+
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "updateOrderAddress", &id, key, async {
+				editing::update_address(context, &id, address, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
 
-		stripe.client_secret = Some(pi.client_secret.expect("Unwrapping client secret failed"));
+	/// Switch an unpaid order between pickup and postage. Switching to postage
+	/// needs an address unless the order already has one.
+	async fn changeCollectionMethod(
+		context : &Context,
+		id : String,
+		delivery_method : CollectionMethod,
+		pickup_location : Option<String>,
+		address_apt : Option<String>,
+		address_street : Option<String>,
+		address_town : Option<String>,
+		address_state : Option<String>,
+		address_post_code : Option<String>,
+		address_country : Option<String>,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("changeCollectionMethod", async move {
+			let given =
+				address_street.is_some() || address_town.is_some() || address_post_code.is_some();
+			let address = match (delivery_method, given) {
+				(CollectionMethod::Post, true) => match Address::validate(
+					address_apt,
+					address_street,
+					address_town,
+					address_state,
+					address_post_code,
+					address_country,
+				) {
+					Ok(address) => Some(address),
+					Err(errors) => return Err(AddressError::to_field_error(&errors)),
+				},
+				_ => None,
+			};
+
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "changeCollectionMethod", &id, key, async {
+				editing::change_method(context, &id, delivery_method, pickup_location, address, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
 
-		Ok(Some(order))
+	/// Cancel an order that has not been paid for yet
+	async fn cancelOrder(
+		context : &Context,
+		id : String,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("cancelOrder", async move {
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "cancelOrder", &id, key, async {
+				cancellation::cancel_order(context, &id, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
 	}
 
-	/// Set the requested postage method from the user. Once this is done the
-	/// order is practically finalized and just needs to be paid for.
-	fn setPostage(context : &Context, id : String, code : String) -> FieldResult<Order> {
-		let stripe_client = get_stripe();
-		let orders = context.orders_handel();
-
-		orders
-			.update_one(
-				doc! {
-				"_id" => match mongodb::oid::ObjectId::with_string(&id) {
-					Ok(oid) => oid,
-					Err(_) => return Err(juniper::FieldError::new(
-					"UID is not valid",
-					graphql_value!({
-						"type": "NO_WHATEVER"
-					}),
-				))
-				}},
-				doc! {
-					"$set" => {
-						"postage" => {
-							"code" => &code,
-						}
-					}
-				},
-				None,
-			)
-			.unwrap();
+	/// Pay for an order on Stripe's hosted Checkout page instead of with the
+	/// client secret from getStripeCS. Send the customer to the returned URL.
+	/// Retrying with the same idempotency key returns the same session.
+	async fn createCheckoutSession(
+		context : &Context,
+		order_id : String,
+		idempotency_key : Option<String>,
+	) -> FieldResult<CheckoutLink> {
+		metrics::operation("createCheckoutSession", async move {
+			checkout::create_session(context, &order_id, idempotency_key.as_deref())
+				.await
+				.map_err(|e| e.to_field_error())
+		})
+		.await
+	}
 
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => {
-				return Err(juniper::FieldError::new(
-					"UID is not valid",
+	/// Record money received for an order paid outside the site, such as a
+	/// bank transfer or cash at pickup. The order is marked as paid once
+	/// everything it costs has been received.
+	async fn recordOfflinePayment(
+		context : &Context,
+		id : String,
+		amount : f64,
+		reference : String,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("recordOfflinePayment", async move {
+			context.require_admin()?;
+
+			let amount = (amount * 100.0).round() as i64;
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "recordOfflinePayment", &id, key, async {
+				offline::record_payment(context, &id, amount, reference)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
+
+	/// Refund a paid order through Stripe. Without an amount whatever has not
+	/// already been refunded is refunded. Orders paid outside the site can not
+	/// be refunded here.
+	async fn refundOrder(
+		context : &Context,
+		id : String,
+		amount : Option<f64>,
+		reason : String,
+		idempotency_key : Option<String>,
+	) -> FieldResult<Order> {
+		metrics::operation("refundOrder", async move {
+			context.require_admin()?;
+
+			let amount = amount.map(|dollars| (dollars * 100.0).round() as i64);
+			let key = idempotency_key.as_deref();
+			idempotency::once(context, "refundOrder", &id, key, async {
+				cancellation::refund_order(context, &id, amount, reason, key)
+					.await
+					.map_err(|e| e.to_field_error())
+			})
+			.await
+		})
+		.await
+	}
+
+	/// Group every paid order matching the method, pickup location and postage
+	/// service that is not already in a batch into a new fulfilment batch
+	async fn createFulfilmentBatch(
+		context : &Context,
+		name : String,
+		method : CollectionMethod,
+		pickup_location : Option<String>,
+		postage_code : Option<String>,
+	) -> FieldResult<FulfilmentBatch> {
+		metrics::operation("createFulfilmentBatch", async move {
+			context.require_admin()?;
+
+			match fulfilment::create_batch(context, name, method, pickup_location, postage_code)
+				.await
+			{
+				Ok(batch) => Ok(batch),
+				Err(BatchError::NoOrders) => Err(juniper::FieldError::new(
+					"There are no paid orders to put in the batch",
 					graphql_value!({
-						"type": "INVALID_UID"
+						"type": "NO_ORDERS"
 					}),
-				))
-			},
-		};
-
-		let order : Order = match DBHelper::get(orders, id) {
-			Some(o) => o,
-			None => {
-				return Err(juniper::FieldError::new(
-					"The requested order was not found",
+				)),
+				Err(BatchError::DatabaseError) => Err(juniper::FieldError::new(
+					"Failed to create the batch",
 					graphql_value!({
-						"type": "NOT_FOUND"
+						"type": "DATABASE_ERROR"
 					}),
-				))
-			},
-		};
+				)),
+			}
+		})
+		.await
+	}
 
-		let pi = order.payment.clone().unwrap().stripe.unwrap().pi;
+	/// Mark a whole batch, and every order in it, as packed or shipped
+	async fn setFulfilmentBatchStatus(
+		context : &Context,
+		id : String,
+		status : BatchStatus,
+	) -> FieldResult<FulfilmentBatch> {
+		metrics::operation("setFulfilmentBatchStatus", async move {
+			context.require_admin()?;
+
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => {
+					return Err(juniper::FieldError::new(
+						"UID is not valid",
+						graphql_value!({
+							"type": "INVALID_UID"
+						}),
+					))
+				},
+			};
 
-		let q = order.quantity.clone();
+			let mut batch : FulfilmentBatch =
+				match DBHelper::get(context.batches_handel(), id).await {
+					Some(b) => b,
+					None => {
+						return Err(juniper::FieldError::new(
+							"The requested batch was not found",
+							graphql_value!({
+								"type": "NOT_FOUND"
+							}),
+						))
+					},
+				};
 
-		let dopts = match PostDeliveryOption::get(
-			order.quantity as u32,
-			order.address.clone().unwrap().post_code as u32,
-		) {
-			Ok(opts) => opts,
-			Err(_) => {
-				return Err(juniper::FieldError::new(
-					"Quantity must be greater than 0",
+			match fulfilment::set_status(context, &mut batch, status).await {
+				Ok(_) => Ok(batch),
+				Err(_) => Err(juniper::FieldError::new(
+					"Failed to update the batch",
 					graphql_value!({
-						"type": "INVALID_QUANTITY"
+						"type": "DATABASE_ERROR"
 					}),
-				))
-			},
-		};
-
-		let opt = dopts
-			.into_iter()
-			.filter(|opt : &PostDeliveryOption| opt.code == code)
-			.collect::<Vec<PostDeliveryOption>>();
-
-		let opt : &PostDeliveryOption = match opt.first() {
-			Some(o) => o,
-			_ => {
-				return Err(juniper::FieldError::new(
-					"User has not selected a valid postage option",
-					graphql_value!({
-						"type": "INVALID_QUANTITY"
-					}),
-				))
-			},
-		};
-
-		let price = (opt.price * f64::from(100)) as u64;
-
-		match stripe::PaymentIntent::update(
-			&stripe_client,
-			&pi,
-			stripe::PaymentIntentUpdateParams {
-				amount :                  Some(SCARVE_PRICE * q as u64 + price),
-				application_fee_amount :  None,
-				currency :                None,
-				customer :                None,
-				description :             None,
-				metadata :                None,
-				receipt_email :           None,
-				save_source_to_customer : None,
-				shipping :                None,
-				source :                  None,
-				transfer_group :          None,
-			},
-		) {
-			Ok(p) => {},
-			_ => {},
-		};
-
-		Ok(order)
+				)),
+			}
+		})
+		.await
 	}
 }
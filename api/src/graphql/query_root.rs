@@ -1,180 +1,385 @@
 use crate::{
-	db::helpers as DBHelper,
+	catalogue::{self, Product},
+	dashboard::{self, Dashboard, DashboardError},
+	db::{
+		helpers as DBHelper,
+		orders::{self as OrderQuery, OrderConnection, OrderFilter, OrderQueryError, OrderSort},
+	},
 	graphql::context::Context,
-	models::{CollectionMethod, Order, PostDeliveryOption},
-	stripe::get_stripe,
+	history, metrics,
+	models::{CollectionMethod, FulfilmentBatch, Order, PostDeliveryOption, PostageQuote},
+	order_number,
+	search::{self, SearchError, SearchResult},
+	stripe::{client_secret, get_stripe, payment_intent},
 };
-use juniper::{graphql_value, FieldResult};
+use chrono::{DateTime, Utc};
+use juniper::{graphql_object, graphql_value, FieldResult};
+use mongodb::bson::Bson;
 
 pub struct QueryRoot;
-#[juniper::object(
-    Context = Context,
-)]
+#[graphql_object(context = Context)]
 impl QueryRoot {
-	/// All orders
-	fn orders(context : &Context) -> Vec<Order> {
-		let orders = context.orders_handel();
-		DBHelper::all(orders)
-	}
-
-	fn order(context : &Context, id : String) -> FieldResult<Option<Order>> {
-		let orders = context.orders_handel();
+	/// A page of orders for admins, filtered and sorted. Newest orders are
+	/// first unless another sort is given. Pass the endCursor of a page as
+	/// `after` to get the next one.
+	async fn orders(
+		context : &Context,
+		first : Option<i32>,
+		after : Option<String>,
+		filter : Option<OrderFilter>,
+		sort : Option<OrderSort>,
+	) -> FieldResult<OrderConnection> {
+		metrics::operation("orders", async move {
+			context.require_admin()?;
 
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => {
-				return Err(juniper::FieldError::new(
-					"UID is not valid",
+			match OrderQuery::page(
+				context.orders_handel(),
+				&filter.unwrap_or_default(),
+				&sort.unwrap_or_default(),
+				first,
+				after,
+			)
+			.await
+			{
+				Ok(page) => Ok(page),
+				Err(OrderQueryError::InvalidCursor) => Err(juniper::FieldError::new(
+					"The cursor is not valid",
+					graphql_value!({
+						"type": "INVALID_CURSOR"
+					}),
+				)),
+				Err(OrderQueryError::DatabaseError) => Err(juniper::FieldError::new(
+					"Failed to load orders",
 					graphql_value!({
-						"type": "INVALID_UID"
+						"type": "DATABASE_ERROR"
 					}),
-				))
-			},
-		};
+				)),
+			}
+		})
+		.await
+	}
+
+	/// Every fulfilment batch, for admins
+	async fn fulfilmentBatches(context : &Context) -> FieldResult<Vec<FulfilmentBatch>> {
+		metrics::operation("fulfilmentBatches", async move {
+			context.require_admin()?;
 
-		Ok(DBHelper::get(orders, id))
+			Ok(DBHelper::all(context.batches_handel()).await)
+		})
+		.await
 	}
 
-	/// For an order, calculate the price to post the items to the user
-	fn calculatePostage(context : &Context, id : String) -> FieldResult<Vec<PostDeliveryOption>> {
-		let orders = context.orders_handel();
+	async fn fulfilmentBatch(
+		context : &Context,
+		id : String,
+	) -> FieldResult<Option<FulfilmentBatch>> {
+		metrics::operation("fulfilmentBatch", async move {
+			context.require_admin()?;
 
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => {
-				return Err(juniper::FieldError::new(
-					"UID is not valid",
-					graphql_value!({
-						"type": "INVALID_UID"
-					}),
-				))
-			},
-		};
-
-		let order : Order = match DBHelper::get(orders, id) {
-			Some(o) => o,
-			None => {
-				return Err(juniper::FieldError::new(
-					"The requested order was not found",
-					graphql_value!({
-						"type": "NOT_FOUND"
-					}),
-				))
-			},
-		};
-
-		let postcode = match order.address {
-			Some(addr) => addr.post_code,
-			None => {
-				return Err(juniper::FieldError::new(
-					"This order does not have an address defined. This is likely because the Pickup option was selected",
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => {
+					return Err(juniper::FieldError::new(
+						"UID is not valid",
+						graphql_value!({
+							"type": "INVALID_UID"
+						}),
+					))
+				},
+			};
+
+			Ok(DBHelper::get(context.batches_handel(), id).await)
+		})
+		.await
+	}
+
+	/// Everything we sell, including the details needed for customs
+	async fn products() -> Vec<Product> {
+		metrics::operation("products", async move { catalogue::products() }).await
+	}
+
+	/// Live sales figures for admins, for orders placed from `from` until
+	/// before `to`, or every order
+	async fn salesDashboard(
+		context : &Context,
+		from : Option<DateTime<Utc>>,
+		to : Option<DateTime<Utc>>,
+	) -> FieldResult<Dashboard> {
+		metrics::operation("salesDashboard", async move {
+			context.require_admin()?;
+
+			match dashboard::dashboard(context.orders_handel(), from, to).await {
+				Ok(dashboard) => Ok(dashboard),
+				Err(DashboardError::DatabaseError) => Err(juniper::FieldError::new(
+					"Failed to load the sales figures",
 					graphql_value!({
-						"type": "NO_ADDRESS"
+						"type": "DATABASE_ERROR"
 					}),
-				))
-			},
-		};
-
-		match PostDeliveryOption::get(order.quantity as u32, postcode as u32) {
-			Ok(opts) => Ok(opts),
-			Err(_) => Err(juniper::FieldError::new(
-				"Quantity must be greater than 0",
-				graphql_value!({
-					"type": "INVALID_QUANTITY"
-				}),
-			)),
-		}
+				)),
+			}
+		})
+		.await
 	}
 
-	/// Return the price of the order, excluding postage
-	fn orderPrice(context : &Context, id : String) -> FieldResult<f64> {
-		let orders = context.orders_handel();
-
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => {
-				return Err(juniper::FieldError::new(
-					"UID is not valid",
+	/// Find orders for admins from any part of the customer's name, email or
+	/// address, or the order ID. Best matches are first.
+	async fn searchOrders(
+		context : &Context,
+		query : String,
+		limit : Option<i32>,
+	) -> FieldResult<Vec<SearchResult>> {
+		metrics::operation("searchOrders", async move {
+			context.require_admin()?;
+
+			match search::search(context.orders_handel(), &query, limit).await {
+				Ok(results) => Ok(results),
+				Err(SearchError::EmptyQuery) => Err(juniper::FieldError::new(
+					"Enter something to search for",
 					graphql_value!({
-						"type": "INVALID_UID"
+						"type": "EMPTY_QUERY"
 					}),
-				))
-			},
-		};
-
-		let order : Order = match DBHelper::get(orders, id) {
-			Some(o) => o,
-			None => {
-				return Err(juniper::FieldError::new(
-					"The requested order was not found",
+				)),
+				Err(SearchError::DatabaseError) => Err(juniper::FieldError::new(
+					"Failed to search orders",
 					graphql_value!({
-						"type": "NOT_FOUND"
+						"type": "DATABASE_ERROR"
 					}),
-				))
-			},
-		};
+				)),
+			}
+		})
+		.await
+	}
 
-		let stripe_client = get_stripe();
+	/// Look up an order by its ID or its order number. Order numbers are easy
+	/// to guess, so unless the request is from an admin the email the order
+	/// was placed with has to be given as well.
+	async fn order(
+		context : &Context,
+		id : String,
+		email : Option<String>,
+	) -> FieldResult<Option<Order>> {
+		metrics::operation("order", async move {
+			let orders = context.orders_handel();
 
-		let pi = order.payment.unwrap().stripe.unwrap().pi;
+			if let Some(number) = order_number::parse(&id) {
+				let order : Option<Order> =
+					DBHelper::find_one(orders, doc! {"number": number}).await;
+				if context.admin {
+					return Ok(order);
+				}
 
-		let price = match stripe::PaymentIntent::retrieve(&stripe_client, &pi) {
-			Ok(pi) => pi.amount,
-			_ => {
-				return Err(juniper::FieldError::new(
-					"Internal Error decoding Document from database",
-					graphql_value!({
-						"type": "NO_WHATEVER"
-					}),
-				))
-			},
-		};
+				let email = match email {
+					Some(email) => email,
+					None => {
+						return Err(juniper::FieldError::new(
+							"An email address is required to look up an order by its number",
+							graphql_value!({
+								"type": "EMAIL_REQUIRED"
+							}),
+						))
+					},
+				};
+				// A wrong email looks the same as a missing order
+				return Ok(
+					order.filter(|order| order.user.email.eq_ignore_ascii_case(email.trim()))
+				);
+			}
 
-		Ok(f64::from(price as u32) / 100.0)
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => {
+					return Err(juniper::FieldError::new(
+						"UID is not valid",
+						graphql_value!({
+							"type": "INVALID_UID"
+						}),
+					))
+				},
+			};
+
+			Ok(DBHelper::get(orders, id).await)
+		})
+		.await
+	}
+
+	/// For an order, calculate the price to post the items to the user. Each
+	/// option is issued as a quote that can be accepted with setPostage until
+	/// it expires. Previously issued quotes for the order are replaced.
+	async fn calculatePostage(context : &Context, id : String) -> FieldResult<Vec<PostageQuote>> {
+		metrics::operation("calculatePostage", async move {
+			let orders = context.orders_handel();
+
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => {
+					return Err(juniper::FieldError::new(
+						"UID is not valid",
+						graphql_value!({
+							"type": "INVALID_UID"
+						}),
+					))
+				},
+			};
+
+			let order : Order = match DBHelper::get(orders, id).await {
+				Some(o) => o,
+				None => {
+					return Err(juniper::FieldError::new(
+						"The requested order was not found",
+						graphql_value!({
+							"type": "NOT_FOUND"
+						}),
+					))
+				},
+			};
+
+			let address = match order.address {
+				Some(addr) => addr,
+				None => {
+					return Err(juniper::FieldError::new(
+						"This order does not have an address defined. This is likely because the Pickup option was selected",
+						graphql_value!({
+							"type": "NO_ADDRESS"
+						}),
+					))
+				},
+			};
+
+			let quotes = match PostDeliveryOption::get(order.quantity as u32, &address).await {
+				Ok(opts) => PostageQuote::issue(opts),
+				Err(_) => {
+					return Err(juniper::FieldError::new(
+						"Quantity must be greater than 0",
+						graphql_value!({
+							"type": "INVALID_QUANTITY"
+						}),
+					))
+				},
+			};
+
+			let quote_docs : Vec<Bson> = quotes
+				.iter()
+				.map(|quote| Bson::Document(quote.to_doc()))
+				.collect();
+
+			context
+				.orders_handel()
+				.update_one(
+					doc! {"_id": id},
+					history::update(doc! { "quotes": quote_docs }, &[]),
+					None,
+				)
+				.await
+				.expect("Storing postage quotes failed");
+
+			Ok(quotes)
+		})
+		.await
 	}
 
 	/// Return the price of the order, excluding postage
-	fn getStripeCS(context : &Context, id : String) -> Option<String> {
-		let orders = context.orders_handel();
+	async fn orderPrice(context : &Context, id : String) -> FieldResult<f64> {
+		metrics::operation("orderPrice", async move {
+			let orders = context.orders_handel();
 
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => return None,
-		};
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => {
+					return Err(juniper::FieldError::new(
+						"UID is not valid",
+						graphql_value!({
+							"type": "INVALID_UID"
+						}),
+					))
+				},
+			};
 
-		let order : Order = match DBHelper::get(orders, id) {
-			Some(o) => o,
-			None => return None,
-		};
+			let order : Order = match DBHelper::get(orders, id).await {
+				Some(o) => o,
+				None => {
+					return Err(juniper::FieldError::new(
+						"The requested order was not found",
+						graphql_value!({
+							"type": "NOT_FOUND"
+						}),
+					))
+				},
+			};
 
-		let stripe_client = get_stripe();
+			let stripe_client = get_stripe();
 
-		let pi = order.payment.unwrap().stripe.unwrap().pi;
+			let pi = match order.payment.and_then(|p| p.stripe) {
+				Some(stripe) => stripe.pi,
+				None => {
+					return Err(juniper::FieldError::new(
+						"This order is not paid for on the site",
+						graphql_value!({
+							"type": "OFFLINE_PAYMENT"
+						}),
+					))
+				},
+			};
 
-		let cs = match stripe::PaymentIntent::retrieve(&stripe_client, &pi) {
-			Ok(pi) => pi.client_secret.unwrap(),
-			_ => return None,
-		};
+			let price = match payment_intent(&stripe_client, &pi).await {
+				Some(pi) => pi.amount,
+				_ => {
+					return Err(juniper::FieldError::new(
+						"Internal Error decoding Document from database",
+						graphql_value!({
+							"type": "NO_WHATEVER"
+						}),
+					))
+				},
+			};
 
-		Some(cs)
+			Ok(f64::from(price as u32) / 100.0)
+		})
+		.await
 	}
 
 	/// Return the price of the order, excluding postage
-	fn getOrderMethod(context : &Context, id : String) -> Option<String> {
-		let orders = context.orders_handel();
-		let id = match mongodb::oid::ObjectId::with_string(&id) {
-			Ok(oid) => oid,
-			Err(_) => return None,
-		};
-
-		let order : Order = match DBHelper::get(orders, id) {
-			Some(o) => o,
-			None => return None,
-		};
-
-		Some(String::from(match order.method {
-			CollectionMethod::Pickup => "PICKUP",
-			CollectionMethod::Post => "POST",
-		}))
+	async fn getStripeCS(context : &Context, id : String) -> Option<String> {
+		metrics::operation("getStripeCS", async move {
+			let orders = context.orders_handel();
+
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => return None,
+			};
+
+			let order : Order = match DBHelper::get(orders, id).await {
+				Some(o) => o,
+				None => return None,
+			};
+
+			let stripe_client = get_stripe();
+
+			let pi = order.payment?.stripe?.pi;
+
+			client_secret(&stripe_client, &pi).await
+		})
+		.await
+	}
+
+	/// Return the price of the order, excluding postage
+	async fn getOrderMethod(context : &Context, id : String) -> Option<String> {
+		metrics::operation("getOrderMethod", async move {
+			let orders = context.orders_handel();
+			let id = match mongodb::bson::oid::ObjectId::parse_str(&id) {
+				Ok(oid) => oid,
+				Err(_) => return None,
+			};
+
+			let order : Order = match DBHelper::get(orders, id).await {
+				Some(o) => o,
+				None => return None,
+			};
+
+			Some(String::from(match order.method {
+				CollectionMethod::Pickup => "PICKUP",
+				CollectionMethod::Post => "POST",
+			}))
+		})
+		.await
 	}
 }
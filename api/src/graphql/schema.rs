@@ -1,9 +1,27 @@
-use crate::models::{
-	Address, CollectionMethod, Order, Payment, PaymentStripe, PostDeliveryOption, Postage, User,
+use crate::{
+	address::AustralianState,
+	catalogue::{self, Product, SCARF},
+	checkout::CheckoutLink,
+	config::{self, BankAccount},
+	dashboard::{Dashboard, Sales, VariantSales},
+	db::orders::{OrderConnection, OrderEdge},
+	fulfilment::{self, PickListLine},
+	graphql::context::Context,
+	history::{Actor, EventKind, OrderEvent},
+	models::{
+		Address, Allocation, BatchStatus, CollectionMethod, FulfilmentBatch, Group, GroupKind,
+		LineItem, OfflinePayment, Order, OrderStatus, Payment, PaymentMethod, PaymentStripe,
+		PostDeliveryOption, Postage, PostageQuote, Refund, User,
+	},
+	search::{Highlight, MatchRange, SearchResult},
 };
-use juniper::ID;
+use chrono::{DateTime, Utc};
+use juniper::{graphql_object, FieldResult, ID};
 
-#[juniper::object(description = "Contact Details of the person making the purchase")]
+#[graphql_object(
+	context = Context,
+	description = "Contact Details of the person making the purchase"
+)]
 impl User {
 	/// Contact name
 	fn name(&self) -> &str { &self.name }
@@ -12,12 +30,16 @@ impl User {
 	fn email(&self) -> &str { &self.email }
 }
 
-#[juniper::object(
+#[graphql_object(
+	context = Context,
 	description = "The root order. This holds all details on an order including contact, address and postage information"
 )]
 impl Order {
 	fn id(&self) -> &ID { &self.id }
 
+	/// Short order number for the customer to quote, such as RS-2026-00421
+	fn number(&self) -> Option<String> { self.number.clone() }
+
 	/// Contact details
 	fn user(&self) -> User { self.user.clone() }
 
@@ -30,13 +52,104 @@ impl Order {
 	/// quantity of scarves to be delivered
 	fn quantity(&self) -> i32 { self.quantity }
 
+	/// the scarves in the order by variant
+	fn items(&self) -> Vec<LineItem> { self.items.clone() }
+
+	/// The group a leader is ordering for
+	fn group(&self) -> Option<Group> { self.group.clone() }
+
+	/// Who in the group each scarf is for
+	fn allocations(&self) -> Vec<Allocation> { self.allocations.clone() }
+
 	/// is the item Picked up or delivered
 	fn method(&self) -> CollectionMethod { self.method }
 
+	/// where the order will be picked up from
+	fn pickup_location(&self) -> Option<String> { self.pickup_location.clone() }
+
 	fn payment(&self) -> Option<Payment> { self.payment.clone() }
+
+	/// Where the order is up to
+	fn status(&self) -> OrderStatus { self.status }
+
+	/// Money that has been given back, oldest first
+	fn refunds(&self) -> Vec<Refund> { self.refunds.clone() }
+
+	fn created_at(&self) -> DateTime<Utc> { self.created_at }
+
+	/// When anything about the order last changed
+	fn updated_at(&self) -> DateTime<Utc> { self.updated_at }
+
+	/// Everything that has happened to the order, oldest first. Admins only.
+	fn history(&self, context : &Context) -> FieldResult<Vec<OrderEvent>> {
+		context.require_admin()?;
+		Ok(self.history.clone())
+	}
+}
+
+#[graphql_object(
+	context = Context,
+	description = "Money given back to the customer through Stripe"
+)]
+impl Refund {
+	/// The Stripe refund ID
+	fn id(&self) -> &str { &self.id }
+
+	fn amount(&self) -> f64 { self.amount as f64 / 100.0 }
+
+	/// The amount formatted with its currency
+	fn display_amount(&self) -> String { catalogue::format_price(self.amount) }
+
+	fn reason(&self) -> &str { &self.reason }
+
+	fn created_at(&self) -> DateTime<Utc> { self.created_at }
+}
+
+#[graphql_object(context = Context, description = "Something that happened to an order")]
+impl OrderEvent {
+	fn kind(&self) -> EventKind { self.kind }
+
+	/// Who did it
+	fn actor(&self) -> Actor { self.actor }
+
+	fn at(&self) -> DateTime<Utc> { self.at }
+
+	/// The part of the order that changed
+	fn field(&self) -> Option<String> { self.field.clone() }
+
+	/// The value before the change
+	fn previous(&self) -> Option<String> { self.previous.clone() }
+
+	/// The value after the change
+	fn new(&self) -> Option<String> { self.new.clone() }
 }
 
-#[juniper::object(description = "Delivery Address")]
+#[graphql_object(
+	context = Context,
+	description = "The scout group, unit or section a group order is for"
+)]
+impl Group {
+	fn name(&self) -> &str { &self.name }
+
+	fn kind(&self) -> GroupKind { self.kind }
+}
+
+#[graphql_object(context = Context, description = "Scarves in a group order for one member")]
+impl Allocation {
+	/// Pass this to removeAllocation to remove it
+	fn id(&self) -> &ID { &self.id }
+
+	fn member(&self) -> &str { &self.member }
+
+	fn variant(&self) -> &str { &self.variant }
+
+	/// The name of the product and variant
+	fn name(&self) -> String { SCARF.item_name(&self.variant) }
+
+	fn quantity(&self) -> i32 { self.quantity }
+}
+
+#[graphql_object(context = Context, description = "Delivery Address")]
 impl Address {
 	fn apartment(&self) -> Option<String> { self.apartment.clone() }
 
@@ -44,17 +157,67 @@ impl Address {
 
 	fn town(&self) -> &str { &self.town }
 
-	fn state(&self) -> &str { &self.state }
+	/// Set for Australian addresses
+	fn state(&self) -> Option<AustralianState> { self.state }
+
+	/// State, province or county of an overseas address
+	fn region(&self) -> Option<String> { self.region.clone() }
+
+	/// Four digit postcode, including any leading zero
+	fn post_code(&self) -> &str { self.post_code.as_str() }
 
-	fn post_code(&self) -> i32 { self.post_code }
+	/// ISO 3166-1 alpha-2 country code
+	fn country(&self) -> &str { &self.country }
 }
 
-#[juniper::object(description = "The type of postage the user has selected, and the price")]
+#[graphql_object(
+	context = Context,
+	description = "The type of postage the user has selected, and the price"
+)]
 impl Postage {
 	fn code(&self) -> &str { &self.code }
+
+	/// The name of the delivery option
+	fn name(&self) -> &str { &self.name }
+
+	/// The price that was locked in by the accepted quote
+	fn price(&self) -> f64 { self.price as f64 / 100.0 }
+
+	/// The price formatted with its currency
+	fn display_price(&self) -> String { catalogue::format_price(self.price) }
+
+	fn currency(&self) -> &str { catalogue::CURRENCY }
+
+	/// The quote that was accepted for this postage
+	fn quote_id(&self) -> Option<String> { self.quote_id.clone() }
 }
 
-#[juniper::object(description = "A post delivery option from Australia Post")]
+#[graphql_object(
+	context = Context,
+	description = "A postage price that is locked against an order until it expires"
+)]
+impl PostageQuote {
+	/// Pass this to setPostage to accept the quote
+	fn id(&self) -> &ID { &self.id }
+
+	/// The name of the delivery option
+	fn name(&self) -> &str { &self.name }
+
+	/// The price that will be charged if this quote is accepted
+	fn price(&self) -> f64 { self.price as f64 / 100.0 }
+
+	/// The price formatted with its currency
+	fn display_price(&self) -> String { catalogue::format_price(self.price) }
+
+	fn currency(&self) -> &str { catalogue::CURRENCY }
+
+	fn code(&self) -> &str { &self.code }
+
+	/// The quote can not be accepted after this time
+	fn expires_at(&self) -> DateTime<Utc> { self.expires_at }
+}
+
+#[graphql_object(context = Context, description = "A post delivery option from Australia Post")]
 impl PostDeliveryOption {
 	/// The name of the delivery option
 	fn name(&self) -> &str { &self.name }
@@ -65,14 +228,283 @@ impl PostDeliveryOption {
 	fn code(&self) -> &str { &self.code }
 }
 
-#[juniper::object]
-#[derive(Clone, Debug)]
+#[graphql_object(context = Context)]
 impl Payment {
+	fn method(&self) -> PaymentMethod { self.method }
+
+	/// What to write in the description of a bank transfer
+	fn reference(&self) -> Option<&str> { self.reference.as_deref() }
+
+	/// Where to send a bank transfer
+	fn bank_account(&self) -> Option<BankAccount> {
+		match self.method {
+			PaymentMethod::BankTransfer => config::bank_account(),
+			_ => None,
+		}
+	}
+
+	/// Payments recorded by the committee
+	fn received(&self) -> Vec<OfflinePayment> { self.received.clone() }
+
 	fn stripe(&self) -> Option<PaymentStripe> { self.stripe.clone() }
 }
 
-#[juniper::object]
-#[derive(Clone, Debug)]
+#[graphql_object(context = Context, description = "A Stripe Checkout page for an order")]
+impl CheckoutLink {
+	/// The Checkout Session ID
+	fn session(&self) -> &str { &self.session }
+
+	/// Where to send the customer to pay
+	fn url(&self) -> &str { &self.url }
+}
+
+#[graphql_object(context = Context)]
+impl BankAccount {
+	fn name(&self) -> &str { &self.name }
+
+	fn bsb(&self) -> &str { &self.bsb }
+
+	fn number(&self) -> &str { &self.number }
+}
+
+#[graphql_object(context = Context, description = "Money received outside the site")]
+impl OfflinePayment {
+	fn amount(&self) -> f64 { self.amount as f64 / 100.0 }
+
+	/// The amount formatted with its currency
+	fn display_amount(&self) -> String { catalogue::format_price(self.amount) }
+
+	fn reference(&self) -> &str { &self.reference }
+
+	fn recorded_at(&self) -> DateTime<Utc> { self.recorded_at }
+}
+
+#[graphql_object(context = Context)]
 impl PaymentStripe {
 	fn client_secret(&self) -> Option<String> { self.client_secret.clone() }
 }
+
+#[graphql_object(context = Context, description = "Something we sell")]
+impl Product {
+	fn code(&self) -> &str { self.code }
+
+	fn name(&self) -> &str { self.name }
+
+	/// Price of a single item
+	fn price(&self) -> f64 { self.price as f64 / 100.0 }
+
+	/// The price formatted with its currency
+	fn display_price(&self) -> String { catalogue::format_price(self.price as i64) }
+
+	fn currency(&self) -> &str { catalogue::CURRENCY }
+
+	/// Packed weight of a single item
+	fn weight_kg(&self) -> f64 { self.weight_kg }
+
+	/// Description of the goods for customs declarations
+	fn customs_description(&self) -> &str { self.customs_description }
+
+	/// Harmonized System tariff code for customs declarations
+	fn hs_tariff_code(&self) -> &str { self.hs_tariff_code }
+
+	/// ISO 3166-1 alpha-2 code of the country the item was made in
+	fn origin_country(&self) -> &str { self.origin_country }
+}
+
+#[graphql_object(context = Context, description = "A number of scarves of one variant")]
+impl LineItem {
+	fn variant(&self) -> &str { &self.variant }
+
+	/// Display name of the variant
+	fn name(&self) -> &str {
+		match SCARF.variant(&self.variant) {
+			Some(v) => v.name,
+			None => &self.variant,
+		}
+	}
+
+	fn quantity(&self) -> i32 { self.quantity }
+}
+
+#[graphql_object(
+	context = Context,
+	description = "A group of paid orders that are packed and shipped together"
+)]
+impl FulfilmentBatch {
+	fn id(&self) -> &ID { &self.id }
+
+	fn name(&self) -> &str { &self.name }
+
+	fn method(&self) -> CollectionMethod { self.method }
+
+	/// Only orders picked up from here are in the batch
+	fn pickup_location(&self) -> Option<String> { self.pickup_location.clone() }
+
+	/// Only orders posted with this service are in the batch
+	fn postage_code(&self) -> Option<String> { self.postage_code.clone() }
+
+	fn status(&self) -> BatchStatus { self.status }
+
+	fn created_at(&self) -> DateTime<Utc> { self.created_at }
+
+	async fn orders(&self, context : &Context) -> Vec<Order> {
+		fulfilment::orders(context, self).await
+	}
+
+	/// Total scarves of each variant to pick for the batch
+	async fn pick_list(&self, context : &Context) -> Vec<PickListLine> {
+		fulfilment::pick_list(&fulfilment::orders(context, self).await)
+	}
+}
+
+#[graphql_object(context = Context, description = "Total number of scarves of one variant to pick")]
+impl PickListLine {
+	fn variant(&self) -> &str { &self.variant }
+
+	fn name(&self) -> &str { &self.name }
+
+	fn quantity(&self) -> i32 { self.quantity }
+}
+
+#[graphql_object(context = Context, description = "A page of orders")]
+impl OrderConnection {
+	fn edges(&self) -> &Vec<OrderEdge> { &self.edges }
+
+	fn page_info(&self) -> PageInfo {
+		PageInfo {
+			has_next_page :     self.has_next_page,
+			has_previous_page : self.has_previous,
+			start_cursor :      self.edges.first().map(|edge| edge.cursor.to_owned()),
+			end_cursor :        self.edges.last().map(|edge| edge.cursor.to_owned()),
+		}
+	}
+
+	/// Number of orders matching the filter, across every page
+	fn total_count(&self) -> i32 { self.total_count }
+}
+
+#[graphql_object(context = Context)]
+impl OrderEdge {
+	fn cursor(&self) -> &str { &self.cursor }
+
+	fn node(&self) -> &Order { &self.node }
+}
+
+pub struct PageInfo {
+	has_next_page :     bool,
+	has_previous_page : bool,
+	start_cursor :      Option<String>,
+	end_cursor :        Option<String>,
+}
+
+#[graphql_object(context = Context)]
+impl PageInfo {
+	fn has_next_page(&self) -> bool { self.has_next_page }
+
+	fn has_previous_page(&self) -> bool { self.has_previous_page }
+
+	fn start_cursor(&self) -> Option<String> { self.start_cursor.clone() }
+
+	fn end_cursor(&self) -> Option<String> { self.end_cursor.clone() }
+}
+
+#[graphql_object(context = Context, description = "An order that matched a search")]
+impl SearchResult {
+	fn order(&self) -> &Order { &self.order }
+
+	/// Higher scores are better matches
+	fn score(&self) -> f64 { self.score }
+
+	/// The fields that matched the search
+	fn highlights(&self) -> &Vec<Highlight> { &self.highlights }
+}
+
+#[graphql_object(context = Context, description = "A field of an order that matched a search")]
+impl Highlight {
+	fn field(&self) -> &str { &self.field }
+
+	fn value(&self) -> &str { &self.value }
+
+	/// Where the search terms appear in the value
+	fn matches(&self) -> &Vec<MatchRange> { &self.matches }
+}
+
+#[graphql_object(context = Context, description = "Character offsets of a match, end exclusive")]
+impl MatchRange {
+	fn start(&self) -> i32 { self.start }
+
+	fn end(&self) -> i32 { self.end }
+}
+
+#[graphql_object(
+	context = Context,
+	description = "Sales figures for the orders placed in a period, for admins"
+)]
+impl Dashboard {
+	/// Every order that has been paid for
+	fn totals(&self) -> &Sales { &self.totals }
+
+	/// Sales for each day, in Sydney time, oldest first
+	fn by_day(&self) -> &Vec<Sales> { &self.by_day }
+
+	/// Sales for PICKUP and POST orders
+	fn by_method(&self) -> &Vec<Sales> { &self.by_method }
+
+	/// Sales for each Australian state, with overseas orders under their
+	/// country code
+	fn by_state(&self) -> &Vec<Sales> { &self.by_state }
+
+	/// Sales for domestic orders by the first two digits of the postcode
+	fn by_region(&self) -> &Vec<Sales> { &self.by_region }
+
+	fn by_variant(&self) -> &Vec<VariantSales> { &self.by_variant }
+
+	/// Orders waiting to be paid for
+	fn unpaid_orders(&self) -> i32 { self.unpaid_orders as i32 }
+
+	fn unpaid_scarves(&self) -> i32 { self.unpaid_scarves as i32 }
+
+	/// Orders that expired without being paid for
+	fn abandoned_orders(&self) -> i32 { self.abandoned_orders as i32 }
+}
+
+#[graphql_object(context = Context, description = "Sales figures for one group of orders")]
+impl Sales {
+	/// What the orders have in common, such as the day or the state. Empty
+	/// for the totals.
+	fn key(&self) -> &str { &self.key }
+
+	fn orders(&self) -> i32 { self.orders as i32 }
+
+	fn scarves(&self) -> i32 { self.scarves as i32 }
+
+	/// Everything customers paid, including postage
+	fn gross(&self) -> f64 { self.gross as f64 / 100.0 }
+
+	fn postage(&self) -> f64 { self.postage as f64 / 100.0 }
+
+	fn refunded(&self) -> f64 { self.refunded as f64 / 100.0 }
+
+	/// What was kept for the scarves, without postage or refunds
+	fn net(&self) -> f64 { self.net() as f64 / 100.0 }
+
+	/// Scarves in an order on average
+	fn average_scarves(&self) -> f64 { self.average_scarves() }
+
+	/// Amount paid for an order on average
+	fn average_value(&self) -> f64 { self.average_value() / 100.0 }
+}
+
+#[graphql_object(context = Context, description = "Scarves sold of one variant")]
+impl VariantSales {
+	fn variant(&self) -> &str { &self.variant }
+
+	fn name(&self) -> String { SCARF.item_name(&self.variant) }
+
+	fn orders(&self) -> i32 { self.orders as i32 }
+
+	fn scarves(&self) -> i32 { self.scarves as i32 }
+
+	/// What the scarves sold for, without postage or refunds
+	fn revenue(&self) -> f64 { self.revenue() as f64 / 100.0 }
+}
@@ -0,0 +1,130 @@
+//! What the container platform asks the server about itself. Liveness only
+//! says the process is running. Readiness checks everything a request needs:
+//! the database can be reached and the required settings are set. The state
+//! of each upstream's circuit breaker is reported too, and only counts
+//! against readiness when asked for, since the server can still take orders
+//! for pickup while AusPost is down.
+
+use crate::{
+	config,
+	db::PrimaryDb,
+	upstream::{CircuitState, Service},
+};
+use rocket::tokio::time::timeout;
+use serde::Serialize;
+use std::time::Duration;
+
+/// How long the database has to answer a ping
+const PING_TIMEOUT : Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct Liveness {
+	pub status : &'static str,
+}
+
+pub fn liveness() -> Liveness {
+	Liveness {
+		status : "ok"
+	}
+}
+
+#[derive(Serialize)]
+pub struct Version {
+	pub version :  &'static str,
+	pub git_hash : &'static str,
+	pub built_at : &'static str,
+}
+
+pub fn version() -> Version {
+	Version {
+		version :  env!("CARGO_PKG_VERSION"),
+		git_hash : env!("GIT_HASH"),
+		built_at : env!("BUILD_TIME"),
+	}
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+	pub ready :     bool,
+	pub checks :    Vec<Check>,
+	pub upstreams : Vec<Upstream>,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+	pub name :   &'static str,
+	pub ok :     bool,
+	/// What is wrong, if the check failed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub detail : Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Upstream {
+	pub service : &'static str,
+	pub circuit : &'static str,
+}
+
+/// Whether the server can take requests. Upstreams with an open circuit
+/// only make the server unready if `upstream` is set.
+pub async fn readiness(db : Option<&PrimaryDb>, upstream : bool) -> Readiness {
+	let mut checks = vec![database(db).await, settings()];
+
+	let upstreams = Service::ALL
+		.iter()
+		.map(|service| Upstream {
+			service : service.name(),
+			circuit : service.circuit().name(),
+		})
+		.collect();
+
+	if upstream {
+		checks.extend(Service::ALL.iter().map(|service| {
+			let circuit = service.circuit();
+			Check {
+				name :   service.name(),
+				ok :     circuit != CircuitState::Open,
+				detail : match circuit {
+					CircuitState::Open => Some("Too many requests have failed".to_string()),
+					_ => None,
+				},
+			}
+		}));
+	}
+
+	Readiness {
+		ready : checks.iter().all(|check| check.ok),
+		checks,
+		upstreams,
+	}
+}
+
+async fn database(db : Option<&PrimaryDb>) -> Check {
+	let error = match db {
+		None => Some("Not connected".to_string()),
+		Some(db) => match timeout(PING_TIMEOUT, db.run_command(doc! { "ping": 1 }, None)).await {
+			Ok(Ok(_)) => None,
+			Ok(Err(e)) => Some(e.to_string()),
+			Err(_) => Some("Timed out".to_string()),
+		},
+	};
+
+	Check {
+		name :   "database",
+		ok :     error.is_none(),
+		detail : error,
+	}
+}
+
+fn settings() -> Check {
+	let missing = config::missing();
+
+	Check {
+		name :   "config",
+		ok :     missing.is_empty(),
+		detail : match missing.is_empty() {
+			true => None,
+			false => Some(format!("Not set: {}", missing.join(", "))),
+		},
+	}
+}
@@ -0,0 +1,179 @@
+//! An append-only log of everything that happens to an order. Events are
+//! pushed onto the order's `history` array in the same update as the change
+//! they describe, and are never edited or removed.
+
+use chrono::{DateTime, Utc};
+use juniper::GraphQLEnum;
+use mongodb::bson::{Bson, Document};
+
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+	Created,
+	PostageChanged,
+	PaymentStarted,
+	PaymentSucceeded,
+	PaymentReceived,
+	StatusChanged,
+	BatchAssigned,
+	Edited,
+	Cancelled,
+	Refunded,
+	ReminderSent,
+	Expired,
+}
+
+impl EventKind {
+	pub fn code(&self) -> &'static str {
+		match self {
+			EventKind::Created => "CREATED",
+			EventKind::PostageChanged => "POSTAGE_CHANGED",
+			EventKind::PaymentStarted => "PAYMENT_STARTED",
+			EventKind::PaymentSucceeded => "PAYMENT_SUCCEEDED",
+			EventKind::PaymentReceived => "PAYMENT_RECEIVED",
+			EventKind::StatusChanged => "STATUS_CHANGED",
+			EventKind::BatchAssigned => "BATCH_ASSIGNED",
+			EventKind::Edited => "EDITED",
+			EventKind::Cancelled => "CANCELLED",
+			EventKind::Refunded => "REFUNDED",
+			EventKind::ReminderSent => "REMINDER_SENT",
+			EventKind::Expired => "EXPIRED",
+		}
+	}
+
+	pub fn parse(kind : &str) -> Self {
+		match kind {
+			"CREATED" => EventKind::Created,
+			"POSTAGE_CHANGED" => EventKind::PostageChanged,
+			"PAYMENT_STARTED" => EventKind::PaymentStarted,
+			"PAYMENT_SUCCEEDED" => EventKind::PaymentSucceeded,
+			"PAYMENT_RECEIVED" => EventKind::PaymentReceived,
+			"STATUS_CHANGED" => EventKind::StatusChanged,
+			"BATCH_ASSIGNED" => EventKind::BatchAssigned,
+			"CANCELLED" => EventKind::Cancelled,
+			"REFUNDED" => EventKind::Refunded,
+			"REMINDER_SENT" => EventKind::ReminderSent,
+			"EXPIRED" => EventKind::Expired,
+			_ => EventKind::Edited,
+		}
+	}
+}
+
+/// Who made a change
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Actor {
+	/// The person who placed the order
+	Customer,
+	/// A committee member using the admin token
+	Admin,
+	/// Stripe, found when checking the payment
+	Stripe,
+	/// Anything the API does by itself
+	System,
+}
+
+impl Actor {
+	pub fn code(&self) -> &'static str {
+		match self {
+			Actor::Customer => "CUSTOMER",
+			Actor::Admin => "ADMIN",
+			Actor::Stripe => "STRIPE",
+			Actor::System => "SYSTEM",
+		}
+	}
+
+	pub fn parse(actor : &str) -> Self {
+		match actor {
+			"CUSTOMER" => Actor::Customer,
+			"ADMIN" => Actor::Admin,
+			"STRIPE" => Actor::Stripe,
+			_ => Actor::System,
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct OrderEvent {
+	pub kind :     EventKind,
+	pub actor :    Actor,
+	pub at :       DateTime<Utc>,
+	/// The part of the order that changed, if it was a change
+	pub field :    Option<String>,
+	pub previous : Option<String>,
+	pub new :      Option<String>,
+}
+
+impl OrderEvent {
+	pub fn new(kind : EventKind, actor : Actor) -> Self {
+		Self {
+			kind,
+			actor,
+			at : Utc::now(),
+			field : None,
+			previous : None,
+			new : None,
+		}
+	}
+
+	/// Record the previous and new values of a field
+	pub fn change(
+		kind : EventKind,
+		actor : Actor,
+		field : &str,
+		previous : Option<String>,
+		new : Option<String>,
+	) -> Self {
+		Self {
+			field : Some(field.to_string()),
+			previous,
+			new,
+			..Self::new(kind, actor)
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"kind": self.kind.code(),
+			"actor": self.actor.code(),
+			"at": Bson::DateTime(self.at.into()),
+			"field": optional(&self.field),
+			"previous": optional(&self.previous),
+			"new": optional(&self.new),
+		}
+	}
+
+	pub fn from_doc(item : Document) -> Self {
+		let get = |key : &str| item.get_str(key).ok().map(String::from);
+
+		Self {
+			kind :     EventKind::parse(item.get_str("kind").unwrap_or("")),
+			actor :    Actor::parse(item.get_str("actor").unwrap_or("")),
+			at :       match item.get_datetime("at") {
+				Ok(at) => at.to_chrono(),
+				_ => Utc::now(),
+			},
+			field :    get("field"),
+			previous : get("previous"),
+			new :      get("new"),
+		}
+	}
+}
+
+fn optional(value : &Option<String>) -> Bson {
+	match value {
+		Some(v) => Bson::String(v.to_owned()),
+		None => Bson::Null,
+	}
+}
+
+/// An update that sets the fields, bumps `updated_at` and appends the events
+/// to the order's history
+pub fn update(mut set : Document, events : &[OrderEvent]) -> Document {
+	set.insert("updated_at", Bson::DateTime(Utc::now().into()));
+
+	let mut update = doc! { "$set": set };
+	if !events.is_empty() {
+		let events : Vec<Bson> = events.iter().map(|e| Bson::Document(e.to_doc())).collect();
+		update.insert("$push", doc! { "history": { "$each": events } });
+	}
+	update
+}
@@ -0,0 +1,159 @@
+//! Making retried mutations safe. A client sends the same idempotency key
+//! when it retries a request, and the mutation is only carried out the first
+//! time. newOrder stores the key on the order itself; the other mutations
+//! that change a payment claim the key in the `idempotency_keys` collection,
+//! where the order they returned is kept for retries.
+//! Keys are also passed on to Stripe so its side is never repeated either.
+
+use crate::{
+	db::{helpers as DBHelper, FromDoc},
+	graphql::context::Context,
+	models::Order,
+	stripe::{client_secret, get_stripe},
+};
+use chrono::Utc;
+use juniper::{graphql_value, FieldError, FieldResult};
+use mongodb::{
+	bson::{Bson, Document},
+	options::IndexOptions,
+	Collection, IndexModel,
+};
+use std::{future::Future, time::Duration};
+
+/// How long a claimed key is remembered
+const KEY_LIFETIME_SECONDS : u64 = 60 * 60 * 24;
+
+/// Run a mutation on an order once for each key. A retry with a key that has
+/// already been used gets the same order back as the first request did, or
+/// is told to try again if the first request has not finished. If the
+/// mutation fails the key is given back so the retry can try again.
+pub async fn once<F>(
+	context : &Context,
+	mutation : &str,
+	id : &str,
+	key : Option<&str>,
+	run : F,
+) -> FieldResult<Order>
+where
+	F : Future<Output = FieldResult<Order>>,
+{
+	let key = match key {
+		Some(key) => key,
+		None => return run.await,
+	};
+
+	let claimed = format!("{}:{}:{}", mutation, id, key);
+	if !claim(context, &claimed).await {
+		return response(context, &claimed).await.ok_or_else(|| {
+			FieldError::new(
+				"This request is still being carried out, please try again shortly",
+				graphql_value!({
+					"type": "IN_PROGRESS"
+				}),
+			)
+		});
+	}
+
+	let result = run.await;
+	match &result {
+		Ok(order) => finish(context, &claimed, order).await,
+		Err(_) => release(context, &claimed).await,
+	}
+	result
+}
+
+/// Claim a newOrder key, so a retry sent while the first request is still
+/// running can not make a second order. False if the key is already taken.
+pub async fn claim_new_order(context : &Context, key : &str) -> bool {
+	claim(context, &format!("newOrder:{}", key)).await
+}
+
+/// Give a newOrder key back after the order could not be made, so the retry
+/// can try again
+pub async fn release_new_order(context : &Context, key : &str) {
+	release(context, &format!("newOrder:{}", key)).await
+}
+
+async fn claim(context : &Context, claim : &str) -> bool {
+	context
+		.idempotency_keys_handel()
+		.insert_one(
+			doc! {
+				"_id": claim,
+				"created_at": Bson::DateTime(Utc::now().into()),
+			},
+			None,
+		)
+		.await
+		.is_ok()
+}
+
+/// Keep the order a claimed key's mutation returned, for its retries
+async fn finish(context : &Context, claim : &str, order : &Order) {
+	context
+		.idempotency_keys_handel()
+		.update_one(
+			doc! {"_id": claim},
+			doc! {"$set": {"response": order.to_doc()}},
+			None,
+		)
+		.await
+		.ok();
+}
+
+/// The order a claimed key's mutation returned, once it has finished
+async fn response(context : &Context, claim : &str) -> Option<Order> {
+	let claimed = context
+		.idempotency_keys_handel()
+		.find_one(doc! {"_id": claim}, None)
+		.await
+		.ok()??;
+
+	claimed
+		.get_document("response")
+		.ok()
+		.map(|order| Order::from_doc(order.to_owned()))
+}
+
+async fn release(context : &Context, claim : &str) {
+	context
+		.idempotency_keys_handel()
+		.delete_one(doc! {"_id": claim}, None)
+		.await
+		.ok();
+}
+
+/// The order a newOrder call with this key already created, with the client
+/// secret filled in as it was the first time
+pub async fn created_order(context : &Context, key : &str) -> Option<Order> {
+	let mut order : Order =
+		DBHelper::find_one(context.orders_handel(), doc! {"idempotency_key": key}).await?;
+
+	if let Some(stripe) = order.payment.as_mut().and_then(|p| p.stripe.as_mut()) {
+		stripe.client_secret = client_secret(&get_stripe(), &stripe.pi).await;
+	}
+
+	Some(order)
+}
+
+/// Stripe keys are per request, so each call made for a mutation gets its
+/// own key derived from the client's
+pub fn stripe_key(key : Option<&str>, operation : &str) -> Option<String> {
+	key.map(|key| format!("{}:{}", key, operation))
+}
+
+/// Forget claimed keys after a day
+pub async fn create_indexes(keys : &Collection<Document>) {
+	let expiry = IndexModel::builder()
+		.keys(doc! { "created_at": 1 })
+		.options(
+			IndexOptions::builder()
+				.expire_after(Duration::from_secs(KEY_LIFETIME_SECONDS))
+				.build(),
+		)
+		.build();
+
+	keys.create_index(expiry, None)
+		.await
+		.expect("Creating idempotency key index failed");
+}
@@ -0,0 +1,273 @@
+//! Orders from a spreadsheet, for scout groups that send us their orders
+//! rather than using the site, from the `/orders/import` route or `rainbow
+//! import` on the command line. Each CSV row is one order, paid for outside
+//! the site, so no payment intents are made. Rows say whether the money has
+//! already been received or an invoice is still to be sent.
+//!
+//! Every row is checked before anything is created, and nothing is created
+//! if any row has a problem, so a sheet can be fixed and imported again
+//! without doubling up orders. A dry run only checks the rows. Postage is
+//! looked up with AusPost as each order is created, so a problem with it only
+//! shows up in a real import.
+
+use crate::{
+	catalogue::SCARF,
+	creation::{self, NewOrder},
+	graphql::context::Context,
+	models::{Address, CollectionMethod, LineItem, PaymentMethod},
+};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Columns every file needs. The others can be left out, and the address
+/// columns are only used for posted orders.
+pub const REQUIRED : &[&str] = &["name", "email", "quantity", "method"];
+
+/// One row of the file. Everything is read as text so each problem with a
+/// row can be reported.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ImportRow {
+	name :            String,
+	email :           String,
+	quantity :        String,
+	variant :         String,
+	method :          String,
+	pickup_location : String,
+	apartment :       String,
+	street :          String,
+	town :            String,
+	state :           String,
+	post_code :       String,
+	country :         String,
+	/// Yes if the money has been received, otherwise the order waits for an
+	/// invoice to be paid
+	paid :            String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+	pub dry_run : bool,
+	/// Whether every row was valid and, unless it was a dry run, created
+	pub ok :      bool,
+	pub rows :    Vec<RowReport>,
+}
+
+impl ImportReport {
+	pub fn created(&self) -> usize { self.rows.iter().filter(|row| row.order.is_some()).count() }
+
+	pub fn failed(&self) -> usize {
+		self.rows
+			.iter()
+			.filter(|row| !row.errors.is_empty())
+			.count()
+	}
+}
+
+#[derive(Serialize)]
+pub struct RowReport {
+	/// Line of the file, counting the header as line 1
+	pub line :     u64,
+	pub name :     String,
+	pub quantity : Option<i32>,
+	pub paid :     bool,
+	pub errors :   Vec<String>,
+	/// Number of the order made from the row
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub order :    Option<String>,
+}
+
+/// Check every row of a CSV file, and create an order for each unless it is
+/// a dry run. Problems with the file as a whole are errors; problems with a
+/// row are in the report.
+pub async fn import(
+	context : &Context,
+	file : &[u8],
+	dry_run : bool,
+) -> Result<ImportReport, String> {
+	let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(file);
+	let headers : StringRecord = reader
+		.headers()
+		.map_err(|_| "The file is not CSV".to_string())?
+		.iter()
+		.map(|header| header.to_lowercase().replace(' ', "_"))
+		.collect();
+
+	for column in REQUIRED {
+		if !headers.iter().any(|header| header == *column) {
+			return Err(format!("The {} column is missing", column));
+		}
+	}
+
+	let mut rows = vec![];
+	for record in reader.records() {
+		let record = match record {
+			Ok(record) => record,
+			Err(e) => {
+				rows.push((
+					RowReport {
+						line :     e.position().map(|p| p.line()).unwrap_or(0),
+						name :     String::new(),
+						quantity : None,
+						paid :     false,
+						errors :   vec!["The row could not be read".to_string()],
+						order :    None,
+					},
+					None,
+				));
+				continue;
+			},
+		};
+
+		let row : ImportRow = record.deserialize(Some(&headers)).unwrap_or_default();
+		let mut report = RowReport {
+			line :     record.position().map(|p| p.line()).unwrap_or(0),
+			name :     row.name.to_owned(),
+			quantity : row.quantity.parse().ok(),
+			paid :     is_paid(&row.paid).unwrap_or(false),
+			errors :   vec![],
+			order :    None,
+		};
+		let new = match validate(row) {
+			Ok(new) => Some(new),
+			Err(errors) => {
+				report.errors = errors;
+				None
+			},
+		};
+		rows.push((report, new));
+	}
+
+	let valid = rows.iter().all(|(report, _)| report.errors.is_empty());
+	if dry_run || !valid {
+		return Ok(ImportReport {
+			dry_run,
+			ok : valid,
+			rows : rows.into_iter().map(|(report, _)| report).collect(),
+		});
+	}
+
+	let mut report = ImportReport {
+		dry_run,
+		ok : true,
+		rows : vec![],
+	};
+	for (mut row, new) in rows {
+		if let Some(new) = new {
+			match creation::create(context, new).await {
+				Ok(order) => row.order = Some(order.reference()),
+				Err(e) => {
+					row.errors.push(e.message().to_string());
+					report.ok = false;
+				},
+			}
+		}
+		report.rows.push(row);
+	}
+
+	info!(
+		created = report.created(),
+		failed = report.failed(),
+		"orders imported",
+	);
+
+	Ok(report)
+}
+
+/// A row as an order, or everything that is wrong with it
+fn validate(row : ImportRow) -> Result<NewOrder, Vec<String>> {
+	let mut errors = vec![];
+
+	if row.name.is_empty() {
+		errors.push("A name is required".to_string());
+	}
+	if !row.email.contains('@') {
+		errors.push("The email address is not valid".to_string());
+	}
+
+	let quantity = match row.quantity.parse::<i32>() {
+		Ok(quantity) if quantity >= 1 => quantity,
+		_ => {
+			errors.push("Quantity must be a whole number greater than 0".to_string());
+			0
+		},
+	};
+
+	let variant = match row.variant.as_str() {
+		"" => Some(SCARF.default_variant()),
+		code => SCARF.variant(code),
+	};
+	if variant.is_none() {
+		errors.push(format!("The variant {} does not exist", row.variant));
+	}
+
+	let method = match row.method.to_uppercase().as_str() {
+		"PICKUP" => Some(CollectionMethod::Pickup),
+		"POST" => Some(CollectionMethod::Post),
+		_ => {
+			errors.push("Method must be PICKUP or POST".to_string());
+			None
+		},
+	};
+
+	let address = match method {
+		Some(CollectionMethod::Post) => match Address::validate(
+			given(row.apartment),
+			given(row.street),
+			given(row.town),
+			given(row.state),
+			given(row.post_code),
+			given(row.country),
+		) {
+			Ok(address) => Some(address),
+			Err(address_errors) => {
+				errors.extend(address_errors.into_iter().map(|e| e.message));
+				None
+			},
+		},
+		_ => None,
+	};
+
+	let paid = is_paid(&row.paid).unwrap_or_else(|| {
+		errors.push("Paid must be yes or no".to_string());
+		false
+	});
+
+	match (errors.is_empty(), variant, method) {
+		(true, Some(variant), Some(method)) => Ok(NewOrder {
+			name : row.name,
+			email : row.email,
+			items : vec![LineItem {
+				variant : variant.code.to_string(),
+				quantity,
+			}],
+			group : None,
+			allocations : vec![],
+			method,
+			pickup_location : given(row.pickup_location),
+			address,
+			payment : PaymentMethod::Offline,
+			paid,
+			idempotency_key : None,
+		}),
+		_ => Err(errors),
+	}
+}
+
+/// Blank cells are the same as a missing column
+fn given(cell : String) -> Option<String> {
+	match cell.is_empty() {
+		true => None,
+		false => Some(cell),
+	}
+}
+
+/// Orders are awaiting an invoice unless the row says they were paid
+fn is_paid(cell : &str) -> Option<bool> {
+	match cell.to_lowercase().as_str() {
+		"" | "no" | "n" | "false" | "invoice" => Some(false),
+		"yes" | "y" | "true" | "paid" => Some(true),
+		_ => None,
+	}
+}
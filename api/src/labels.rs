@@ -0,0 +1,189 @@
+use crate::{
+	catalogue::{self, SCARF},
+	config::Sender,
+	models::{CollectionMethod, Order, OrderStatus},
+	pdf::{self, Font, Page},
+};
+
+/// Render an address label and a packing slip for each order, in the order
+/// they were given
+pub fn render(orders : &[Order], sender : &Sender) -> Vec<u8> {
+	let mut document = pdf::Document::new();
+
+	for order in orders {
+		document.add_page(label(order, sender));
+		document.add_page(packing_slip(order));
+	}
+
+	document.render()
+}
+
+/// Reasons an order can not have a label printed for it
+pub fn check_printable(order : &Order) -> Result<(), String> {
+	let id = order.reference();
+
+	match (order.method, &order.address, order.status) {
+		(CollectionMethod::Pickup, ..) => Err(format!("Order {} is being picked up", id)),
+		(_, None, _) => Err(format!("Order {} has no address", id)),
+		(_, _, OrderStatus::Unpaid) => Err(format!("Order {} has not been paid", id)),
+		(_, _, OrderStatus::Cancelled) => Err(format!("Order {} was cancelled", id)),
+		(_, _, OrderStatus::Expired) => Err(format!("Order {} expired without being paid", id)),
+		(_, _, OrderStatus::Refunded) => Err(format!("Order {} was refunded", id)),
+		_ => Ok(()),
+	}
+}
+
+fn label(order : &Order, sender : &Sender) -> Page {
+	let mut page = Page::new(pdf::LABEL_WIDTH, pdf::LABEL_HEIGHT);
+	let left = 6.0 * pdf::MM;
+	let right = pdf::LABEL_WIDTH - left;
+
+	page.text(left, 10.0 * pdf::MM, 8.0, Font::Bold, "FROM:");
+	let mut top = 14.0 * pdf::MM;
+	for line in sender.lines() {
+		page.text(left, top, 9.0, Font::Regular, &line);
+		top += 4.0 * pdf::MM;
+	}
+
+	page.line(left, 30.0 * pdf::MM, right, 30.0 * pdf::MM);
+
+	page.text(left, 40.0 * pdf::MM, 10.0, Font::Bold, "TO:");
+	page.text(
+		left,
+		48.0 * pdf::MM,
+		16.0,
+		Font::Bold,
+		&order.user.name.to_uppercase(),
+	);
+	let mut top = 56.0 * pdf::MM;
+	if let Some(address) = &order.address {
+		for line in address.lines() {
+			page.text(left, top, 14.0, Font::Regular, &line.to_uppercase());
+			top += 7.0 * pdf::MM;
+		}
+	}
+
+	page.line(left, 100.0 * pdf::MM, right, 100.0 * pdf::MM);
+
+	if let Some(postage) = &order.postage {
+		page.text(left, 106.0 * pdf::MM, 9.0, Font::Regular, &postage.name);
+	}
+	page.text(
+		left,
+		111.0 * pdf::MM,
+		9.0,
+		Font::Regular,
+		&format!("Order {}", order.reference()),
+	);
+
+	// Overseas parcels need a customs declaration of the contents
+	if let Some(address) = &order.address {
+		if !address.is_domestic() {
+			page.text(
+				left,
+				120.0 * pdf::MM,
+				8.0,
+				Font::Bold,
+				"CUSTOMS DECLARATION - MERCHANDISE",
+			);
+			page.text(
+				left,
+				125.0 * pdf::MM,
+				8.0,
+				Font::Regular,
+				&format!("{} x{}", SCARF.customs_description, order.quantity),
+			);
+			page.text(
+				left,
+				130.0 * pdf::MM,
+				8.0,
+				Font::Regular,
+				&format!(
+					"HS {}  Origin {}  Value {}",
+					SCARF.hs_tariff_code,
+					SCARF.origin_country,
+					catalogue::format_price(SCARF.price as i64 * i64::from(order.quantity))
+				),
+			);
+		}
+	}
+
+	page
+}
+
+fn packing_slip(order : &Order) -> Page {
+	let mut page = Page::new(pdf::A4_WIDTH, pdf::A4_HEIGHT);
+	let left = 20.0 * pdf::MM;
+	let right = pdf::A4_WIDTH - left;
+
+	page.text(left, 25.0 * pdf::MM, 18.0, Font::Bold, "Packing Slip");
+	page.text(
+		left,
+		35.0 * pdf::MM,
+		11.0,
+		Font::Regular,
+		&format!("Order {}", order.reference()),
+	);
+	page.text(
+		left,
+		41.0 * pdf::MM,
+		11.0,
+		Font::Regular,
+		&format!("{} <{}>", order.user.name, order.user.email),
+	);
+	if let Some(postage) = &order.postage {
+		page.text(left, 47.0 * pdf::MM, 11.0, Font::Regular, &postage.name);
+	}
+
+	page.text(left, 60.0 * pdf::MM, 11.0, Font::Bold, "Ship to");
+	let mut top = 66.0 * pdf::MM;
+	if let Some(address) = &order.address {
+		for line in address.lines() {
+			page.text(left, top, 11.0, Font::Regular, &line);
+			top += 6.0 * pdf::MM;
+		}
+	}
+
+	let items = 95.0 * pdf::MM;
+	page.text(left, items, 11.0, Font::Bold, "Item");
+	page.text(left + 100.0 * pdf::MM, items, 11.0, Font::Bold, "Code");
+	page.text(right - 15.0 * pdf::MM, items, 11.0, Font::Bold, "Qty");
+	page.line(left, items + 2.0 * pdf::MM, right, items + 2.0 * pdf::MM);
+
+	let mut top = items + 8.0 * pdf::MM;
+	for item in &order.items {
+		page.text(
+			left,
+			top,
+			11.0,
+			Font::Regular,
+			&SCARF.item_name(&item.variant),
+		);
+		page.text(
+			left + 100.0 * pdf::MM,
+			top,
+			11.0,
+			Font::Regular,
+			&item.variant,
+		);
+		page.text(
+			right - 15.0 * pdf::MM,
+			top,
+			11.0,
+			Font::Regular,
+			&item.quantity.to_string(),
+		);
+		top += 6.0 * pdf::MM;
+	}
+
+	page.line(left, top - 3.0 * pdf::MM, right, top - 3.0 * pdf::MM);
+	page.text(
+		right - 50.0 * pdf::MM,
+		top + 3.0 * pdf::MM,
+		11.0,
+		Font::Bold,
+		&format!("Total items: {}", order.quantity),
+	);
+
+	page
+}
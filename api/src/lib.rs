@@ -1,12 +1,37 @@
-#![feature(decl_macro, proc_macro_hygiene)]
-
 #[macro_use]
-extern crate mongodb;
+extern crate bson;
 
 extern crate juniper;
 
+pub mod address;
+pub mod auth;
+pub mod cancellation;
+pub mod catalogue;
+pub mod checkout;
+pub mod cli;
+pub mod config;
+pub mod creation;
+pub mod dashboard;
 pub mod db;
+pub mod editing;
+pub mod expiry;
+pub mod export;
+pub mod fulfilment;
 pub mod graphql;
+pub mod health;
+pub mod history;
+pub mod idempotency;
+pub mod import;
+pub mod labels;
+pub mod logging;
+pub mod mail;
+pub mod metrics;
 pub mod models;
+pub mod offline;
+pub mod order_number;
+pub mod pdf;
+pub mod roster;
 pub mod routes;
+pub mod search;
 pub mod stripe;
+pub mod upstream;
@@ -0,0 +1,168 @@
+//! Structured logs, written to stdout as one JSON object per line. Every
+//! request gets a correlation ID, taken from its `X-Request-Id` header or
+//! made up, which is sent back in the response and attached to everything
+//! logged while handling it. Customers' names, emails and addresses must go
+//! through `Redacted` before they are logged.
+
+use mongodb::{
+	bson::oid::ObjectId,
+	event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent},
+};
+use rocket::{
+	fairing::{Fairing, Info, Kind},
+	http::Header,
+	request::{self, FromRequest, Request},
+	Data, Response,
+};
+use std::{convert::Infallible, fmt};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER : &str = "X-Request-Id";
+
+/// Start logging. The level is set with `RUST_LOG` and defaults to info.
+pub fn init() {
+	tracing_subscriber::fmt()
+		.json()
+		.with_current_span(true)
+		.with_span_list(true)
+		.with_env_filter(
+			EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+		)
+		.init();
+
+	std::panic::set_hook(Box::new(|panic| {
+		error!(
+			location = panic
+				.location()
+				.map(|l| l.to_string())
+				.unwrap_or_default()
+				.as_str(),
+			"panic: {}",
+			panic
+				.payload()
+				.downcast_ref::<&str>()
+				.copied()
+				.or_else(|| panic.payload().downcast_ref::<String>().map(String::as_str))
+				.unwrap_or("unknown"),
+		);
+	}));
+}
+
+/// The correlation ID of a request
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+	/// The ID the client sent, if it is safe to log, or a new one
+	fn from_header(header : Option<&str>) -> Self {
+		match header {
+			Some(id) if Self::is_safe(id) => RequestId(id.to_string()),
+			_ => RequestId(ObjectId::new().to_hex()),
+		}
+	}
+
+	fn is_safe(id : &str) -> bool {
+		!id.is_empty()
+			&& id.len() <= 64
+			&& id
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+	}
+}
+
+impl fmt::Display for RequestId {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.0) }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+	type Error = Infallible;
+
+	async fn from_request(request : &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+		request::Outcome::Success(request_id(request).clone())
+	}
+}
+
+fn request_id<'r>(request : &'r Request<'_>) -> &'r RequestId {
+	request.local_cache(|| RequestId::from_header(request.headers().get_one(REQUEST_ID_HEADER)))
+}
+
+/// Gives every request a correlation ID, returns it in the response and logs
+/// how the request went
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+	fn info(&self) -> Info {
+		Info {
+			name : "Request IDs",
+			kind : Kind::Request | Kind::Response,
+		}
+	}
+
+	async fn on_request(&self, request : &mut Request<'_>, _ : &mut Data<'_>) {
+		request_id(request);
+	}
+
+	async fn on_response<'r>(&self, request : &'r Request<'_>, response : &mut Response<'r>) {
+		let id = request_id(request);
+		response.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+
+		// Query strings are left out as they can hold customers' details
+		info!(
+			request_id = id.0.as_str(),
+			method = request.method().as_str(),
+			path = request.uri().path().as_str(),
+			status = response.status().code,
+			"request finished",
+		);
+	}
+}
+
+/// Logs each database command, without its contents, as part of whatever
+/// span it was sent from
+pub struct CommandLogger;
+
+impl CommandEventHandler for CommandLogger {
+	fn handle_command_succeeded_event(&self, event : CommandSucceededEvent) {
+		info!(
+			command = event.command_name.as_str(),
+			elapsed_ms = event.duration.as_millis() as u64,
+			"mongo command",
+		);
+	}
+
+	fn handle_command_failed_event(&self, event : CommandFailedEvent) {
+		warn!(
+			command = event.command_name.as_str(),
+			elapsed_ms = event.duration.as_millis() as u64,
+			error = %event.failure,
+			"mongo command failed",
+		);
+	}
+}
+
+/// Personal details hidden for logging. Only the first letter of each word
+/// is kept, and the domain of an email, so a log line can still be matched
+/// up with an order by someone who can see the order.
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		let (local, domain) = match self.0.rfind('@') {
+			Some(at) => (&self.0[..at], Some(&self.0[at..])),
+			None => (self.0, None),
+		};
+
+		let masked : Vec<String> = local
+			.split_whitespace()
+			.map(|word| match word.chars().next() {
+				Some(first) => format!("{}***", first),
+				None => String::new(),
+			})
+			.collect();
+
+		write!(f, "{}{}", masked.join(" "), domain.unwrap_or(""))
+	}
+}
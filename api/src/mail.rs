@@ -0,0 +1,52 @@
+use crate::{config, logging::Redacted};
+use lettre::{
+	transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+	Tokio1Executor,
+};
+use tracing::{info_span, warn, Instrument};
+
+/// Send a plain text email. Returns false if email is not set up or the
+/// message could not be sent.
+pub async fn send(to : &str, subject : &str, body : &str) -> bool {
+	let smtp = match config::smtp() {
+		Some(smtp) => smtp,
+		None => return false,
+	};
+
+	let (recipient, from) = match (to.parse(), smtp.from.parse()) {
+		(Ok(recipient), Ok(from)) => (recipient, from),
+		_ => return false,
+	};
+
+	let email = match Message::builder()
+		.to(recipient)
+		.from(from)
+		.subject(subject)
+		.body(body.to_string())
+	{
+		Ok(email) => email,
+		Err(_) => return false,
+	};
+
+	let client = match AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host) {
+		Ok(client) => client,
+		Err(_) => return false,
+	};
+
+	let sent = client
+		.credentials(Credentials::new(smtp.username, smtp.password))
+		.timeout(Some(config::timeouts().smtp))
+		.build()
+		.send(email)
+		.instrument(info_span!("smtp"))
+		.await;
+
+	match sent {
+		Ok(_) => true,
+		Err(e) => {
+			// The server's message can repeat the address, so only its code is kept
+			warn!(to = %Redacted(to), code = ?e.status(), "email not sent");
+			false
+		},
+	}
+}
@@ -0,0 +1,230 @@
+//! Counters for Prometheus, served at `/metrics`. They cover how long each
+//! GraphQL operation takes, how AusPost and Stripe are responding, and how
+//! the sale is going. Counts start again from zero whenever the server
+//! restarts, which Prometheus allows for.
+
+use crate::{
+	models::CollectionMethod,
+	upstream::{CircuitState, Service},
+};
+use prometheus::{
+	Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+	TextEncoder,
+};
+use std::{
+	future::Future,
+	sync::OnceLock,
+	time::{Duration, Instant},
+};
+use tracing::{info, info_span, Instrument, Span};
+
+static METRICS : OnceLock<Metrics> = OnceLock::new();
+
+struct Metrics {
+	registry :          Registry,
+	operations :        IntCounterVec,
+	operation_seconds : HistogramVec,
+	upstream :          IntCounterVec,
+	upstream_seconds :  HistogramVec,
+	circuits :          IntGaugeVec,
+	orders_created :    IntCounterVec,
+	payments :          IntCounterVec,
+	revenue :           IntCounter,
+	refunded :          IntCounter,
+}
+
+impl Metrics {
+	fn new() -> Self {
+		let registry = Registry::new();
+		let metrics = Self {
+			operations : IntCounterVec::new(
+				Opts::new("graphql_requests_total", "GraphQL operations run"),
+				&["operation"],
+			)
+			.unwrap(),
+			operation_seconds : HistogramVec::new(
+				HistogramOpts::new(
+					"graphql_request_duration_seconds",
+					"Time to run GraphQL operations",
+				),
+				&["operation"],
+			)
+			.unwrap(),
+			upstream : IntCounterVec::new(
+				Opts::new("upstream_requests_total", "Requests to upstream services"),
+				&["service", "outcome"],
+			)
+			.unwrap(),
+			upstream_seconds : HistogramVec::new(
+				HistogramOpts::new(
+					"upstream_request_duration_seconds",
+					"Time for upstream services to answer",
+				),
+				&["service"],
+			)
+			.unwrap(),
+			circuits : IntGaugeVec::new(
+				Opts::new(
+					"upstream_circuit_state",
+					"1 for the state each upstream service's circuit breaker is in",
+				),
+				&["service", "state"],
+			)
+			.unwrap(),
+			orders_created : IntCounterVec::new(
+				Opts::new("orders_created_total", "Orders placed"),
+				&["method"],
+			)
+			.unwrap(),
+			payments : IntCounterVec::new(
+				Opts::new("payments_total", "Payment attempts seen"),
+				&["outcome"],
+			)
+			.unwrap(),
+			revenue : IntCounter::new("revenue_cents_total", "Money received, in cents").unwrap(),
+			refunded : IntCounter::new("refunded_cents_total", "Money refunded, in cents").unwrap(),
+			registry,
+		};
+
+		metrics
+			.registry
+			.register(Box::new(metrics.operations.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.operation_seconds.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.upstream.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.upstream_seconds.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.circuits.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.orders_created.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.payments.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.revenue.clone()))
+			.unwrap();
+		metrics
+			.registry
+			.register(Box::new(metrics.refunded.clone()))
+			.unwrap();
+		metrics
+	}
+}
+
+fn metrics() -> &'static Metrics { METRICS.get_or_init(Metrics::new) }
+
+/// Times a GraphQL operation from when it starts until it is dropped, so
+/// every way out of the resolver is covered, even the request being given
+/// up on. The operation is logged in its own span when it finishes.
+struct OperationTimer {
+	name :    &'static str,
+	started : Instant,
+	span :    Span,
+}
+
+impl Drop for OperationTimer {
+	fn drop(&mut self) {
+		let elapsed = self.started.elapsed();
+		info!(
+			parent: &self.span,
+			elapsed_ms = elapsed.as_millis() as u64,
+			"graphql operation finished",
+		);
+
+		let metrics = metrics();
+		metrics.operations.with_label_values(&[self.name]).inc();
+		metrics
+			.operation_seconds
+			.with_label_values(&[self.name])
+			.observe(elapsed.as_secs_f64());
+	}
+}
+
+/// Run a GraphQL operation's resolver inside its own span and time it. The
+/// queries and upstream requests it makes are logged under that span.
+pub async fn operation<T>(name : &'static str, resolver : impl Future<Output = T>) -> T {
+	let span = info_span!("graphql_operation", operation = name);
+	let _timer = OperationTimer {
+		name,
+		started : Instant::now(),
+		span : span.clone(),
+	};
+
+	resolver.instrument(span).await
+}
+
+/// Record one attempt at a request to an upstream service
+pub fn upstream_request(service : Service, outcome : &str, elapsed : Duration) {
+	let metrics = metrics();
+	metrics
+		.upstream
+		.with_label_values(&[service.name(), outcome])
+		.inc();
+	metrics
+		.upstream_seconds
+		.with_label_values(&[service.name()])
+		.observe(elapsed.as_secs_f64());
+}
+
+pub fn order_created(method : CollectionMethod) {
+	let method = match method {
+		CollectionMethod::Pickup => "pickup",
+		CollectionMethod::Post => "post",
+	};
+	metrics().orders_created.with_label_values(&[method]).inc();
+}
+
+/// An order has been paid for, with the amount received in cents. Only
+/// called by whatever marks the order as paid, so each order counts once.
+pub fn payment_succeeded(amount : i64) {
+	let metrics = metrics();
+	metrics.payments.with_label_values(&["succeeded"]).inc();
+	metrics.revenue.inc_by(amount.max(0) as u64);
+}
+
+/// Stripe declined an attempt to pay, as told by its webhook
+pub fn payment_failed() { metrics().payments.with_label_values(&["failed"]).inc(); }
+
+/// Money has been given back to a customer, in cents
+pub fn refunded(amount : i64) { metrics().refunded.inc_by(amount.max(0) as u64); }
+
+/// Everything recorded so far, in the Prometheus text format
+pub fn render() -> String {
+	let metrics = metrics();
+	for service in Service::ALL.iter() {
+		let circuit = service.circuit();
+		for state in [
+			CircuitState::Closed,
+			CircuitState::Open,
+			CircuitState::HalfOpen,
+		]
+		.iter()
+		{
+			metrics
+				.circuits
+				.with_label_values(&[service.name(), state.name()])
+				.set((circuit == *state) as i64);
+		}
+	}
+
+	let mut buffer = vec![];
+	TextEncoder::new()
+		.encode(&metrics.registry.gather(), &mut buffer)
+		.unwrap();
+	String::from_utf8(buffer).unwrap()
+}
@@ -1,42 +1,153 @@
-use crate::db::FromDoc;
-use juniper::{GraphQLEnum, ID};
-use mongodb::{oid::ObjectId, Document};
+use crate::{
+	address::{self, AddressError, AddressErrorCode, AustralianState, PostCode, DOMESTIC_COUNTRY},
+	catalogue::{self, SCARF},
+	checkout, config,
+	db::FromDoc,
+	history::{self, Actor, EventKind, OrderEvent},
+	metrics,
+	stripe::{get_stripe, payment_outcome, PaymentOutcome},
+	upstream::{self, Service},
+};
+use chrono::{DateTime, Duration, Utc};
+use juniper::{GraphQLEnum, GraphQLInputObject, ID};
+use mongodb::{
+	bson::{oid::ObjectId, Bson, Document},
+	Collection,
+};
 use reqwest::header;
 use serde::Deserialize;
+use std::{collections::BTreeMap, sync::OnceLock};
 
 #[derive(Clone, Debug)]
 pub struct Order {
-	pub id :       ID,
-	pub quantity : i32,
-	pub address :  Option<Address>,
-	pub user :     User,
-	pub method :   CollectionMethod,
-	pub postage :  Option<Postage>,
-	pub payment :  Option<Payment>,
+	pub id :              ID,
+	/// Short number for customers to quote. Orders from before numbers were
+	/// allocated do not have one.
+	pub number :          Option<String>,
+	/// Total number of scarves across all of the items
+	pub quantity :        i32,
+	pub items :           Vec<LineItem>,
+	/// Set when a leader is ordering for a group
+	pub group :           Option<Group>,
+	/// Who in the group each scarf is for. The items are worked out from
+	/// these.
+	pub allocations :     Vec<Allocation>,
+	pub address :         Option<Address>,
+	pub user :            User,
+	pub method :          CollectionMethod,
+	pub pickup_location : Option<String>,
+	pub postage :         Option<Postage>,
+	pub quotes :          Vec<PostageQuote>,
+	pub payment :         Option<Payment>,
+	pub status :          OrderStatus,
+	pub refunds :         Vec<Refund>,
+	pub batch_id :        Option<String>,
+	pub created_at :      DateTime<Utc>,
+	pub updated_at :      DateTime<Utc>,
+	/// Everything that has happened to the order, oldest first
+	pub history :         Vec<OrderEvent>,
 }
 
 impl FromDoc for Order {
 	fn from_doc(item : Document) -> Self {
 		Self {
-			id :       Self::doc_get_id(&item),
-			quantity : Self::doc_get_quantity(&item),
-			user :     Self::doc_get_user(&item),
-			address :  Self::doc_get_address(&item),
-			method :   Self::doc_get_method(&item),
-			postage :  Self::doc_get_postage(&item),
-			payment :  Self::doc_get_payment(&item),
+			id :              Self::doc_get_id(&item),
+			number :          Self::doc_get_number(&item),
+			quantity :        Self::doc_get_quantity(&item),
+			items :           Self::doc_get_items(&item),
+			group :           Self::doc_get_group(&item),
+			allocations :     Self::doc_get_allocations(&item),
+			user :            Self::doc_get_user(&item),
+			address :         Self::doc_get_address(&item),
+			method :          Self::doc_get_method(&item),
+			pickup_location : Self::doc_get_pickup_location(&item),
+			postage :         Self::doc_get_postage(&item),
+			quotes :          Self::doc_get_quotes(&item),
+			payment :         Self::doc_get_payment(&item),
+			status :          Self::doc_get_status(&item),
+			refunds :         Self::doc_get_refunds(&item),
+			batch_id :        Self::doc_get_batch_id(&item),
+			created_at :      Self::doc_get_created_at(&item),
+			updated_at :      Self::doc_get_updated_at(&item),
+			history :         Self::doc_get_history(&item),
 		}
 	}
 }
 
 impl Order {
+	/// The order in the shape it is stored in, so it can be read back with
+	/// `from_doc`
+	pub fn to_doc(&self) -> Document {
+		let docs = |docs : Vec<Document>| docs.into_iter().map(Bson::Document).collect::<Vec<_>>();
+		doc! {
+			"_id": match ObjectId::parse_str(&*self.id) {
+				Ok(oid) => Bson::ObjectId(oid),
+				_ => Bson::Null,
+			},
+			"number": match &self.number {
+				Some(n) => Bson::String(n.to_owned()),
+				None => Bson::Null,
+			},
+			"quantity": self.quantity,
+			"items": docs(self.items.iter().map(LineItem::to_doc).collect()),
+			"group": match &self.group {
+				Some(g) => Bson::Document(g.to_doc()),
+				None => Bson::Null,
+			},
+			"allocations": docs(self.allocations.iter().map(Allocation::to_doc).collect()),
+			"user": self.user.to_doc(),
+			"address": match &self.address {
+				Some(a) => Bson::Document(a.to_doc()),
+				None => Bson::Null,
+			},
+			"method": self.method.code(),
+			"pickup_location": match &self.pickup_location {
+				Some(l) => Bson::String(l.to_owned()),
+				None => Bson::Null,
+			},
+			"postage": match &self.postage {
+				Some(p) => Bson::Document(p.to_doc()),
+				None => Bson::Null,
+			},
+			"quotes": docs(self.quotes.iter().map(PostageQuote::to_doc).collect()),
+			"payment": match &self.payment {
+				Some(p) => Bson::Document(p.to_doc()),
+				None => Bson::Null,
+			},
+			"status": self.status.code(),
+			"refunds": docs(self.refunds.iter().map(Refund::to_doc).collect()),
+			"batch_id": match self.batch_id.as_deref().map(ObjectId::parse_str) {
+				Some(Ok(oid)) => Bson::ObjectId(oid),
+				_ => Bson::Null,
+			},
+			"created_at": Bson::DateTime(self.created_at.into()),
+			"updated_at": Bson::DateTime(self.updated_at.into()),
+			"history": docs(self.history.iter().map(OrderEvent::to_doc).collect()),
+		}
+	}
+
 	pub fn doc_get_id(item : &Document) -> ID {
 		ID::from(match item.get_object_id("_id") {
 			Ok(oid) => oid.to_string(),
-			_ => ObjectId::new().unwrap().to_string(),
+			_ => ObjectId::new().to_string(),
 		})
 	}
 
+	pub fn doc_get_number(item : &Document) -> Option<String> {
+		match item.get_str("number") {
+			Ok(n) => Some(String::from(n)),
+			_ => None,
+		}
+	}
+
+	/// How the order is referred to on anything printed
+	pub fn reference(&self) -> String {
+		match &self.number {
+			Some(number) => number.to_owned(),
+			None => self.id.to_string(),
+		}
+	}
+
 	pub fn doc_get_quantity(item : &Document) -> i32 {
 		match item.get_i32("quantity") {
 			Ok(q) => q as i32,
@@ -44,6 +155,130 @@ impl Order {
 		}
 	}
 
+	/// Orders placed before variants existed are all the default variant
+	pub fn doc_get_items(item : &Document) -> Vec<LineItem> {
+		match item.get_array("items") {
+			Ok(items) => items
+				.iter()
+				.filter_map(|line| match line {
+					Bson::Document(d) => Some(LineItem::from_doc(d.to_owned())),
+					_ => None,
+				})
+				.collect(),
+			_ => vec![LineItem {
+				variant :  SCARF.default_variant().code.to_string(),
+				quantity : Self::doc_get_quantity(item),
+			}],
+		}
+	}
+
+	pub fn doc_get_group(item : &Document) -> Option<Group> {
+		match item.get_document("group") {
+			Ok(d) => Some(Group::from_doc(d.to_owned())),
+			_ => None,
+		}
+	}
+
+	pub fn doc_get_allocations(item : &Document) -> Vec<Allocation> {
+		match item.get_array("allocations") {
+			Ok(allocations) => allocations
+				.iter()
+				.filter_map(|allocation| match allocation {
+					Bson::Document(d) => Some(Allocation::from_doc(d.to_owned())),
+					_ => None,
+				})
+				.collect(),
+			_ => vec![],
+		}
+	}
+
+	pub fn doc_get_pickup_location(item : &Document) -> Option<String> {
+		match item.get_str("pickup_location") {
+			Ok(l) => Some(String::from(l)),
+			_ => None,
+		}
+	}
+
+	pub fn doc_get_batch_id(item : &Document) -> Option<String> {
+		match item.get_object_id("batch_id") {
+			Ok(oid) => Some(oid.to_string()),
+			_ => None,
+		}
+	}
+
+	/// Orders from before timestamps were stored were created when their ID
+	/// was
+	pub fn doc_get_created_at(item : &Document) -> DateTime<Utc> {
+		match item.get_datetime("created_at") {
+			Ok(at) => at.to_chrono(),
+			_ => match item.get_object_id("_id") {
+				Ok(oid) => oid.timestamp().to_chrono(),
+				_ => Utc::now(),
+			},
+		}
+	}
+
+	pub fn doc_get_updated_at(item : &Document) -> DateTime<Utc> {
+		match item.get_datetime("updated_at") {
+			Ok(at) => at.to_chrono(),
+			_ => Self::doc_get_created_at(item),
+		}
+	}
+
+	pub fn doc_get_refunds(item : &Document) -> Vec<Refund> {
+		match item.get_array("refunds") {
+			Ok(refunds) => refunds
+				.iter()
+				.filter_map(|refund| match refund {
+					Bson::Document(d) => Some(Refund::from_doc(d.to_owned())),
+					_ => None,
+				})
+				.collect(),
+			_ => vec![],
+		}
+	}
+
+	/// Total refunded so far, in cents
+	pub fn refunded(&self) -> i64 { self.refunds.iter().map(|refund| refund.amount).sum() }
+
+	pub fn payment_method(&self) -> PaymentMethod {
+		match &self.payment {
+			Some(payment) => payment.method,
+			None => PaymentMethod::Card,
+		}
+	}
+
+	/// What the order costs at the current price, with postage, in cents
+	pub fn total(&self) -> i64 {
+		SCARF.price as i64 * i64::from(self.quantity)
+			+ self
+				.postage
+				.as_ref()
+				.map(|postage| postage.price)
+				.unwrap_or(0)
+	}
+
+	/// Total received outside the site so far, in cents
+	pub fn received(&self) -> i64 {
+		match &self.payment {
+			Some(payment) => payment.received.iter().map(|p| p.amount).sum(),
+			None => 0,
+		}
+	}
+
+	pub fn doc_get_history(item : &Document) -> Vec<OrderEvent> {
+		match item.get_array("history") {
+			Ok(events) => events
+				.iter()
+				.filter_map(|event| match event {
+					Bson::Document(d) => Some(OrderEvent::from_doc(d.to_owned())),
+					_ => None,
+				})
+				.collect(),
+			_ => vec![],
+		}
+	}
+
 	pub fn doc_get_user(item : &Document) -> User {
 		match item.get_document("user") {
 			Ok(d) => User::from_doc(d.to_owned()),
@@ -65,6 +300,26 @@ impl Order {
 		}
 	}
 
+	pub fn doc_get_quotes(item : &Document) -> Vec<PostageQuote> {
+		match item.get_array("quotes") {
+			Ok(quotes) => quotes
+				.iter()
+				.filter_map(|quote| match quote {
+					Bson::Document(d) => Some(PostageQuote::from_doc(d.to_owned())),
+					_ => None,
+				})
+				.collect(),
+			_ => vec![],
+		}
+	}
+
+	/// Find a quote that was issued against this order
+	pub fn quote(&self, quote_id : &str) -> Option<&PostageQuote> {
+		self.quotes
+			.iter()
+			.find(|quote| quote.id.to_string() == quote_id)
+	}
+
 	pub fn doc_get_method(item : &Document) -> CollectionMethod {
 		match item.get_i32("method") {
 			Ok(1) => CollectionMethod::Pickup,
@@ -78,17 +333,241 @@ impl Order {
 			_ => None,
 		}
 	}
+
+	pub fn doc_get_status(item : &Document) -> OrderStatus {
+		match item.get_str("status") {
+			Ok(s) => OrderStatus::parse(s),
+			_ => OrderStatus::Unpaid,
+		}
+	}
+
+	/// Unpaid orders are checked with Stripe, and marked as paid once their
+	/// payment intent or Checkout Session has succeeded
+	pub async fn refresh_status(&mut self, orders : &Collection<Document>) {
+		if self.status != OrderStatus::Unpaid {
+			return;
+		}
+
+		// A paid Checkout Session brings its own payment intent
+		checkout::refresh_session(orders, self).await;
+
+		let pi = match self.payment.as_ref().and_then(|p| p.stripe.as_ref()) {
+			Some(stripe) => stripe.pi.to_owned(),
+			None => return,
+		};
+
+		let received = match payment_outcome(&get_stripe(), &pi).await {
+			PaymentOutcome::Succeeded(received) => received,
+			PaymentOutcome::Pending => return,
+		};
+
+		let result = orders
+			.update_one(
+				doc! {
+					"_id": ObjectId::parse_str(&*self.id).expect("Order ID is not valid"),
+					// Orders from before statuses were stored have none
+					"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+				},
+				history::update(
+					doc! {
+						"status": OrderStatus::Paid.code(),
+						"payment.amount": received,
+					},
+					&[OrderEvent::change(
+						EventKind::PaymentSucceeded,
+						Actor::Stripe,
+						"status",
+						Some(OrderStatus::Unpaid.code().to_string()),
+						Some(OrderStatus::Paid.code().to_string()),
+					)],
+				),
+				None,
+			)
+			.await
+			.expect("Marking order as paid failed");
+
+		// Only the request that marked the order as paid counts the payment
+		if result.modified_count == 1 {
+			metrics::payment_succeeded(received);
+		}
+
+		self.status = OrderStatus::Paid;
+		if let Some(payment) = self.payment.as_mut() {
+			payment.amount = Some(received);
+		}
+	}
+
+	/// Whether Stripe has been paid for an unpaid order, without storing
+	/// anything. Used where nothing is meant to change, like dry runs.
+	pub async fn paid_upstream(&self) -> bool {
+		if checkout::session_paid(self).await {
+			return true;
+		}
+
+		match self.payment.as_ref().and_then(|p| p.stripe.as_ref()) {
+			Some(stripe) => matches!(
+				payment_outcome(&get_stripe(), &stripe.pi).await,
+				PaymentOutcome::Succeeded(_)
+			),
+			None => false,
+		}
+	}
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OrderStatus {
+	/// Waiting for the customer to pay
+	Unpaid,
+	/// Paid and ready to be packed
+	Paid,
+	/// Packed as part of a fulfilment batch
+	Packed,
+	/// Posted or handed over to the customer
+	Shipped,
+	/// Cancelled by the customer before paying
+	Cancelled,
+	/// Never paid for, and cancelled automatically
+	Expired,
+	/// The whole payment has been refunded
+	Refunded,
+}
+
+impl OrderStatus {
+	/// Orders without a status have not been paid
+	pub fn parse(status : &str) -> Self {
+		match status {
+			"PAID" => OrderStatus::Paid,
+			"PACKED" => OrderStatus::Packed,
+			"SHIPPED" => OrderStatus::Shipped,
+			"CANCELLED" => OrderStatus::Cancelled,
+			"EXPIRED" => OrderStatus::Expired,
+			"REFUNDED" => OrderStatus::Refunded,
+			_ => OrderStatus::Unpaid,
+		}
+	}
+
+	pub fn code(&self) -> &'static str {
+		match self {
+			OrderStatus::Unpaid => "UNPAID",
+			OrderStatus::Paid => "PAID",
+			OrderStatus::Packed => "PACKED",
+			OrderStatus::Shipped => "SHIPPED",
+			OrderStatus::Cancelled => "CANCELLED",
+			OrderStatus::Expired => "EXPIRED",
+			OrderStatus::Refunded => "REFUNDED",
+		}
+	}
+
+	/// Whether the order has been paid for and not entirely refunded
+	pub fn is_paid(&self) -> bool {
+		match self {
+			OrderStatus::Paid | OrderStatus::Packed | OrderStatus::Shipped => true,
+			_ => false,
+		}
+	}
+}
+
+/// Money given back to the customer through Stripe
+#[derive(Clone, Debug)]
+pub struct Refund {
+	/// The Stripe refund ID
+	pub id :         String,
+	/// In cents
+	pub amount :     i64,
+	pub reason :     String,
+	pub created_at : DateTime<Utc>,
+}
+
+impl Refund {
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"id": &self.id,
+			"amount": self.amount,
+			"reason": &self.reason,
+			"created_at": Bson::DateTime(self.created_at.into()),
+		}
+	}
+
+	pub fn from_doc(item : Document) -> Self {
+		Self {
+			id :         item.get_str("id").unwrap_or("").to_string(),
+			amount :     item.get_i64("amount").unwrap_or(0),
+			reason :     item.get_str("reason").unwrap_or("").to_string(),
+			created_at : match item.get_datetime("created_at") {
+				Ok(at) => at.to_chrono(),
+				_ => Utc::now(),
+			},
+		}
+	}
 }
 
 #[derive(Clone, Debug)]
 pub struct Payment {
-	pub stripe : Option<PaymentStripe>,
+	pub method :    PaymentMethod,
+	/// What a bank transfer should be labelled with, so it can be matched up
+	/// with the order
+	pub reference : Option<String>,
+	/// Money taken outside the site, as recorded by the committee
+	pub received :  Vec<OfflinePayment>,
+	pub stripe :    Option<PaymentStripe>,
+	/// What was charged in cents, stored when the order is paid. Orders paid
+	/// before this was stored do not have it.
+	pub amount :    Option<i64>,
 }
 
 impl Payment {
+	pub fn to_doc(&self) -> Document {
+		let received : Vec<Bson> = self
+			.received
+			.iter()
+			.map(|p| Bson::Document(p.to_doc()))
+			.collect();
+		doc! {
+			"method": self.method.code(),
+			"reference": match &self.reference {
+				Some(r) => Bson::String(r.to_owned()),
+				None => Bson::Null,
+			},
+			"received": received,
+			"stripe": match &self.stripe {
+				Some(s) => Bson::Document(s.to_doc()),
+				None => Bson::Null,
+			},
+			"amount": match self.amount {
+				Some(a) => Bson::Int64(a),
+				None => Bson::Null,
+			},
+		}
+	}
+
 	pub fn from_doc(item : Document) -> Self {
 		Self {
-			stripe : Self::doc_get_stripe(&item),
+			method :    Self::doc_get_method(&item),
+			reference : item.get_str("reference").ok().map(String::from),
+			received :  Self::doc_get_received(&item),
+			stripe :    Self::doc_get_stripe(&item),
+			amount :    item.get_i64("amount").ok(),
+		}
+	}
+
+	pub fn doc_get_received(item : &Document) -> Vec<OfflinePayment> {
+		match item.get_array("received") {
+			Ok(payments) => payments
+				.iter()
+				.filter_map(|payment| match payment {
+					Bson::Document(d) => Some(OfflinePayment::from_doc(d.to_owned())),
+					_ => None,
+				})
+				.collect(),
+			_ => vec![],
+		}
+	}
+
+	/// Orders from before other methods were taken were all paid by card
+	pub fn doc_get_method(item : &Document) -> PaymentMethod {
+		match item.get_str("method") {
+			Ok(method) => PaymentMethod::parse(method),
+			_ => PaymentMethod::Card,
 		}
 	}
 
@@ -100,17 +579,98 @@ impl Payment {
 	}
 }
 
+/// How an order is paid for. Only card payments go through Stripe; the rest
+/// are recorded by the committee once the money arrives.
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PaymentMethod {
+	/// Online through Stripe
+	Card,
+	/// Into our bank account, quoting the order's payment reference
+	BankTransfer,
+	/// In cash when the order is picked up
+	Cash,
+	/// Arranged by the committee, such as an invoice for a scout group
+	Offline,
+}
+
+impl PaymentMethod {
+	pub fn parse(method : &str) -> Self {
+		match method {
+			"BANK_TRANSFER" => PaymentMethod::BankTransfer,
+			"CASH" => PaymentMethod::Cash,
+			"OFFLINE" => PaymentMethod::Offline,
+			_ => PaymentMethod::Card,
+		}
+	}
+
+	pub fn code(&self) -> &'static str {
+		match self {
+			PaymentMethod::Card => "CARD",
+			PaymentMethod::BankTransfer => "BANK_TRANSFER",
+			PaymentMethod::Cash => "CASH",
+			PaymentMethod::Offline => "OFFLINE",
+		}
+	}
+}
+
+/// Money received outside the site
+#[derive(Clone, Debug)]
+pub struct OfflinePayment {
+	/// In cents
+	pub amount :      i64,
+	/// The bank transfer's description, receipt number or similar
+	pub reference :   String,
+	pub recorded_at : DateTime<Utc>,
+}
+
+impl OfflinePayment {
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"amount": self.amount,
+			"reference": &self.reference,
+			"recorded_at": Bson::DateTime(self.recorded_at.into()),
+		}
+	}
+
+	pub fn from_doc(item : Document) -> Self {
+		Self {
+			amount :      item.get_i64("amount").unwrap_or(0),
+			reference :   item.get_str("reference").unwrap_or("").to_string(),
+			recorded_at : match item.get_datetime("recorded_at") {
+				Ok(at) => at.to_chrono(),
+				_ => Utc::now(),
+			},
+		}
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct PaymentStripe {
-	pub pi :            String,
-	pub client_secret : Option<String>,
+	pub pi :               String,
+	pub client_secret :    Option<String>,
+	/// The latest Checkout Session made for the order, if the customer paid
+	/// through Stripe Checkout. Its own payment intent replaces `pi` once it
+	/// is paid.
+	pub checkout_session : Option<String>,
 }
 
 impl PaymentStripe {
+	/// The client secret is never stored
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"pi": &self.pi,
+			"checkout_session": match &self.checkout_session {
+				Some(c) => Bson::String(c.to_owned()),
+				None => Bson::Null,
+			},
+		}
+	}
+
 	pub fn from_doc(item : Document) -> Self {
 		Self {
-			pi :            Self::doc_get_pi(&item),
-			client_secret : None,
+			pi :               Self::doc_get_pi(&item),
+			client_secret :    None,
+			checkout_session : item.get_str("checkout_session").ok().map(String::from),
 		}
 	}
 
@@ -136,6 +696,13 @@ impl User {
 		}
 	}
 
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"name": &self.name,
+			"email": &self.email,
+		}
+	}
+
 	pub fn from_doc(item : Document) -> Self {
 		Self {
 			name :  Self::doc_get_name(&item),
@@ -163,8 +730,13 @@ pub struct Address {
 	pub apartment : Option<String>,
 	pub street :    String,
 	pub town :      String,
-	pub state :     String,
-	pub post_code : i32,
+	/// Set for Australian addresses
+	pub state :     Option<AustralianState>,
+	/// State, province or county of an overseas address
+	pub region :    Option<String>,
+	pub post_code : PostCode,
+	/// ISO 3166-1 alpha-2 country code
+	pub country :   String,
 }
 
 impl Address {
@@ -173,8 +745,199 @@ impl Address {
 			apartment : None,
 			street :    "".to_string(),
 			town :      "".to_string(),
-			state :     "".to_string(),
-			post_code : 0,
+			state :     None,
+			region :    None,
+			post_code : PostCode::from_legacy(0),
+			country :   String::from(DOMESTIC_COUNTRY),
+		}
+	}
+
+	pub fn is_domestic(&self) -> bool { self.country == DOMESTIC_COUNTRY }
+
+	/// The address as it is written on a parcel
+	pub fn lines(&self) -> Vec<String> {
+		let street = match &self.apartment {
+			Some(apartment) => format!("{}/{}", apartment, self.street),
+			None => self.street.to_owned(),
+		};
+
+		let locality = match (&self.state, &self.region) {
+			(Some(state), _) => format!("{} {} {}", self.town, state, self.post_code),
+			(None, Some(region)) => format!("{} {} {}", self.town, region, self.post_code),
+			(None, None) => format!("{} {}", self.town, self.post_code),
+		};
+
+		let mut lines = vec![street, locality.trim().to_string()];
+		if !self.is_domestic() {
+			lines.push(self.country.to_owned());
+		}
+		lines
+	}
+
+	/// Validate and normalise an address entered by a customer. Every problem
+	/// is returned rather than just the first so they can all be fixed at once.
+	/// Addresses without a country are Australian.
+	pub fn validate(
+		apartment : Option<String>,
+		street : Option<String>,
+		town : Option<String>,
+		state : Option<String>,
+		post_code : Option<String>,
+		country : Option<String>,
+	) -> Result<Self, Vec<AddressError>> {
+		let country = match country {
+			Some(ref c) if !c.trim().is_empty() => match address::parse_country(c) {
+				Ok(c) => c,
+				Err(e) => return Err(vec![e]),
+			},
+			_ => String::from(DOMESTIC_COUNTRY),
+		};
+
+		if country != DOMESTIC_COUNTRY {
+			return Self::validate_international(
+				apartment, street, town, state, post_code, country,
+			);
+		}
+
+		let mut errors = vec![];
+
+		let street = Self::required("street", street, &mut errors);
+		let town = Self::required("town", town, &mut errors).to_uppercase();
+		let state = Self::required("state", state, &mut errors);
+		let post_code = Self::required("postCode", post_code, &mut errors);
+
+		let state = match AustralianState::parse(&state) {
+			Some(s) => Some(s),
+			None if state.is_empty() => None,
+			None => {
+				errors.push(AddressError::new(
+					"state",
+					AddressErrorCode::InvalidState,
+					"State is not an Australian state or territory",
+				));
+				None
+			},
+		};
+
+		let post_code = match PostCode::parse(&post_code) {
+			Ok(p) => Some(p),
+			Err(_) if post_code.is_empty() => None,
+			Err(e) => {
+				errors.push(e);
+				None
+			},
+		};
+
+		if let (Some(state), Some(post_code)) = (state, &post_code) {
+			if !state.contains(post_code) {
+				errors.push(AddressError::new(
+					"postCode",
+					AddressErrorCode::PostCodeNotInState,
+					&format!("Postcode {} is not in {}", post_code, state),
+				));
+			} else if !town.is_empty() && !address::town_matches_post_code(&town, post_code) {
+				errors.push(AddressError::new(
+					"town",
+					AddressErrorCode::TownNotInPostCode,
+					&format!("{} is not in postcode {}", town, post_code),
+				));
+			}
+		}
+
+		match (errors.is_empty(), post_code) {
+			(true, Some(post_code)) => Ok(Self {
+				apartment : apartment
+					.map(|a| a.trim().to_string())
+					.filter(|a| !a.is_empty()),
+				street,
+				town,
+				state,
+				region : None,
+				post_code,
+				country,
+			}),
+			_ => Err(errors),
+		}
+	}
+
+	/// Overseas addresses only need a street and town. The state is kept as a
+	/// free text region.
+	fn validate_international(
+		apartment : Option<String>,
+		street : Option<String>,
+		town : Option<String>,
+		region : Option<String>,
+		post_code : Option<String>,
+		country : String,
+	) -> Result<Self, Vec<AddressError>> {
+		let mut errors = vec![];
+
+		let street = Self::required("street", street, &mut errors);
+		let town = Self::required("town", town, &mut errors).to_uppercase();
+
+		let post_code = match PostCode::international(&post_code.unwrap_or_default()) {
+			Ok(p) => p,
+			Err(e) => {
+				errors.push(e);
+				PostCode::from_legacy(0)
+			},
+		};
+
+		if !errors.is_empty() {
+			return Err(errors);
+		}
+
+		Ok(Self {
+			apartment : apartment
+				.map(|a| a.trim().to_string())
+				.filter(|a| !a.is_empty()),
+			street,
+			town,
+			state : None,
+			region : region
+				.map(|r| r.trim().to_string())
+				.filter(|r| !r.is_empty()),
+			post_code,
+			country,
+		})
+	}
+
+	fn required(
+		field : &'static str,
+		value : Option<String>,
+		errors : &mut Vec<AddressError>,
+	) -> String {
+		match value.map(|v| v.trim().to_string()) {
+			Some(v) if !v.is_empty() => v,
+			_ => {
+				errors.push(AddressError::new(
+					field,
+					AddressErrorCode::Required,
+					"This field is required",
+				));
+				String::from("")
+			},
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"apartment": match &self.apartment {
+				Some(a) => Bson::String(a.to_owned()),
+				None => Bson::Null,
+			},
+			"street": &self.street,
+			"town": &self.town,
+			"state": match self.state {
+				Some(s) => Bson::String(s.code().to_string()),
+				None => Bson::Null,
+			},
+			"region": match &self.region {
+				Some(r) => Bson::String(r.to_owned()),
+				None => Bson::Null,
+			},
+			"post_code": self.post_code.as_str(),
+			"country": &self.country,
 		}
 	}
 
@@ -184,7 +947,9 @@ impl Address {
 			street :    Self::doc_get_street(&item),
 			town :      Self::doc_get_town(&item),
 			state :     Self::doc_get_state(&item),
+			region :    Self::doc_get_region(&item),
 			post_code : Self::doc_get_post_code(&item),
+			country :   Self::doc_get_country(&item),
 		}
 	}
 
@@ -209,36 +974,90 @@ impl Address {
 		}
 	}
 
-	pub fn doc_get_state(item : &Document) -> String {
+	pub fn doc_get_state(item : &Document) -> Option<AustralianState> {
 		match item.get_str("state") {
+			Ok(c) => AustralianState::parse(c),
+			_ => None,
+		}
+	}
+
+	pub fn doc_get_region(item : &Document) -> Option<String> {
+		match item.get_str("region") {
+			Ok(t) => Some(String::from(t)),
+			_ => None,
+		}
+	}
+
+	/// Orders placed before international shipping are all Australian
+	pub fn doc_get_country(item : &Document) -> String {
+		match item.get_str("country") {
 			Ok(c) => String::from(c),
-			_ => String::from(""),
+			_ => String::from(DOMESTIC_COUNTRY),
 		}
 	}
 
-	pub fn doc_get_post_code(item : &Document) -> i32 {
-		match item.get_i32("post_code") {
-			Ok(c) => c,
-			_ => 0,
+	/// Older orders stored the postcode as an integer
+	pub fn doc_get_post_code(item : &Document) -> PostCode {
+		match item.get("post_code") {
+			Some(Bson::String(c)) => PostCode::international(c).unwrap_or(PostCode::from_legacy(0)),
+			Some(Bson::Int32(c)) => PostCode::from_legacy(*c),
+			_ => PostCode::from_legacy(0),
 		}
 	}
 }
 
 #[derive(Clone, Debug)]
 pub struct Postage {
-	pub code : String,
+	pub code :     String,
+	pub name :     String,
+	/// Price in cents that was locked in by the quote
+	pub price :    i64,
+	pub quote_id : Option<String>,
 }
 
 impl Postage {
+	/// The service and price, for the order history
+	pub fn describe(&self) -> String {
+		format!("{} {}", self.code, catalogue::format_price(self.price))
+	}
+
 	pub fn default() -> Self {
 		Self {
-			code : String::from(""),
+			code :     String::from(""),
+			name :     String::from(""),
+			price :    0,
+			quote_id : None,
 		}
 	}
 
 	pub fn from_doc(item : Document) -> Self {
 		Self {
-			code : Self::doc_get_code(&item),
+			code :     Self::doc_get_code(&item),
+			name :     Self::doc_get_name(&item),
+			price :    Self::doc_get_price(&item),
+			quote_id : Self::doc_get_quote_id(&item),
+		}
+	}
+
+	/// The postage that is charged when the given quote is accepted
+	pub fn from_quote(quote : &PostageQuote) -> Self {
+		Self {
+			code :     quote.code.to_owned(),
+			name :     quote.name.to_owned(),
+			price :    quote.price,
+			quote_id : Some(quote.id.to_string()),
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"code": &self.code,
+			"name": &self.name,
+			"price": self.price,
+			"quote_id": match &self.quote_id {
+				Some(q) => Bson::ObjectId(ObjectId::parse_str(q).expect("Quote ID is not valid")),
+				None => Bson::Null,
+			},
 		}
 	}
 
@@ -248,14 +1067,391 @@ impl Postage {
 			_ => String::from(""),
 		}
 	}
+
+	pub fn doc_get_name(item : &Document) -> String {
+		match item.get_str("name") {
+			Ok(c) => String::from(c),
+			_ => String::from(""),
+		}
+	}
+
+	pub fn doc_get_price(item : &Document) -> i64 {
+		match item.get_i64("price") {
+			Ok(p) => p,
+			_ => 0,
+		}
+	}
+
+	pub fn doc_get_quote_id(item : &Document) -> Option<String> {
+		match item.get_object_id("quote_id") {
+			Ok(oid) => Some(oid.to_string()),
+			_ => None,
+		}
+	}
 }
 
-#[derive(GraphQLEnum, Clone, Copy, Debug)]
+/// How long a postage quote can be accepted for after it was issued
+pub const QUOTE_VALIDITY_MINUTES : i64 = 30;
+
+/// A price for a postage option, locked against an order until it expires.
+/// Accepting the quote charges exactly this price regardless of what the
+/// AusPost API returns at that time.
+#[derive(Clone, Debug)]
+pub struct PostageQuote {
+	pub id :         ID,
+	pub code :       String,
+	pub name :       String,
+	/// Price in cents
+	pub price :      i64,
+	pub expires_at : DateTime<Utc>,
+}
+
+impl PostageQuote {
+	/// Issue a quote for each of the delivery options returned by AusPost
+	pub fn issue(options : Vec<PostDeliveryOption>) -> Vec<Self> {
+		let expires_at = Utc::now() + Duration::minutes(QUOTE_VALIDITY_MINUTES);
+
+		options
+			.iter()
+			.map(|opt| Self {
+				id : ID::from(ObjectId::new().to_string()),
+				code : opt.code.to_owned(),
+				name : opt.name.to_owned(),
+				price : (opt.price * f64::from(100)).round() as i64,
+				expires_at,
+			})
+			.collect()
+	}
+
+	pub fn is_expired(&self) -> bool { self.expires_at <= Utc::now() }
+
+	pub fn from_doc(item : Document) -> Self {
+		Self {
+			id :         Self::doc_get_id(&item),
+			code :       Self::doc_get_code(&item),
+			name :       Self::doc_get_name(&item),
+			price :      Self::doc_get_price(&item),
+			expires_at : Self::doc_get_expires_at(&item),
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"_id": ObjectId::parse_str(&*self.id).expect("Quote ID is not valid"),
+			"code": &self.code,
+			"name": &self.name,
+			"price": self.price,
+			"expires_at": Bson::DateTime(self.expires_at.into()),
+		}
+	}
+
+	pub fn doc_get_id(item : &Document) -> ID {
+		ID::from(match item.get_object_id("_id") {
+			Ok(oid) => oid.to_string(),
+			_ => String::from(""),
+		})
+	}
+
+	pub fn doc_get_code(item : &Document) -> String {
+		match item.get_str("code") {
+			Ok(c) => String::from(c),
+			_ => String::from(""),
+		}
+	}
+
+	pub fn doc_get_name(item : &Document) -> String {
+		match item.get_str("name") {
+			Ok(c) => String::from(c),
+			_ => String::from(""),
+		}
+	}
+
+	pub fn doc_get_price(item : &Document) -> i64 {
+		match item.get_i64("price") {
+			Ok(p) => p,
+			_ => 0,
+		}
+	}
+
+	/// Quotes without an expiry are treated as already expired
+	pub fn doc_get_expires_at(item : &Document) -> DateTime<Utc> {
+		match item.get_datetime("expires_at") {
+			Ok(d) => d.to_chrono(),
+			_ => Utc::now(),
+		}
+	}
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
 pub enum CollectionMethod {
 	Pickup,
 	Post,
 }
 
+impl CollectionMethod {
+	/// How the method is stored in the database
+	pub fn code(&self) -> i32 {
+		match self {
+			CollectionMethod::Pickup => 1,
+			CollectionMethod::Post => 0,
+		}
+	}
+}
+
+/// A number of scarves of one variant
+#[derive(Clone, Debug)]
+pub struct LineItem {
+	pub variant :  String,
+	pub quantity : i32,
+}
+
+impl LineItem {
+	pub fn from_doc(item : Document) -> Self {
+		Self {
+			variant :  Self::doc_get_variant(&item),
+			quantity : Self::doc_get_quantity(&item),
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"variant": &self.variant,
+			"quantity": self.quantity,
+		}
+	}
+
+	pub fn doc_get_variant(item : &Document) -> String {
+		match item.get_str("variant") {
+			Ok(v) => String::from(v),
+			_ => SCARF.default_variant().code.to_string(),
+		}
+	}
+
+	pub fn doc_get_quantity(item : &Document) -> i32 {
+		match item.get_i32("quantity") {
+			Ok(q) => q,
+			_ => 0,
+		}
+	}
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum GroupKind {
+	ScoutGroup,
+	Unit,
+	Section,
+}
+
+impl GroupKind {
+	pub fn parse(kind : &str) -> Self {
+		match kind {
+			"SCOUT_GROUP" => GroupKind::ScoutGroup,
+			"SECTION" => GroupKind::Section,
+			_ => GroupKind::Unit,
+		}
+	}
+
+	pub fn code(&self) -> &'static str {
+		match self {
+			GroupKind::ScoutGroup => "SCOUT_GROUP",
+			GroupKind::Unit => "UNIT",
+			GroupKind::Section => "SECTION",
+		}
+	}
+}
+
+/// The scout group, unit or section a leader is ordering for
+#[derive(Clone, Debug)]
+pub struct Group {
+	pub name : String,
+	pub kind : GroupKind,
+}
+
+impl Group {
+	pub fn from_doc(item : Document) -> Self {
+		Self {
+			name : item.get_str("name").unwrap_or("").to_string(),
+			kind : GroupKind::parse(item.get_str("kind").unwrap_or("")),
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"name": &self.name,
+			"kind": self.kind.code(),
+		}
+	}
+}
+
+#[derive(GraphQLInputObject, Clone, Debug)]
+pub struct AllocationInput {
+	/// Who the scarves are for
+	pub member :   String,
+	/// The default variant if not given
+	pub variant :  Option<String>,
+	pub quantity : i32,
+}
+
+/// Scarves in a group order that are for one member
+#[derive(Clone, Debug)]
+pub struct Allocation {
+	pub id :       ID,
+	pub member :   String,
+	pub variant :  String,
+	pub quantity : i32,
+}
+
+impl Allocation {
+	/// Check an allocation a leader has entered
+	pub fn validate(input : AllocationInput) -> Result<Self, String> {
+		let member = input.member.trim().to_string();
+		if member.is_empty() {
+			return Err(String::from("Every allocation needs a member name"));
+		}
+		if input.quantity < 1 {
+			return Err(format!(
+				"The quantity for {} must be greater than 0",
+				member
+			));
+		}
+
+		let variant = match input.variant {
+			Some(code) => match SCARF.variant(&code) {
+				Some(v) => v,
+				None => return Err(format!("The variant for {} does not exist", member)),
+			},
+			None => SCARF.default_variant(),
+		};
+
+		Ok(Self {
+			id : ID::from(ObjectId::new().to_string()),
+			member,
+			variant : variant.code.to_string(),
+			quantity : input.quantity,
+		})
+	}
+
+	/// The line items of an order are the allocations added up by variant
+	pub fn items(allocations : &[Allocation]) -> Vec<LineItem> {
+		let mut totals : BTreeMap<String, i32> = BTreeMap::new();
+		for allocation in allocations {
+			*totals.entry(allocation.variant.to_owned()).or_insert(0) += allocation.quantity;
+		}
+
+		totals
+			.into_iter()
+			.map(|(variant, quantity)| LineItem {
+				variant,
+				quantity,
+			})
+			.collect()
+	}
+
+	pub fn from_doc(item : Document) -> Self {
+		Self {
+			id :       ID::from(item.get_str("id").unwrap_or("").to_string()),
+			member :   item.get_str("member").unwrap_or("").to_string(),
+			variant :  LineItem::doc_get_variant(&item),
+			quantity : LineItem::doc_get_quantity(&item),
+		}
+	}
+
+	pub fn to_doc(&self) -> Document {
+		doc! {
+			"id": self.id.to_string(),
+			"member": &self.member,
+			"variant": &self.variant,
+			"quantity": self.quantity,
+		}
+	}
+}
+
+/// A group of paid orders that are packed and shipped together on a packing
+/// night. Postal batches can be limited to one postage service, and pickup
+/// batches to one pickup location. Orders point at the batch they are in.
+#[derive(Clone, Debug)]
+pub struct FulfilmentBatch {
+	pub id :              ID,
+	pub name :            String,
+	pub method :          CollectionMethod,
+	pub pickup_location : Option<String>,
+	pub postage_code :    Option<String>,
+	pub status :          BatchStatus,
+	pub created_at :      DateTime<Utc>,
+}
+
+impl FromDoc for FulfilmentBatch {
+	fn from_doc(item : Document) -> Self {
+		Self {
+			id :              Order::doc_get_id(&item),
+			name :            Self::doc_get_name(&item),
+			method :          Order::doc_get_method(&item),
+			pickup_location : Order::doc_get_pickup_location(&item),
+			postage_code :    Self::doc_get_postage_code(&item),
+			status :          Self::doc_get_status(&item),
+			created_at :      Self::doc_get_created_at(&item),
+		}
+	}
+}
+
+impl FulfilmentBatch {
+	pub fn doc_get_name(item : &Document) -> String {
+		match item.get_str("name") {
+			Ok(n) => String::from(n),
+			_ => String::from(""),
+		}
+	}
+
+	pub fn doc_get_postage_code(item : &Document) -> Option<String> {
+		match item.get_str("postage_code") {
+			Ok(c) => Some(String::from(c)),
+			_ => None,
+		}
+	}
+
+	pub fn doc_get_status(item : &Document) -> BatchStatus {
+		match item.get_str("status") {
+			Ok("PACKED") => BatchStatus::Packed,
+			Ok("SHIPPED") => BatchStatus::Shipped,
+			_ => BatchStatus::Open,
+		}
+	}
+
+	pub fn doc_get_created_at(item : &Document) -> DateTime<Utc> {
+		match item.get_datetime("created_at") {
+			Ok(d) => d.to_chrono(),
+			_ => Utc::now(),
+		}
+	}
+}
+
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum BatchStatus {
+	/// Orders are being picked and packed
+	Open,
+	Packed,
+	Shipped,
+}
+
+impl BatchStatus {
+	pub fn code(&self) -> &'static str {
+		match self {
+			BatchStatus::Open => "OPEN",
+			BatchStatus::Packed => "PACKED",
+			BatchStatus::Shipped => "SHIPPED",
+		}
+	}
+
+	/// The status the orders in a batch move to along with it
+	pub fn order_status(&self) -> OrderStatus {
+		match self {
+			BatchStatus::Open => OrderStatus::Paid,
+			BatchStatus::Packed => OrderStatus::Packed,
+			BatchStatus::Shipped => OrderStatus::Shipped,
+		}
+	}
+}
+
 #[derive(Deserialize, Debug)]
 struct PostPricesServiceOptions {
 	pub option : Vec<PostPricesService>,
@@ -280,6 +1476,12 @@ struct PostPrices {
 	pub services : PostPricesServices,
 }
 
+/// The postage option that is locked in when a postal order is created or
+/// repriced. The customer can accept a different quote with setPostage.
+pub const DEFAULT_POST_OPTION : &str = "AUS_PARCEL_REGULAR_PACKAGE_SMALL";
+/// As above, for orders posted overseas
+pub const DEFAULT_INTERNATIONAL_POST_OPTION : &str = "INT_PARCEL_STD_OWN_PACKAGING";
+
 pub struct PostDeliveryOptions {
 	pub options : Vec<PostDeliveryOption>,
 }
@@ -290,37 +1492,57 @@ pub struct PostDeliveryOption {
 	pub price : f64,
 }
 
+static AUSPOST_CLIENT : OnceLock<reqwest::Client> = OnceLock::new();
+
 pub enum PostDeliveryOptionError {
 	ApiError,
 }
 
 impl PostDeliveryOption {
-	pub fn get(quantity : u32, postcode : u32) -> Result<Vec<Self>, PostDeliveryOptionError> {
-		let mut headers = header::HeaderMap::new();
-		headers.insert(
-			header::HeaderName::from_static("auth-key"),
-			header::HeaderValue::from_str(&std::env::var("AUSPOST_PAC_API").unwrap()).unwrap(),
-		);
-		let client = reqwest::blocking::Client::builder()
-			.default_headers(headers)
-			.build()
-			.unwrap();
-		let body : PostPrices = match client
-			.get("https://digitalapi.auspost.com.au/postage/parcel/domestic/service.json")
-			.query(&[
-				("from_postcode", "2077"),
-				("to_postcode", &postcode.to_string()),
-				("length", "22"),
-				("width", "16"),
-				("height", "7.7"),
-				("weight", &(quantity as f64 * 0.1).to_string()),
-			])
-			.send()
-		{
-			Ok(response) => response.json().unwrap(),
-			Err(_) => return Err(PostDeliveryOptionError::ApiError),
+	pub fn default_code(address : &Address) -> &'static str {
+		match address.is_domestic() {
+			true => DEFAULT_POST_OPTION,
+			false => DEFAULT_INTERNATIONAL_POST_OPTION,
+		}
+	}
+
+	/// Get the AusPost delivery options for posting the given number of
+	/// scarves to an address, domestic or overseas
+	pub async fn get(
+		quantity : u32,
+		address : &Address,
+	) -> Result<Vec<Self>, PostDeliveryOptionError> {
+		let weight = (f64::from(quantity) * SCARF.weight_kg).to_string();
+		let from_postcode = config::sender().post_code;
+		let url = config::auspost_url();
+
+		let request = || match address.is_domestic() {
+			true => Self::client()
+				.get(format!("{}/postage/parcel/domestic/service.json", url))
+				.query(&[
+					("from_postcode", from_postcode.as_str()),
+					("to_postcode", address.post_code.as_str()),
+					("length", "22"),
+					("width", "16"),
+					("height", "7.7"),
+					("weight", weight.as_str()),
+				]),
+			false => Self::client()
+				.get(format!("{}/postage/parcel/international/service.json", url))
+				.query(&[
+					("country_code", address.country.as_str()),
+					("weight", weight.as_str()),
+				]),
 		};
 
+		// Looking up prices changes nothing so it can always be retried
+		let body : PostPrices = upstream::call(Service::AusPost, true, || {
+			let request = request();
+			async move { request.send().await?.error_for_status()?.json().await }
+		})
+		.await
+		.map_err(|_| PostDeliveryOptionError::ApiError)?;
+
 		Ok(body
 			.services
 			.service
@@ -329,6 +1551,21 @@ impl PostDeliveryOption {
 			.collect())
 	}
 
+	/// The AusPost client. Its connections are shared by every request.
+	fn client() -> &'static reqwest::Client {
+		AUSPOST_CLIENT.get_or_init(|| {
+			let mut headers = header::HeaderMap::new();
+			headers.insert(
+				header::HeaderName::from_static("auth-key"),
+				header::HeaderValue::from_str(&std::env::var("AUSPOST_PAC_API").unwrap()).unwrap(),
+			);
+			reqwest::Client::builder()
+				.default_headers(headers)
+				.build()
+				.unwrap()
+		})
+	}
+
 	fn from_api_service(service : &PostPricesService) -> Self {
 		Self {
 			name :  service.name.to_owned(),
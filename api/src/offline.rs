@@ -0,0 +1,158 @@
+//! Payments taken outside the site: bank transfers, cash at pickup and
+//! anything else the committee has arranged. Each payment is recorded
+//! against the order as it arrives. Once the order's total has been
+//! received it is marked as paid, the same as a card order once Stripe has
+//! the money, and it is packed and handed over like any other paid order.
+
+use crate::{
+	db::helpers as DBHelper,
+	graphql::context::Context,
+	history::{self, EventKind, OrderEvent},
+	metrics,
+	models::{OfflinePayment, Order, OrderStatus, PaymentMethod},
+};
+use chrono::Utc;
+use juniper::{graphql_value, FieldError};
+use mongodb::bson::{oid::ObjectId, Bson};
+
+pub enum OfflineError {
+	InvalidId,
+	NotFound,
+	CardPayment,
+	NotUnpaid,
+	InvalidAmount,
+	MissingReference,
+	Changed,
+	DatabaseError,
+}
+
+impl OfflineError {
+	pub fn to_field_error(&self) -> FieldError {
+		let (message, code) = match self {
+			OfflineError::InvalidId => ("UID is not valid", "INVALID_UID"),
+			OfflineError::NotFound => ("The requested order was not found", "NOT_FOUND"),
+			OfflineError::CardPayment => (
+				"This order is paid for by card through Stripe",
+				"CARD_PAYMENT",
+			),
+			OfflineError::NotUnpaid => (
+				"Payments can only be recorded for unpaid orders",
+				"NOT_UNPAID",
+			),
+			OfflineError::InvalidAmount => ("The amount must be more than 0", "INVALID_AMOUNT"),
+			OfflineError::MissingReference => (
+				"A reference for the payment is required",
+				"MISSING_REFERENCE",
+			),
+			OfflineError::Changed => (
+				"Another payment was recorded at the same time, please check the order",
+				"CONFLICT",
+			),
+			OfflineError::DatabaseError => ("Failed to update the order", "DATABASE_ERROR"),
+		};
+
+		FieldError::new(message, graphql_value!({ "type": code }))
+	}
+}
+
+/// Record money received for an order, in cents. Part payments are kept
+/// until they add up to the order's total.
+pub async fn record_payment(
+	context : &Context,
+	id : &str,
+	amount : i64,
+	reference : String,
+) -> Result<Order, OfflineError> {
+	let oid = ObjectId::parse_str(id).map_err(|_| OfflineError::InvalidId)?;
+	let mut order : Order = DBHelper::get(context.orders_handel(), oid)
+		.await
+		.ok_or(OfflineError::NotFound)?;
+
+	if order.payment_method() == PaymentMethod::Card {
+		return Err(OfflineError::CardPayment);
+	}
+	if order.status != OrderStatus::Unpaid {
+		return Err(OfflineError::NotUnpaid);
+	}
+	if amount <= 0 {
+		return Err(OfflineError::InvalidAmount);
+	}
+	let reference = reference.trim().to_string();
+	if reference.is_empty() {
+		return Err(OfflineError::MissingReference);
+	}
+
+	let entry = OfflinePayment {
+		amount,
+		reference,
+		recorded_at : Utc::now(),
+	};
+	let previous = order.received();
+	let mut events = vec![OrderEvent::change(
+		EventKind::PaymentReceived,
+		context.actor(),
+		"received",
+		Some(previous.to_string()),
+		Some((previous + amount).to_string()),
+	)];
+
+	let mut set = doc! {};
+	let paid = previous + amount >= order.total();
+	if paid {
+		events.push(OrderEvent::change(
+			EventKind::PaymentSucceeded,
+			context.actor(),
+			"status",
+			Some(OrderStatus::Unpaid.code().to_string()),
+			Some(OrderStatus::Paid.code().to_string()),
+		));
+		set.insert("status", OrderStatus::Paid.code());
+		set.insert("payment.amount", previous + amount);
+	}
+
+	let mut update = history::update(set, &events);
+	if let Some(Bson::Document(push)) = update.get_mut("$push") {
+		push.insert("payment.received", Bson::Document(entry.to_doc()));
+	}
+
+	// Whether the order is paid depends on what had been received before, so
+	// nothing else can have been recorded in the meantime
+	let received = order
+		.payment
+		.as_ref()
+		.map(|p| p.received.len())
+		.unwrap_or(0) as i64;
+	let updated = context
+		.orders_handel()
+		.update_one(
+			doc! {
+				"_id": oid,
+				// Orders from before statuses were stored have none
+				"status": { "$in": [OrderStatus::Unpaid.code(), Bson::Null] },
+				"$expr": {
+					"$eq": [{ "$size": { "$ifNull": ["$payment.received", []] } }, received],
+				},
+			},
+			update,
+			None,
+		)
+		.await
+		.map_err(|_| OfflineError::DatabaseError)?;
+
+	if updated.matched_count == 0 {
+		return Err(OfflineError::Changed);
+	}
+
+	if paid {
+		metrics::payment_succeeded(previous + amount);
+		order.status = OrderStatus::Paid;
+	}
+	if let Some(payment) = order.payment.as_mut() {
+		payment.received.push(entry);
+		if paid {
+			payment.amount = Some(previous + amount);
+		}
+	}
+	order.history.extend(events);
+	Ok(order)
+}
@@ -0,0 +1,93 @@
+//! Short order numbers that can be read out over the phone, such as
+//! `RS-2026-00421`. The number is a sequence that restarts each year followed
+//! by a Luhn check digit, so a misheard digit is caught instead of finding
+//! someone else's order.
+
+use mongodb::{
+	bson::Document,
+	options::{FindOneAndUpdateOptions, ReturnDocument},
+	Collection,
+};
+
+const PREFIX : &str = "RS";
+
+/// Take the next number for the year from the counters collection. The
+/// increment is atomic so concurrent orders never share a number.
+pub async fn allocate(counters : &Collection<Document>, year : i32) -> Option<String> {
+	let options = FindOneAndUpdateOptions::builder()
+		.return_document(ReturnDocument::After)
+		.upsert(true)
+		.build();
+
+	let counter : Document = counters
+		.find_one_and_update(
+			doc! {"_id": format!("orders-{}", year)},
+			doc! {"$inc": {"seq": 1}},
+			options,
+		)
+		.await
+		.ok()??;
+
+	match counter.get_i32("seq") {
+		Ok(seq) => Some(format(year, seq as u32)),
+		_ => None,
+	}
+}
+
+pub fn format(year : i32, seq : u32) -> String {
+	let digits = format!("{}{:04}", year, seq);
+	format!("{}-{}-{:04}{}", PREFIX, year, seq, check_digit(&digits))
+}
+
+/// What customers paying by bank transfer write in the transfer's
+/// description. Some banks drop dashes, so they are left out, and `parse`
+/// still reads it as the order number.
+pub fn bank_reference(number : &str) -> String { number.replace('-', "") }
+
+/// Luhn check digit of a string of digits
+fn check_digit(digits : &str) -> u32 {
+	let sum : u32 = digits
+		.chars()
+		.rev()
+		.filter_map(|c| c.to_digit(10))
+		.enumerate()
+		.map(|(i, d)| match i % 2 {
+			0 => match d * 2 {
+				doubled if doubled > 9 => doubled - 9,
+				doubled => doubled,
+			},
+			_ => d,
+		})
+		.sum();
+
+	(10 - sum % 10) % 10
+}
+
+/// Normalise an order number typed in by a person, ignoring case, spaces and
+/// dashes. Returns None if it is not an order number or the check digit is
+/// wrong.
+pub fn parse(number : &str) -> Option<String> {
+	let compact : String = number
+		.chars()
+		.filter(|c| c.is_ascii_alphanumeric())
+		.collect::<String>()
+		.to_uppercase();
+
+	if !compact.starts_with(PREFIX) {
+		return None;
+	}
+
+	let digits = &compact[PREFIX.len()..];
+	if digits.len() < 9 || !digits.chars().all(|c| c.is_ascii_digit()) {
+		return None;
+	}
+
+	let (body, check) = digits.split_at(digits.len() - 1);
+	let year : i32 = body[..4].parse().ok()?;
+	let seq : u32 = body[4..].parse().ok()?;
+
+	match check.parse::<u32>() {
+		Ok(check) if check == check_digit(body) => Some(format(year, seq)),
+		_ => None,
+	}
+}
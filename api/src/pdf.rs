@@ -0,0 +1,156 @@
+//! A minimal PDF writer for printing labels and packing slips. Only the base
+//! Helvetica fonts, text and lines are supported. Output contains no
+//! timestamps or random IDs so the same input always renders the same bytes.
+
+/// Points per millimetre
+pub const MM : f64 = 72.0 / 25.4;
+
+pub const A4_WIDTH : f64 = 210.0 * MM;
+pub const A4_HEIGHT : f64 = 297.0 * MM;
+
+/// Standard 4x6" thermal shipping label
+pub const LABEL_WIDTH : f64 = 100.0 * MM;
+pub const LABEL_HEIGHT : f64 = 150.0 * MM;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Font {
+	Regular,
+	Bold,
+}
+
+impl Font {
+	fn resource(&self) -> &'static str {
+		match self {
+			Font::Regular => "F1",
+			Font::Bold => "F2",
+		}
+	}
+}
+
+pub struct Page {
+	width :   f64,
+	height :  f64,
+	content : String,
+}
+
+impl Page {
+	pub fn new(width : f64, height : f64) -> Self {
+		Self {
+			width,
+			height,
+			content : String::new(),
+		}
+	}
+
+	/// Write a line of text with its baseline `top` points from the top of the
+	/// page
+	pub fn text(&mut self, left : f64, top : f64, size : f64, font : Font, text : &str) {
+		self.content.push_str(&format!(
+			"BT /{} {:.2} Tf {:.2} {:.2} Td ({}) Tj ET\n",
+			font.resource(),
+			size,
+			left,
+			self.height - top,
+			escape(text)
+		));
+	}
+
+	/// Draw a straight line, measured from the top left of the page
+	pub fn line(&mut self, left : f64, top : f64, right : f64, bottom : f64) {
+		self.content.push_str(&format!(
+			"0.5 w {:.2} {:.2} m {:.2} {:.2} l S\n",
+			left,
+			self.height - top,
+			right,
+			self.height - bottom
+		));
+	}
+}
+
+pub struct Document {
+	pages : Vec<Page>,
+}
+
+impl Document {
+	pub fn new() -> Self {
+		Self {
+			pages : vec![]
+		}
+	}
+
+	pub fn add_page(&mut self, page : Page) { self.pages.push(page); }
+
+	pub fn render(&self) -> Vec<u8> {
+		// Objects 1 to 4 are the catalog, page tree and fonts. Each page is
+		// followed by its content stream.
+		let mut objects = vec![
+			String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+			format!(
+				"<< /Type /Pages /Kids [{}] /Count {} >>",
+				(0..self.pages.len())
+					.map(|i| format!("{} 0 R", 5 + i * 2))
+					.collect::<Vec<String>>()
+					.join(" "),
+				self.pages.len()
+			),
+			String::from(
+				"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>",
+			),
+			String::from(
+				"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>",
+			),
+		];
+
+		for (i, page) in self.pages.iter().enumerate() {
+			objects.push(format!(
+				"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+				page.width,
+				page.height,
+				6 + i * 2
+			));
+			objects.push(format!(
+				"<< /Length {} >>\nstream\n{}endstream",
+				page.content.len(),
+				page.content
+			));
+		}
+
+		let mut out = String::from("%PDF-1.4\n");
+		let mut offsets = vec![];
+
+		for (i, object) in objects.iter().enumerate() {
+			offsets.push(out.len());
+			out.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+		}
+
+		let xref = out.len();
+		out.push_str(&format!(
+			"xref\n0 {}\n0000000000 65535 f \n",
+			objects.len() + 1
+		));
+		for offset in offsets {
+			out.push_str(&format!("{:010} 00000 n \n", offset));
+		}
+		out.push_str(&format!(
+			"trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+			objects.len() + 1,
+			xref
+		));
+
+		out.into_bytes()
+	}
+}
+
+/// Escape text for a PDF string. Latin-1 characters are written as octal
+/// escapes so the file stays ASCII; anything else can not be shown by the
+/// base fonts and is replaced.
+fn escape(text : &str) -> String {
+	text.chars()
+		.map(|c| match c {
+			'\\' | '(' | ')' => format!("\\{}", c),
+			' '..='~' => c.to_string(),
+			'\u{a0}'..='\u{ff}' => format!("\\{:03o}", c as u32),
+			_ => String::from("?"),
+		})
+		.collect()
+}
@@ -0,0 +1,138 @@
+use crate::{
+	catalogue::SCARF,
+	models::{Allocation, GroupKind, Order},
+	pdf::{self, Font, Page},
+};
+
+/// Allocations that fit on one page
+const ROWS_PER_PAGE : usize = 40;
+
+/// A printable list of which member of the group gets which scarves, sorted
+/// by member, with the totals of each variant at the end
+pub fn render(order : &Order) -> Vec<u8> {
+	let mut document = pdf::Document::new();
+
+	let mut allocations = order.allocations.clone();
+	allocations.sort_by(|a, b| a.member.to_lowercase().cmp(&b.member.to_lowercase()));
+
+	let chunks : Vec<&[Allocation]> = match allocations.is_empty() {
+		true => vec![&[]],
+		false => allocations.chunks(ROWS_PER_PAGE).collect(),
+	};
+
+	for (index, chunk) in chunks.iter().enumerate() {
+		let mut page = header(order, index + 1, chunks.len());
+		let mut top = 62.0 * pdf::MM;
+
+		for allocation in chunk.iter() {
+			row(
+				&mut page,
+				top,
+				Font::Regular,
+				[
+					&allocation.member,
+					&SCARF.item_name(&allocation.variant),
+					&allocation.quantity.to_string(),
+				],
+			);
+			top += 5.5 * pdf::MM;
+		}
+
+		if index + 1 == chunks.len() {
+			totals(&mut page, order, top);
+		}
+		document.add_page(page);
+	}
+
+	document.render()
+}
+
+fn header(order : &Order, number : usize, pages : usize) -> Page {
+	let mut page = Page::new(pdf::A4_WIDTH, pdf::A4_HEIGHT);
+	let left = 20.0 * pdf::MM;
+
+	let title = match &order.group {
+		Some(group) => format!("{} {}", group.name, kind_name(group.kind)),
+		None => order.user.name.to_owned(),
+	};
+	page.text(
+		left,
+		25.0 * pdf::MM,
+		18.0,
+		Font::Bold,
+		&format!("Scarf Roster - {}", title),
+	);
+	page.text(
+		left,
+		35.0 * pdf::MM,
+		11.0,
+		Font::Regular,
+		&format!("Order {}", order.reference()),
+	);
+	page.text(
+		left,
+		41.0 * pdf::MM,
+		11.0,
+		Font::Regular,
+		&format!("Leader: {} <{}>", order.user.name, order.user.email),
+	);
+	if pages > 1 {
+		page.text(
+			pdf::A4_WIDTH - 45.0 * pdf::MM,
+			25.0 * pdf::MM,
+			9.0,
+			Font::Regular,
+			&format!("Page {} of {}", number, pages),
+		);
+	}
+
+	row(
+		&mut page,
+		55.0 * pdf::MM,
+		Font::Bold,
+		["Member", "Item", "Qty"],
+	);
+	page.line(left, 57.0 * pdf::MM, pdf::A4_WIDTH - left, 57.0 * pdf::MM);
+
+	page
+}
+
+fn row(page : &mut Page, top : f64, font : Font, columns : [&str; 3]) {
+	let left = 20.0 * pdf::MM;
+	page.text(left, top, 11.0, font, columns[0]);
+	page.text(left + 80.0 * pdf::MM, top, 11.0, font, columns[1]);
+	page.text(pdf::A4_WIDTH - 35.0 * pdf::MM, top, 11.0, font, columns[2]);
+}
+
+fn totals(page : &mut Page, order : &Order, top : f64) {
+	let left = 20.0 * pdf::MM;
+	page.line(
+		left,
+		top - 3.0 * pdf::MM,
+		pdf::A4_WIDTH - left,
+		top - 3.0 * pdf::MM,
+	);
+
+	let mut top = top + 3.0 * pdf::MM;
+	for item in &order.items {
+		row(
+			page,
+			top,
+			Font::Bold,
+			[
+				"Total",
+				&SCARF.item_name(&item.variant),
+				&item.quantity.to_string(),
+			],
+		);
+		top += 5.5 * pdf::MM;
+	}
+}
+
+fn kind_name(kind : GroupKind) -> &'static str {
+	match kind {
+		GroupKind::ScoutGroup => "Scout Group",
+		GroupKind::Unit => "Unit",
+		GroupKind::Section => "Section",
+	}
+}
@@ -1,48 +1,346 @@
-use rocket::{get, post, response::content, State};
+use rocket::{
+	data::{Data, ToByteUnit},
+	get,
+	http::{ContentType, Header, Status},
+	post,
+	response::{content, status, stream::ByteStream},
+	serde::json::Json,
+	Responder, State,
+};
 
-use juniper::RootNode;
+use chrono::Utc;
+use futures::{
+	future::join_all,
+	stream::{self, BoxStream},
+	StreamExt,
+};
+use juniper::{EmptySubscription, RootNode};
+use mongodb::bson::oid::ObjectId;
+use tracing::{info_span, Instrument};
 
 use crate::{
-	db::PrimaryDb,
+	auth::Admin,
+	checkout::{self, StripeSignature, WebhookError},
+	config,
+	db::{helpers as DBHelper, PrimaryDb},
+	export::{self, ExportFilter, ExportFormat},
+	fulfilment,
 	graphql::{context::Context, mutation_root::MutationRoot, query_root::QueryRoot},
+	health::{self, Liveness, Readiness, Version},
+	import::{self, ImportReport},
+	labels,
+	logging::RequestId,
+	metrics,
+	models::{FulfilmentBatch, Order},
+	roster,
 };
 
-pub type Schema = RootNode<'static, QueryRoot, MutationRoot>;
+pub type Schema = RootNode<'static, QueryRoot, MutationRoot, EmptySubscription<Context>>;
+
+pub fn schema() -> Schema { Schema::new(QueryRoot, MutationRoot, EmptySubscription::new()) }
 
 #[get("/")]
 
 pub fn index() -> &'static str { "Hello, world!" }
 
+/// Whether the process is running
+#[get("/healthz")]
+
+pub fn healthz() -> Json<Liveness> { Json(health::liveness()) }
+
+/// Whether the server can take requests. Add `?upstream` to also fail while
+/// AusPost or Stripe are down.
+#[get("/readyz?<upstream>")]
+
+pub async fn readyz(db : Option<PrimaryDb>, upstream : bool) -> (Status, Json<Readiness>) {
+	let readiness = health::readiness(db.as_ref(), upstream).await;
+	let status = match readiness.ready {
+		true => Status::Ok,
+		false => Status::ServiceUnavailable,
+	};
+
+	(status, Json(readiness))
+}
+
+/// Counters for Prometheus to scrape. Sales figures are included so only
+/// admins can see them.
+#[get("/metrics")]
+
+pub fn get_metrics(_admin : Admin) -> (ContentType, String) {
+	(ContentType::Plain, metrics::render())
+}
+
+/// Which build of the server is running
+#[get("/version")]
+
+pub fn version() -> Json<Version> { Json(health::version()) }
+
 #[get("/")]
 
-pub fn graphiql() -> content::Html<String> { juniper_rocket::graphiql_source("/graphql") }
+pub fn graphiql() -> content::RawHtml<String> { juniper_rocket::graphiql_source("/graphql", None) }
 
-#[get("/graphql?<request>")]
+#[get("/graphql?<request..>")]
 
-pub fn get_graphql_handler(
+pub async fn get_graphql_handler(
 	context : PrimaryDb,
+	admin : Option<Admin>,
+	request_id : RequestId,
 	request : juniper_rocket::GraphQLRequest,
-	schema : State<Schema>,
+	schema : &State<Schema>,
 ) -> juniper_rocket::GraphQLResponse {
-	request.execute(
-		&schema,
-		&Context {
-			connection : context,
-		},
-	)
+	request
+		.execute(
+			schema,
+			&Context {
+				connection : context,
+				admin :      admin.is_some(),
+			},
+		)
+		.instrument(info_span!("graphql", request_id = %request_id))
+		.await
 }
 
 #[post("/graphql", data = "<request>")]
 
-pub fn post_graphql_handler(
+pub async fn post_graphql_handler(
 	context : PrimaryDb,
+	admin : Option<Admin>,
+	request_id : RequestId,
 	request : juniper_rocket::GraphQLRequest,
-	schema : State<Schema>,
+	schema : &State<Schema>,
 ) -> juniper_rocket::GraphQLResponse {
-	request.execute(
-		&schema,
-		&Context {
-			connection : context,
-		},
+	request
+		.execute(
+			schema,
+			&Context {
+				connection : context,
+				admin :      admin.is_some(),
+			},
+		)
+		.instrument(info_span!("graphql", request_id = %request_id))
+		.await
+}
+
+/// Address labels and packing slips for a batch of paid postal orders, given
+/// as a comma separated list of order IDs
+#[get("/labels?<orders>")]
+
+pub async fn get_labels(
+	context : PrimaryDb,
+	_admin : Admin,
+	orders : String,
+) -> Result<(ContentType, Vec<u8>), status::BadRequest<String>> {
+	let context = Context {
+		connection : context,
+		admin :      true,
+	};
+
+	// Every order is loaded and checked against Stripe at the same time
+	let loaded = join_all(
+		orders
+			.split(',')
+			.map(|id| id.trim())
+			.filter(|id| !id.is_empty())
+			.map(|id| load_printable(&context, id)),
 	)
+	.await;
+
+	let mut printable = vec![];
+	let mut errors = vec![];
+	for order in loaded {
+		match order {
+			Ok(order) => printable.push(order),
+			Err(e) => errors.push(e),
+		}
+	}
+
+	if !errors.is_empty() {
+		return Err(status::BadRequest(errors.join("\n")));
+	}
+
+	Ok((
+		ContentType::PDF,
+		labels::render(&printable, &config::sender()),
+	))
+}
+
+async fn load_printable(context : &Context, id : &str) -> Result<Order, String> {
+	let oid = ObjectId::parse_str(id).map_err(|_| format!("{} is not a valid order ID", id))?;
+
+	let mut order : Order = DBHelper::get(context.orders_handel(), oid)
+		.await
+		.ok_or_else(|| format!("Order {} was not found", id))?;
+
+	order.refresh_status(&context.orders_handel()).await;
+	labels::check_printable(&order)?;
+
+	Ok(order)
+}
+
+/// Who gets which scarves in a group order, for the leader to print. The
+/// roster has members' names on it, so unless the request is from an admin
+/// the email the order was placed with has to be given as `?email`.
+#[get("/orders/<id>/roster?<email>")]
+pub async fn get_roster(
+	context : PrimaryDb,
+	admin : Option<Admin>,
+	id : String,
+	email : Option<String>,
+) -> Option<(ContentType, Vec<u8>)> {
+	let context = Context {
+		connection : context,
+		admin :      admin.is_some(),
+	};
+
+	let oid = ObjectId::parse_str(&id).ok()?;
+	let order : Order = DBHelper::get(context.orders_handel(), oid).await?;
+	order.group.as_ref()?;
+
+	// A wrong email looks the same as a missing order
+	let owner = email.map_or(false, |email| {
+		order.user.email.eq_ignore_ascii_case(email.trim())
+	});
+	if !context.admin && !owner {
+		return None;
+	}
+
+	Some((ContentType::PDF, roster::render(&order)))
+}
+
+/// CSV manifest of the consignments in a fulfilment batch, for lodging at the
+/// post office
+#[get("/batches/<id>/manifest")]
+
+pub async fn get_batch_manifest(
+	context : PrimaryDb,
+	_admin : Admin,
+	id : String,
+) -> Option<(ContentType, Vec<u8>)> {
+	let context = Context {
+		connection : context,
+		admin :      true,
+	};
+
+	let oid = ObjectId::parse_str(&id).ok()?;
+	let batch : FulfilmentBatch = DBHelper::get(context.batches_handel(), oid).await?;
+
+	Some((
+		ContentType::CSV,
+		fulfilment::manifest(&fulfilment::orders(&context, &batch).await),
+	))
+}
+
+/// A file the browser saves rather than shows
+#[derive(Responder)]
+pub struct Download<T> {
+	body :         T,
+	content_type : ContentType,
+	disposition :  Header<'static>,
+}
+
+/// Orders matching the same filters as the orders query, as a CSV file or an
+/// XLSX workbook. CSV is the default.
+#[get("/orders/export?<format>&<filter..>")]
+
+pub async fn get_orders_export(
+	context : PrimaryDb,
+	_admin : Admin,
+	format : Option<ExportFormat>,
+	filter : ExportFilter,
+) -> Result<Download<ByteStream<BoxStream<'static, Vec<u8>>>>, status::Custom<String>> {
+	let format = format.unwrap_or(ExportFormat::Csv);
+	let filter = filter
+		.to_order_filter()
+		.map_err(|e| status::Custom(Status::BadRequest, e))?;
+
+	let internal = |e| status::Custom(Status::InternalServerError, e);
+	let cursor = export::orders(&context.collection("orders"), &filter)
+		.await
+		.map_err(internal)?;
+
+	let (body, content_type) = match format {
+		ExportFormat::Csv => (export::csv(cursor).boxed(), ContentType::CSV),
+		ExportFormat::Xlsx => {
+			let workbook = export::xlsx(cursor).await.map_err(internal)?;
+			(
+				stream::once(async { workbook }).boxed(),
+				ContentType::new(
+					"application",
+					"vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+				),
+			)
+		},
+	};
+
+	Ok(Download {
+		body : ByteStream(body),
+		content_type,
+		disposition : Header::new(
+			"Content-Disposition",
+			format!(
+				"attachment; filename=\"orders-{}.{}\"",
+				Utc::now().format("%Y-%m-%d"),
+				format.extension()
+			),
+		),
+	})
+}
+
+/// Orders from a CSV file, for groups that send in a spreadsheet. Add
+/// `?dry_run` to only check the rows. Nothing is created unless every row is
+/// valid.
+#[post("/orders/import?<dry_run>", data = "<file>")]
+
+pub async fn post_orders_import(
+	context : PrimaryDb,
+	_admin : Admin,
+	dry_run : bool,
+	file : Data<'_>,
+) -> Result<(Status, Json<ImportReport>), status::Custom<String>> {
+	let file = file
+		.open(2.mebibytes())
+		.into_bytes()
+		.await
+		.map_err(|e| status::Custom(Status::BadRequest, e.to_string()))?;
+	if !file.is_complete() {
+		return Err(status::Custom(
+			Status::PayloadTooLarge,
+			"The file must be smaller than 2 MiB".to_string(),
+		));
+	}
+
+	let context = Context {
+		connection : context,
+		admin :      true,
+	};
+	let report = import::import(&context, &file.value, dry_run)
+		.await
+		.map_err(|e| status::Custom(Status::BadRequest, e))?;
+	let status = match report.ok {
+		true => Status::Ok,
+		false => Status::UnprocessableEntity,
+	};
+
+	Ok((status, Json(report)))
+}
+
+/// Events from Stripe. Only payments, declines and paid Checkout Sessions
+/// are acted on; Stripe sends the event again later if this fails.
+#[post("/stripe/webhook", data = "<payload>")]
+
+pub async fn post_stripe_webhook(
+	context : PrimaryDb,
+	signature : StripeSignature,
+	payload : Data<'_>,
+) -> Status {
+	let payload = match payload.open(256.kibibytes()).into_string().await {
+		Ok(payload) if payload.is_complete() => payload.value,
+		_ => return Status::BadRequest,
+	};
+
+	match checkout::handle_event(&context.collection("orders"), &payload, &signature).await {
+		Ok(()) => Status::Ok,
+		Err(WebhookError::BadSignature) => Status::BadRequest,
+		Err(WebhookError::Unconfigured) => Status::ServiceUnavailable,
+	}
 }
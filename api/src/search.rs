@@ -0,0 +1,231 @@
+//! Finding an order from whatever the customer can remember about it. Each
+//! word of the query has to appear somewhere in the order. Mongo narrows down
+//! the orders with case-insensitive substring matches, so fragments of emails
+//! and names work, as do the last few digits of an order number. The matches
+//! are then ranked and highlighted here.
+
+use crate::{
+	db::{self, orders::escape_regex},
+	models::Order,
+	order_number,
+};
+use mongodb::{
+	bson::{oid::ObjectId, Bson, Document},
+	options::FindOptions,
+	Collection,
+};
+
+pub const DEFAULT_RESULTS : i32 = 20;
+/// Most orders that are ranked for a single search
+const MAX_CANDIDATES : i64 = 500;
+/// Words after this are ignored
+const MAX_TERMS : usize = 6;
+
+/// Document keys that are searched, with how much a match in each counts
+/// towards the rank
+const FIELDS : &[(&str, &str, u32)] = &[
+	("number", "number", 4),
+	("name", "user.name", 3),
+	("email", "user.email", 3),
+	("street", "address.street", 1),
+	("town", "address.town", 2),
+	("postCode", "address.post_code", 2),
+];
+
+/// The characters of a field that matched the query, as character offsets
+#[derive(Clone, Debug)]
+pub struct MatchRange {
+	pub start : i32,
+	pub end :   i32,
+}
+
+/// A field of the order that matched, and where
+#[derive(Clone, Debug)]
+pub struct Highlight {
+	pub field :   String,
+	pub value :   String,
+	pub matches : Vec<MatchRange>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+	pub order :      Order,
+	pub score :      f64,
+	pub highlights : Vec<Highlight>,
+}
+
+pub enum SearchError {
+	EmptyQuery,
+	DatabaseError,
+}
+
+fn terms(query : &str) -> Vec<String> {
+	query
+		.split_whitespace()
+		.map(|term| term.to_lowercase())
+		.take(MAX_TERMS)
+		.collect()
+}
+
+/// Every term has to match at least one field
+fn filter(terms : &[String]) -> Document {
+	let clauses : Vec<Bson> = terms
+		.iter()
+		.map(|term| {
+			let pattern = escape_regex(term);
+
+			let mut fields : Vec<Bson> = FIELDS
+				.iter()
+				.map(|(_, key, _)| {
+					let mut field = Document::new();
+					field.insert(
+						*key,
+						doc! {
+							"$regex": pattern.to_owned(),
+							"$options": "i",
+						},
+					);
+					Bson::Document(field)
+				})
+				.collect();
+
+			if let Ok(id) = ObjectId::parse_str(term) {
+				fields.push(Bson::Document(doc! { "_id": id }));
+			}
+			if let Some(number) = order_number::parse(term) {
+				fields.push(Bson::Document(doc! { "number": number }));
+			}
+
+			Bson::Document(doc! { "$or": fields })
+		})
+		.collect();
+
+	doc! { "$and": clauses }
+}
+
+/// The values that are searched, in the order of FIELDS, followed by the ID
+fn values(order : &Order) -> Vec<(&'static str, String, u32)> {
+	let address = order.address.as_ref();
+	let mut values = vec![
+		(
+			FIELDS[0].0,
+			order.number.clone().unwrap_or_default(),
+			FIELDS[0].2,
+		),
+		(FIELDS[1].0, order.user.name.to_owned(), FIELDS[1].2),
+		(FIELDS[2].0, order.user.email.to_owned(), FIELDS[2].2),
+		(
+			FIELDS[3].0,
+			address.map(|a| a.street.to_owned()).unwrap_or_default(),
+			FIELDS[3].2,
+		),
+		(
+			FIELDS[4].0,
+			address.map(|a| a.town.to_owned()).unwrap_or_default(),
+			FIELDS[4].2,
+		),
+		(
+			FIELDS[5].0,
+			address.map(|a| a.post_code.to_string()).unwrap_or_default(),
+			FIELDS[5].2,
+		),
+	];
+	values.push(("id", order.id.to_string(), 4));
+	values
+}
+
+/// Character ranges where the term appears in the value, ignoring case
+fn find_matches(value : &str, term : &str) -> Vec<MatchRange> {
+	let value = value.to_lowercase();
+	let mut matches = vec![];
+
+	for (byte_start, _) in value.match_indices(term) {
+		let start = value[..byte_start].chars().count() as i32;
+		matches.push(MatchRange {
+			start,
+			end : start + term.chars().count() as i32,
+		});
+	}
+
+	matches
+}
+
+/// Rank an order against the terms. Whole field matches count the most,
+/// then matches at the start of the field, then anywhere else.
+fn rank(order : Order, terms : &[String]) -> SearchResult {
+	let mut score = 0.0;
+	let mut highlights : Vec<Highlight> = vec![];
+
+	for (field, value, weight) in values(&order) {
+		let mut matches = vec![];
+
+		for term in terms {
+			let found = find_matches(&value, term);
+			if found.is_empty() {
+				continue;
+			}
+
+			let closeness = if value.to_lowercase() == *term {
+				3.0
+			} else if found[0].start == 0 {
+				2.0
+			} else {
+				1.0
+			};
+			score += f64::from(weight) * closeness;
+			matches.extend(found);
+		}
+
+		if !matches.is_empty() {
+			matches.sort_by_key(|range| range.start);
+			highlights.push(Highlight {
+				field : field.to_string(),
+				value,
+				matches,
+			});
+		}
+	}
+
+	SearchResult {
+		order,
+		score,
+		highlights,
+	}
+}
+
+/// Search orders, best matches first
+pub async fn search(
+	coll : Collection<Document>,
+	query : &str,
+	limit : Option<i32>,
+) -> Result<Vec<SearchResult>, SearchError> {
+	let terms = terms(query);
+	if terms.is_empty() {
+		return Err(SearchError::EmptyQuery);
+	}
+
+	let options = FindOptions::builder()
+		.limit(MAX_CANDIDATES)
+		.sort(doc! { "_id": -1 })
+		.build();
+
+	let cursor = coll
+		.find(filter(&terms), options)
+		.await
+		.map_err(|_| SearchError::DatabaseError)?;
+	let mut results : Vec<SearchResult> = db::collect::<Order>(cursor)
+		.await
+		.into_iter()
+		.map(|order| rank(order, &terms))
+		.collect();
+
+	// Newer orders win ties, which the sort from Mongo already has them in
+	results.sort_by(|a, b| {
+		b.score
+			.partial_cmp(&a.score)
+			.unwrap_or(std::cmp::Ordering::Equal)
+	});
+	results.truncate(limit.unwrap_or(DEFAULT_RESULTS).max(0) as usize);
+
+	Ok(results)
+}
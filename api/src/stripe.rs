@@ -1,3 +1,192 @@
-use stripe::Client;
+use crate::{
+	config,
+	models::CollectionMethod,
+	upstream::{self, Service},
+};
+use std::sync::OnceLock;
+use stripe::{
+	CancelPaymentIntent, CheckoutSession, CheckoutSessionId, CheckoutSessionStatus, Client,
+	CreateRefund, Metadata, PaymentIntent, PaymentIntentCancellationReason, PaymentIntentId,
+	PaymentIntentStatus, Refund, RequestStrategy, Response, UpdatePaymentIntent,
+};
 
-pub fn get_stripe() -> Client { Client::new("sk_test_sBfn43srr2ACZQH9O1emy0jY00u8wgBVLM") }
+static CLIENT : OnceLock<Client> = OnceLock::new();
+
+/// The Stripe client. Its connections are shared by every request.
+pub fn get_stripe() -> Client {
+	CLIENT
+		.get_or_init(|| {
+			Client::from_url(
+				config::stripe_url().as_str(),
+				"sk_test_sBfn43srr2ACZQH9O1emy0jY00u8wgBVLM",
+			)
+		})
+		.clone()
+}
+
+/// A client that sends an idempotency key with its requests, so Stripe
+/// returns the original result if the same request is made again
+pub fn get_idempotent_stripe(key : Option<String>) -> Client {
+	match key {
+		Some(key) => get_stripe().with_strategy(RequestStrategy::Idempotent(key)),
+		None => get_stripe(),
+	}
+}
+
+/// Send a request to Stripe. Idempotent requests, either reads or writes
+/// made with an idempotency key, are retried if Stripe can not be reached.
+pub async fn call<T, F>(idempotent : bool, request : F) -> Option<T>
+where
+	F : FnMut() -> Response<T>,
+{
+	upstream::call(Service::Stripe, idempotent, request)
+		.await
+		.ok()
+}
+
+/// Look up a payment intent. None if it does not exist or Stripe can not be
+/// reached.
+pub async fn payment_intent(client : &Client, pi : &str) -> Option<PaymentIntent> {
+	let id = pi.parse::<PaymentIntentId>().ok()?;
+	call(true, || PaymentIntent::retrieve(client, &id, &[])).await
+}
+
+/// The secret the browser needs to pay the payment intent
+pub async fn client_secret(client : &Client, pi : &str) -> Option<String> {
+	payment_intent(client, pi).await?.client_secret
+}
+
+/// Where the customer is up to with paying a payment intent
+pub enum PaymentOutcome {
+	/// Paid, with the amount received in cents
+	Succeeded(i64),
+	/// Not paid yet, or Stripe can not be reached
+	Pending,
+}
+
+pub async fn payment_outcome(client : &Client, pi : &str) -> PaymentOutcome {
+	let intent = match payment_intent(client, pi).await {
+		Some(intent) => intent,
+		None => return PaymentOutcome::Pending,
+	};
+
+	match intent.status {
+		PaymentIntentStatus::Succeeded => PaymentOutcome::Succeeded(intent.amount_received),
+		_ => PaymentOutcome::Pending,
+	}
+}
+
+/// What the customer sees on their statement and receipt
+pub fn payment_description(
+	number : &str,
+	name : &str,
+	quantity : i32,
+	method : CollectionMethod,
+) -> String {
+	format!(
+		"{} {}: Scarves x{} for {}",
+		number,
+		name,
+		quantity,
+		match method {
+			CollectionMethod::Pickup => "Pickup",
+			CollectionMethod::Post => "Postage",
+		}
+	)
+}
+
+/// Change the amount of a payment intent that has not been paid yet, and
+/// optionally its description
+pub async fn set_amount(
+	client : &Client,
+	pi : &str,
+	amount : u64,
+	description : Option<&str>,
+) -> bool {
+	let id = match pi.parse::<PaymentIntentId>() {
+		Ok(id) => id,
+		Err(_) => return false,
+	};
+
+	// Setting the amount is the same however many times it is done
+	call(true, || {
+		let mut params = UpdatePaymentIntent::new();
+		params.amount = Some(amount as i64);
+		params.description = description;
+		PaymentIntent::update(client, &id, params)
+	})
+	.await
+	.is_some()
+}
+
+/// Cancel a payment intent so it can no longer be paid. Intents that are
+/// already cancelled are fine.
+pub async fn cancel_payment(client : &Client, pi : &str) -> bool {
+	match payment_intent(client, pi).await {
+		Some(intent) if intent.status == PaymentIntentStatus::Canceled => true,
+		Some(_) => call(false, || {
+			PaymentIntent::cancel(
+				client,
+				pi,
+				CancelPaymentIntent {
+					cancellation_reason : Some(
+						PaymentIntentCancellationReason::RequestedByCustomer,
+					),
+				},
+			)
+		})
+		.await
+		.is_some(),
+		_ => false,
+	}
+}
+
+/// Look up a Checkout Session. None if it does not exist or Stripe can not
+/// be reached.
+pub async fn checkout_session(client : &Client, id : &str) -> Option<CheckoutSession> {
+	let id = id.parse::<CheckoutSessionId>().ok()?;
+	call(true, || CheckoutSession::retrieve(client, &id, &[])).await
+}
+
+/// Expire a Checkout Session so it can no longer be paid. Sessions that are
+/// already complete or expired are fine.
+pub async fn expire_checkout(client : &Client, id : &str) -> bool {
+	match checkout_session(client, id).await {
+		Some(session) if session.status != Some(CheckoutSessionStatus::Open) => true,
+		Some(session) => call(false, || CheckoutSession::expire(client, &session.id))
+			.await
+			.is_some(),
+		None => false,
+	}
+}
+
+/// How much the customer has paid, in cents
+pub async fn amount_received(client : &Client, pi : &str) -> Option<i64> {
+	payment_intent(client, pi)
+		.await
+		.map(|intent| intent.amount_received)
+}
+
+/// Refund some or all of a payment. Returns the refund ID and the amount
+/// that was refunded.
+pub async fn refund(
+	client : &Client,
+	pi : &str,
+	amount : Option<i64>,
+	reason : &str,
+) -> Option<(String, i64)> {
+	let id = pi.parse::<PaymentIntentId>().ok()?;
+
+	call(false, || {
+		let mut metadata = Metadata::new();
+		metadata.insert("reason".to_string(), reason.to_string());
+
+		let mut params = CreateRefund::new();
+		params.payment_intent = Some(id.clone());
+		params.amount = amount;
+		params.metadata = Some(metadata);
+		Refund::create(client, params)
+	})
+	.await
+	.map(|refund| (refund.id.to_string(), refund.amount))
+}
@@ -0,0 +1,298 @@
+//! Requests to the services the API depends on. Every request is given the
+//! service's timeout, idempotent requests are retried with exponential
+//! backoff, and each service has a circuit breaker. Once too many requests
+//! in a row have failed the service is treated as down and requests fail
+//! straight away, until the cooldown has passed and one request is let
+//! through to see whether it is back.
+
+use crate::{config, metrics};
+use rocket::tokio::time::{sleep, timeout};
+use std::{
+	future::Future,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// The longest wait between retries, however many there have been
+const MAX_BACKOFF : Duration = Duration::from_secs(5);
+
+static AUSPOST : Breaker = Breaker::new();
+static STRIPE : Breaker = Breaker::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Service {
+	AusPost,
+	Stripe,
+}
+
+impl Service {
+	pub const ALL : [Service; 2] = [Service::AusPost, Service::Stripe];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Service::AusPost => "auspost",
+			Service::Stripe => "stripe",
+		}
+	}
+
+	/// Whether requests to the service are currently being let through
+	pub fn circuit(self) -> CircuitState { self.breaker().state() }
+
+	fn timeout(self) -> Duration {
+		match self {
+			Service::AusPost => config::timeouts().auspost,
+			Service::Stripe => config::timeouts().stripe,
+		}
+	}
+
+	fn breaker(self) -> &'static Breaker {
+		match self {
+			Service::AusPost => &AUSPOST,
+			Service::Stripe => &STRIPE,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+	/// The service is working and requests are sent
+	Closed,
+	/// The service is down and requests fail straight away
+	Open,
+	/// The cooldown has passed and the next request will be sent to see
+	/// whether the service is back
+	HalfOpen,
+}
+
+impl CircuitState {
+	pub fn name(self) -> &'static str {
+		match self {
+			CircuitState::Closed => "closed",
+			CircuitState::Open => "open",
+			CircuitState::HalfOpen => "half_open",
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamError {
+	/// The service is down so the request was not sent
+	CircuitOpen,
+	/// The service did not answer in time
+	TimedOut,
+	/// The service could not be reached or had a problem of its own
+	Unavailable,
+	/// The service answered but refused the request. Trying again will not
+	/// help.
+	Rejected,
+}
+
+/// Whether a failed request might work if it is tried again. Failures that
+/// are not transient mean the service is up, so they do not count towards
+/// its circuit breaker.
+pub trait Transient {
+	fn is_transient(&self) -> bool;
+}
+
+impl Transient for reqwest::Error {
+	fn is_transient(&self) -> bool {
+		match self.status() {
+			Some(status) => status.is_server_error() || status.as_u16() == 429,
+			None => self.is_timeout() || self.is_connect() || self.is_request(),
+		}
+	}
+}
+
+impl Transient for stripe::StripeError {
+	fn is_transient(&self) -> bool {
+		match self {
+			stripe::StripeError::Stripe(e) => e.http_status >= 500 || e.http_status == 429,
+			stripe::StripeError::ClientError(_) | stripe::StripeError::Timeout => true,
+			_ => false,
+		}
+	}
+}
+
+/// Send a request to a service. `request` is called once for each attempt.
+/// Only idempotent requests are retried, as a request that timed out may
+/// still have been carried out.
+pub async fn call<T, E, F, Fut>(
+	service : Service,
+	idempotent : bool,
+	request : F,
+) -> Result<T, UpstreamError>
+where
+	E : Transient,
+	F : FnMut() -> Fut,
+	Fut : Future<Output = Result<T, E>>,
+{
+	call_with(
+		service,
+		service.breaker(),
+		&config::upstream(),
+		service.timeout(),
+		idempotent,
+		request,
+	)
+	.await
+}
+
+/// `call` with the breaker, settings and timeout given rather than the
+/// service's own
+pub async fn call_with<T, E, F, Fut>(
+	service : Service,
+	breaker : &Breaker,
+	settings : &config::Upstream,
+	limit : Duration,
+	idempotent : bool,
+	mut request : F,
+) -> Result<T, UpstreamError>
+where
+	E : Transient,
+	F : FnMut() -> Fut,
+	Fut : Future<Output = Result<T, E>>,
+{
+	if !breaker.allow(settings) {
+		metrics::upstream_request(service, "circuit_open", Duration::from_secs(0));
+		debug!(
+			service = service.name(),
+			"upstream skipped while its circuit is open"
+		);
+		return Err(UpstreamError::CircuitOpen);
+	}
+
+	let attempts = match idempotent {
+		true => settings.retries + 1,
+		false => 1,
+	};
+	let mut backoff = settings.backoff;
+	let mut error = UpstreamError::Unavailable;
+
+	for attempt in 0..attempts {
+		if attempt > 0 {
+			sleep(backoff).await;
+			backoff = (backoff * 2).min(MAX_BACKOFF);
+		}
+
+		let span = info_span!("upstream", service = service.name(), attempt = attempt + 1);
+		let started = Instant::now();
+		let outcome = timeout(limit, request()).instrument(span.clone()).await;
+		let elapsed = started.elapsed();
+
+		match outcome {
+			Ok(Ok(result)) => {
+				metrics::upstream_request(service, "ok", elapsed);
+				info!(parent: &span, elapsed_ms = elapsed.as_millis() as u64, "upstream request");
+				breaker.succeeded();
+				return Ok(result);
+			},
+			Ok(Err(e)) if !e.is_transient() => {
+				metrics::upstream_request(service, "rejected", elapsed);
+				info!(
+					parent: &span,
+					elapsed_ms = elapsed.as_millis() as u64,
+					"upstream refused the request",
+				);
+				breaker.succeeded();
+				return Err(UpstreamError::Rejected);
+			},
+			Ok(Err(_)) => {
+				metrics::upstream_request(service, "unavailable", elapsed);
+				warn!(
+					parent: &span,
+					elapsed_ms = elapsed.as_millis() as u64,
+					"upstream unavailable",
+				);
+				error = UpstreamError::Unavailable;
+			},
+			Err(_) => {
+				metrics::upstream_request(service, "timed_out", elapsed);
+				warn!(
+					parent: &span,
+					elapsed_ms = elapsed.as_millis() as u64,
+					"upstream timed out",
+				);
+				error = UpstreamError::TimedOut;
+			},
+		}
+	}
+
+	breaker.failed(settings);
+	if breaker.state() == CircuitState::Open {
+		warn!(service = service.name(), "upstream circuit open");
+	}
+	Err(error)
+}
+
+/// Whether a service is up, from how its recent requests went
+pub struct Breaker {
+	state : Mutex<BreakerState>,
+}
+
+struct BreakerState {
+	/// Requests in a row that have failed
+	failures :   u32,
+	/// When requests can be tried again, if the service is down
+	open_until : Option<Instant>,
+	/// When the request checking if the service is back was sent. A trial
+	/// that never finished is given up on after the cooldown.
+	trial :      Option<Instant>,
+}
+
+impl Default for Breaker {
+	fn default() -> Self { Self::new() }
+}
+
+impl Breaker {
+	pub const fn new() -> Self {
+		Self {
+			state : Mutex::new(BreakerState {
+				failures :   0,
+				open_until : None,
+				trial :      None,
+			}),
+		}
+	}
+
+	pub fn state(&self) -> CircuitState {
+		let state = self.state.lock().unwrap();
+		match state.open_until {
+			None => CircuitState::Closed,
+			Some(until) if Instant::now() < until => CircuitState::Open,
+			Some(_) => CircuitState::HalfOpen,
+		}
+	}
+
+	/// Whether a request can be sent. Only one request is let through once
+	/// the cooldown has passed.
+	fn allow(&self, settings : &config::Upstream) -> bool {
+		let now = Instant::now();
+		let mut state = self.state.lock().unwrap();
+		match (state.open_until, state.trial) {
+			(None, _) => true,
+			(Some(until), _) if now < until => false,
+			(_, Some(sent)) if now < sent + settings.breaker_cooldown => false,
+			_ => {
+				state.trial = Some(now);
+				true
+			},
+		}
+	}
+
+	fn succeeded(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.failures = 0;
+		state.open_until = None;
+		state.trial = None;
+	}
+
+	fn failed(&self, settings : &config::Upstream) {
+		let mut state = self.state.lock().unwrap();
+		state.failures += 1;
+		if state.trial.is_some() || state.failures >= settings.breaker_failures {
+			state.open_until = Some(Instant::now() + settings.breaker_cooldown);
+		}
+		state.trial = None;
+	}
+}
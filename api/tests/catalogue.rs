@@ -0,0 +1,17 @@
+//! How prices are shown to customers
+
+use librainbowapi::catalogue::format_price;
+
+#[test]
+fn prices_show_dollars_and_cents() {
+	assert_eq!(format_price(2500), "A$25.00");
+	assert_eq!(format_price(5), "A$0.05");
+	assert_eq!(format_price(0), "A$0.00");
+}
+
+#[test]
+fn negative_prices_keep_their_sign() {
+	assert_eq!(format_price(-50), "-A$0.50");
+	assert_eq!(format_price(-1), "-A$0.01");
+	assert_eq!(format_price(-2550), "-A$25.50");
+}
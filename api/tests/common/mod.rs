@@ -0,0 +1,60 @@
+//! Shared by the integration tests. Tests that need MongoDB are ignored
+//! unless asked for, and each gets a database of its own on the server in
+//! `TEST_MONGODB_URL`:
+//!
+//! ```sh
+//! TEST_MONGODB_URL=mongodb://localhost:27017 cargo test -- --include-ignored
+//! ```
+
+#![allow(dead_code)]
+
+pub mod stub;
+
+use chrono::{DateTime, Utc};
+use librainbowapi::db;
+use mongodb::{
+	bson::{oid::ObjectId, Document},
+	Client, Collection, Database,
+};
+
+pub struct TestDb(pub Database);
+
+impl TestDb {
+	pub async fn new() -> Self {
+		let url = std::env::var("TEST_MONGODB_URL")
+			.ok()
+			.filter(|url| !url.is_empty())
+			.expect("TEST_MONGODB_URL must be set to run the tests that need MongoDB");
+
+		let client = Client::with_uri_str(&url)
+			.await
+			.expect("Connecting to MongoDB failed");
+		TestDb(client.database(&format!("rainbow_test_{}", ObjectId::new())))
+	}
+
+	pub fn orders(&self) -> Collection<Document> { self.0.collection("orders") }
+
+	pub async fn order(&self, id : ObjectId) -> Document {
+		self.orders()
+			.find_one(doc! { "_id": id }, None)
+			.await
+			.expect("Loading the order failed")
+			.expect("The order is missing")
+	}
+
+	pub async fn drop(self) { self.0.drop(None).await.ok(); }
+}
+
+/// An ID for a document made at the given time. Orders are found by the
+/// time in their IDs, so tests can place them anywhere in time.
+pub fn id_created_at(at : DateTime<Utc>) -> ObjectId {
+	let mut bytes = db::id_at(at).bytes();
+	bytes[4..].copy_from_slice(&ObjectId::new().bytes()[4..]);
+	ObjectId::from_bytes(bytes)
+}
+
+pub fn time(at : &str) -> DateTime<Utc> {
+	DateTime::parse_from_rfc3339(at)
+		.expect("The time is not valid")
+		.with_timezone(&Utc)
+}
@@ -0,0 +1,178 @@
+//! A local HTTP server standing in for Stripe and AusPost. Each request is
+//! answered by the function the stub was started with, and kept so tests can
+//! check what was sent. The server runs on a thread of its own, so it
+//! outlives the runtime of any one test.
+
+use rocket::tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+	runtime::Builder,
+	time::sleep,
+};
+use std::{
+	sync::{Arc, Mutex},
+	thread,
+	time::Duration,
+};
+
+#[derive(Clone, Debug)]
+pub struct Request {
+	pub method :  String,
+	/// The path without the query string
+	pub path :    String,
+	pub query :   String,
+	/// Names are lowercase
+	pub headers : Vec<(String, String)>,
+	pub body :    String,
+}
+
+impl Request {
+	pub fn header(&self, name : &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(header, _)| header == name)
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+pub struct Response {
+	pub status : u16,
+	pub body :   String,
+	/// How long to wait before answering
+	pub delay :  Duration,
+}
+
+impl Response {
+	pub fn json(status : u16, body : impl Into<String>) -> Self {
+		Response {
+			status,
+			body : body.into(),
+			delay : Duration::from_secs(0),
+		}
+	}
+
+	pub fn after(self, delay : Duration) -> Self {
+		Response {
+			delay,
+			..self
+		}
+	}
+}
+
+type Respond = dyn Fn(&Request, usize) -> Response + Send + Sync;
+
+pub struct Stub {
+	/// Where the server is listening, without a trailing slash
+	pub url : String,
+	seen :    Arc<Mutex<Vec<Request>>>,
+}
+
+impl Stub {
+	/// Start a server on a free port. `respond` is given each request and how
+	/// many requests came before it.
+	pub fn start<F>(respond : F) -> Self
+	where
+		F : Fn(&Request, usize) -> Response + Send + Sync + 'static,
+	{
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Binding the stub failed");
+		listener
+			.set_nonblocking(true)
+			.expect("Configuring the stub failed");
+		let url = format!("http://{}", listener.local_addr().unwrap());
+
+		let seen = Arc::new(Mutex::new(vec![]));
+		let respond : Arc<Respond> = Arc::new(respond);
+		let log = seen.clone();
+		thread::spawn(move || {
+			let runtime = Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.expect("Starting the stub failed");
+			runtime.block_on(async move {
+				let listener = TcpListener::from_std(listener).expect("Starting the stub failed");
+				while let Ok((stream, _)) = listener.accept().await {
+					rocket::tokio::spawn(serve(stream, log.clone(), respond.clone()));
+				}
+			});
+		});
+
+		Stub {
+			url,
+			seen,
+		}
+	}
+
+	/// Every request answered so far, oldest first
+	pub fn seen(&self) -> Vec<Request> { self.seen.lock().unwrap().clone() }
+}
+
+async fn serve(mut stream : TcpStream, seen : Arc<Mutex<Vec<Request>>>, respond : Arc<Respond>) {
+	let request = match read(&mut stream).await {
+		Some(request) => request,
+		None => return,
+	};
+
+	let count = {
+		let mut seen = seen.lock().unwrap();
+		seen.push(request.clone());
+		seen.len() - 1
+	};
+	let response = respond(&request, count);
+	sleep(response.delay).await;
+
+	let reply = format!(
+		"HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+		 close\r\n\r\n{}",
+		response.status,
+		response.body.len(),
+		response.body
+	);
+	stream.write_all(reply.as_bytes()).await.ok();
+	stream.shutdown().await.ok();
+}
+
+async fn read(stream : &mut TcpStream) -> Option<Request> {
+	let mut buffer = vec![];
+	let mut chunk = [0; 4096];
+	let end = loop {
+		if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+			break end;
+		}
+		match stream.read(&mut chunk).await.ok()? {
+			0 => return None,
+			read => buffer.extend_from_slice(&chunk[..read]),
+		}
+	};
+
+	let head = String::from_utf8_lossy(&buffer[..end]).to_string();
+	let mut lines = head.split("\r\n");
+	let mut start = lines.next()?.split(' ');
+	let method = start.next()?.to_string();
+	let target = start.next()?;
+	let (path, query) = target.split_once('?').unwrap_or((target, ""));
+	let headers : Vec<(String, String)> = lines
+		.filter_map(|line| line.split_once(':'))
+		.map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+		.collect();
+
+	let length = headers
+		.iter()
+		.find(|(name, _)| name == "content-length")
+		.and_then(|(_, value)| value.parse().ok())
+		.unwrap_or(0);
+	let mut body = buffer[end + 4..].to_vec();
+	while body.len() < length {
+		match stream.read(&mut chunk).await.ok()? {
+			0 => break,
+			read => body.extend_from_slice(&chunk[..read]),
+		}
+	}
+
+	Some(Request {
+		method,
+		path : path.to_string(),
+		query : query.to_string(),
+		headers,
+		body : String::from_utf8_lossy(&body).to_string(),
+	})
+}
@@ -0,0 +1,322 @@
+//! newOrder with a failure injected at each step. Whichever step fails, no
+//! order is left behind and no payment intent is left to be paid.
+
+#[macro_use]
+extern crate bson;
+
+mod common;
+
+use chrono::{Datelike, Utc};
+use common::{
+	stub::{Request, Response, Stub},
+	TestDb,
+};
+use futures::join;
+use librainbowapi::{
+	catalogue::SCARF,
+	creation::{self, CreateError, NewOrder},
+	db::{orders, PrimaryDb},
+	graphql::context::Context,
+	models::{Address, CollectionMethod, LineItem, Order, PaymentMethod},
+	order_number,
+};
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+use std::sync::OnceLock;
+
+static STUB : OnceLock<Stub> = OnceLock::new();
+
+/// Stripe and AusPost share one stand-in, since the Stripe client is only
+/// made once. AusPost is always down, and Stripe refuses payment intents for
+/// customers named Refused.
+fn stub() -> &'static Stub {
+	STUB.get_or_init(|| {
+		let stub = Stub::start(respond);
+		std::env::set_var("STRIPE_API_URL", format!("{}/", stub.url));
+		std::env::set_var("AUSPOST_API_URL", &stub.url);
+		std::env::set_var("AUSPOST_PAC_API", "test");
+		std::env::set_var("UPSTREAM_RETRIES", "0");
+		stub
+	})
+}
+
+fn respond(request : &Request, count : usize) -> Response {
+	let intent = |id : &str, status : &str| {
+		let intent = json!({
+			"id": id,
+			"object": "payment_intent",
+			"amount": 2500,
+			"amount_capturable": 0,
+			"amount_received": 0,
+			"capture_method": "automatic",
+			"confirmation_method": "automatic",
+			"created": 1709283600,
+			"currency": "aud",
+			"livemode": false,
+			"metadata": {},
+			"payment_method_types": ["card"],
+			"status": status,
+			"client_secret": format!("{}_secret", id),
+		});
+		Response::json(200, intent.to_string())
+	};
+
+	let path = request.path.as_str();
+	let id = path
+		.trim_start_matches("/v1/payment_intents/")
+		.trim_end_matches("/cancel");
+	match request.method.as_str() {
+		"POST" if path == "/v1/payment_intents" && request.body.contains("Refused") => {
+			Response::json(
+				400,
+				r#"{"error":{"type":"invalid_request_error","message":"Refused"}}"#,
+			)
+		},
+		"POST" if path == "/v1/payment_intents" => {
+			intent(&format!("pi_{}", count), "requires_payment_method")
+		},
+		"POST" if path.ends_with("/cancel") => intent(id, "canceled"),
+		"GET" if path.starts_with("/v1/payment_intents/") => intent(id, "requires_payment_method"),
+		_ => Response::json(500, "{}"),
+	}
+}
+
+/// The payment intents Stripe was asked to make for a customer
+fn intents_for(name : &str) -> Vec<usize> {
+	stub()
+		.seen()
+		.iter()
+		.enumerate()
+		.filter(|(_, request)| request.method == "POST" && request.path == "/v1/payment_intents")
+		.filter(|(_, request)| request.body.contains(name))
+		.map(|(count, _)| count)
+		.collect()
+}
+
+fn cancelled(pi : &str) -> bool {
+	stub()
+		.seen()
+		.iter()
+		.any(|request| request.path == format!("/v1/payment_intents/{}/cancel", pi))
+}
+
+fn context(db : &TestDb) -> Context {
+	Context {
+		connection : PrimaryDb(db.0.clone()),
+		admin :      false,
+	}
+}
+
+fn new_order(name : &str, method : CollectionMethod, key : Option<&str>) -> NewOrder {
+	let address = match method {
+		CollectionMethod::Post => Address::validate(
+			None,
+			Some("1 Pennant Hills Road".to_string()),
+			Some("Normanhurst".to_string()),
+			Some("NSW".to_string()),
+			Some("2076".to_string()),
+			None,
+		)
+		.ok(),
+		CollectionMethod::Pickup => None,
+	};
+
+	NewOrder {
+		name : name.to_string(),
+		email : format!("{}@example.com", name.to_lowercase()),
+		items : vec![LineItem {
+			variant :  SCARF.default_variant().code.to_string(),
+			quantity : 1,
+		}],
+		group : None,
+		allocations : vec![],
+		method,
+		pickup_location : None,
+		address,
+		payment : PaymentMethod::Card,
+		paid : false,
+		idempotency_key : key.map(String::from),
+	}
+}
+
+async fn order_count(db : &TestDb) -> u64 {
+	db.orders()
+		.count_documents(None, None)
+		.await
+		.expect("Counting orders failed")
+}
+
+fn pi(order : &Order) -> String {
+	order
+		.payment
+		.as_ref()
+		.and_then(|payment| payment.stripe.as_ref())
+		.map(|stripe| stripe.pi.to_owned())
+		.expect("The order has no payment intent")
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn creates_the_whole_order_in_one_write() {
+	let db = TestDb::new().await;
+	stub();
+
+	let order = match creation::create(
+		&context(&db),
+		new_order("Whole", CollectionMethod::Pickup, None),
+	)
+	.await
+	{
+		Ok(order) => order,
+		Err(e) => panic!("The order was not created: {}", e.message()),
+	};
+
+	let intents = intents_for("Whole");
+	assert_eq!(intents.len(), 1);
+	assert_eq!(pi(&order), format!("pi_{}", intents[0]));
+
+	let stored = db.order(ObjectId::parse_str(&*order.id).unwrap()).await;
+	assert_eq!(stored.get_str("number").ok(), order.number.as_deref());
+	assert_eq!(
+		stored
+			.get_document("payment")
+			.and_then(|p| p.get_document("stripe"))
+			.and_then(|s| s.get_str("pi"))
+			.ok(),
+		Some(pi(&order).as_str())
+	);
+	assert!(stored.get_document("user").is_ok());
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn postage_failure_leaves_nothing_behind() {
+	let db = TestDb::new().await;
+	stub();
+
+	let result = creation::create(
+		&context(&db),
+		new_order("Unposted", CollectionMethod::Post, None),
+	)
+	.await;
+
+	assert!(matches!(result, Err(CreateError::PostageUnavailable)));
+	assert_eq!(order_count(&db).await, 0);
+	assert!(intents_for("Unposted").is_empty());
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn number_failure_leaves_nothing_behind() {
+	let db = TestDb::new().await;
+	stub();
+
+	// The counter can not be incremented, so no number can be allocated
+	db.0.collection("counters")
+		.insert_one(
+			doc! { "_id": format!("orders-{}", Utc::now().year()), "seq": "broken" },
+			None,
+		)
+		.await
+		.expect("Inserting the counter failed");
+
+	let result = creation::create(
+		&context(&db),
+		new_order("Unnumbered", CollectionMethod::Pickup, None),
+	)
+	.await;
+
+	assert!(matches!(result, Err(CreateError::NumberUnavailable)));
+	assert_eq!(order_count(&db).await, 0);
+	assert!(intents_for("Unnumbered").is_empty());
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn payment_failure_leaves_nothing_behind() {
+	let db = TestDb::new().await;
+	stub();
+
+	let result = creation::create(
+		&context(&db),
+		new_order("Refused", CollectionMethod::Pickup, None),
+	)
+	.await;
+
+	assert!(matches!(result, Err(CreateError::PaymentError)));
+	assert_eq!(order_count(&db).await, 0);
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn insert_failure_cancels_the_payment_intent() {
+	let db = TestDb::new().await;
+	stub();
+
+	// The first number of the year is already taken, so the insert fails
+	orders::create_indexes(&db.orders()).await;
+	db.orders()
+		.insert_one(
+			doc! { "number": order_number::format(Utc::now().year(), 1) },
+			None,
+		)
+		.await
+		.expect("Inserting the order failed");
+
+	let result = creation::create(
+		&context(&db),
+		new_order("Duplicate", CollectionMethod::Pickup, None),
+	)
+	.await;
+
+	assert!(matches!(result, Err(CreateError::DatabaseError)));
+	assert_eq!(order_count(&db).await, 1);
+	let intents = intents_for("Duplicate");
+	assert_eq!(intents.len(), 1);
+	assert!(cancelled(&format!("pi_{}", intents[0])));
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn concurrent_retries_make_one_order() {
+	let db = TestDb::new().await;
+	stub();
+	orders::create_indexes(&db.orders()).await;
+
+	let context = context(&db);
+	let key = Some("concurrent-retry");
+	let (first, second) = join!(
+		creation::create(&context, new_order("Twice", CollectionMethod::Pickup, key)),
+		creation::create(&context, new_order("Twice", CollectionMethod::Pickup, key)),
+	);
+
+	// The retry either gets the order back or is told to try again
+	let mut made = vec![];
+	for result in [first, second] {
+		match result {
+			Ok(order) => made.push(order.id.to_string()),
+			Err(CreateError::InProgress) => {},
+			Err(e) => panic!("The retry failed: {}", e.message()),
+		}
+	}
+	assert!(!made.is_empty());
+	made.dedup();
+	assert_eq!(made.len(), 1);
+	assert_eq!(order_count(&db).await, 1);
+	assert_eq!(intents_for("Twice").len(), 1);
+
+	// A retry once the first request has finished gets the same order
+	let retry =
+		match creation::create(&context, new_order("Twice", CollectionMethod::Pickup, key)).await {
+			Ok(order) => order,
+			Err(e) => panic!("The retry failed: {}", e.message()),
+		};
+	assert_eq!(retry.id.to_string(), made[0]);
+	assert_eq!(intents_for("Twice").len(), 1);
+	db.drop().await;
+}
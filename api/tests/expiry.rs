@@ -0,0 +1,140 @@
+#[macro_use]
+extern crate bson;
+
+mod common;
+
+use chrono::{DateTime, Duration, Utc};
+use common::{id_created_at, time, TestDb};
+use librainbowapi::{
+	config::Expiry,
+	expiry,
+	models::{OrderStatus, PaymentMethod},
+};
+use mongodb::bson::{oid::ObjectId, Bson};
+
+const NUMBER : &str = "RS-2024-00017";
+
+fn settings(remind : bool, dry_run : bool) -> Expiry {
+	Expiry {
+		expire_after : Duration::hours(72),
+		remind_after : Some(Duration::hours(24)).filter(|_| remind),
+		interval : std::time::Duration::from_secs(60),
+		dry_run,
+	}
+}
+
+fn created() -> DateTime<Utc> { time("2024-03-01T09:00:00Z") }
+
+/// An unpaid order with no payment intent, so nothing is sent to Stripe
+async fn unpaid_order(db : &TestDb, method : PaymentMethod) -> ObjectId {
+	let id = id_created_at(created());
+	db.orders()
+		.insert_one(
+			doc! {
+				"_id": id,
+				"number": NUMBER,
+				"quantity": 2,
+				"user": { "name": "Sam Citizen", "email": "sam@example.com" },
+				"method": 1,
+				"payment": { "method": method.code() },
+				"status": OrderStatus::Unpaid.code(),
+				"created_at": Bson::DateTime(created().into()),
+				"history": [],
+			},
+			None,
+		)
+		.await
+		.expect("Inserting the order failed");
+	id
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn nothing_happens_before_the_reminder_is_due() {
+	let db = TestDb::new().await;
+	unpaid_order(&db, PaymentMethod::Card).await;
+
+	let now = created() + Duration::hours(24) - Duration::minutes(1);
+	let report = expiry::run(&db.orders(), &settings(true, true), now).await;
+
+	assert!(report.is_empty());
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn reminds_between_the_reminder_and_expiry() {
+	let db = TestDb::new().await;
+	unpaid_order(&db, PaymentMethod::Card).await;
+
+	for now in [
+		created() + Duration::hours(24) + Duration::minutes(1),
+		created() + Duration::hours(72) - Duration::minutes(1),
+	] {
+		let report = expiry::run(&db.orders(), &settings(true, true), now).await;
+		assert_eq!(report.reminded, vec![NUMBER.to_string()]);
+		assert!(report.expired.is_empty());
+	}
+
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn expires_once_the_expiry_has_passed() {
+	let db = TestDb::new().await;
+	let id = unpaid_order(&db, PaymentMethod::Card).await;
+
+	let before = created() + Duration::hours(72) - Duration::minutes(1);
+	let report = expiry::run(&db.orders(), &settings(false, false), before).await;
+	assert!(report.is_empty());
+	assert_eq!(
+		db.order(id).await.get_str("status").ok(),
+		Some(OrderStatus::Unpaid.code())
+	);
+
+	let after = created() + Duration::hours(72) + Duration::minutes(1);
+	let report = expiry::run(&db.orders(), &settings(false, false), after).await;
+	assert_eq!(report.expired, vec![NUMBER.to_string()]);
+
+	let order = db.order(id).await;
+	assert_eq!(
+		order.get_str("status").ok(),
+		Some(OrderStatus::Expired.code())
+	);
+	assert_eq!(order.get_array("history").map(Vec::len).ok(), Some(1));
+
+	// Nothing is left to expire on the next run
+	let report = expiry::run(&db.orders(), &settings(false, false), after).await;
+	assert!(report.is_empty());
+
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn dry_run_changes_nothing() {
+	let db = TestDb::new().await;
+	let id = unpaid_order(&db, PaymentMethod::Card).await;
+	let stored = db.order(id).await;
+
+	let now = created() + Duration::hours(72) + Duration::minutes(1);
+	let report = expiry::run(&db.orders(), &settings(true, true), now).await;
+
+	assert_eq!(report.expired, vec![NUMBER.to_string()]);
+	assert_eq!(db.order(id).await, stored);
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn orders_paid_outside_the_site_are_left_alone() {
+	let db = TestDb::new().await;
+	unpaid_order(&db, PaymentMethod::BankTransfer).await;
+
+	let now = created() + Duration::hours(72) + Duration::minutes(1);
+	let report = expiry::run(&db.orders(), &settings(true, false), now).await;
+
+	assert!(report.is_empty());
+	db.drop().await;
+}
@@ -0,0 +1,88 @@
+#[macro_use]
+extern crate bson;
+
+use librainbowapi::{config::Sender, db::FromDoc, labels, models::Order};
+use mongodb::bson::Document;
+
+/// Rendered from the orders below. Labels are checked by eye before this is
+/// replaced; set `UPDATE_SNAPSHOTS` to write a new one.
+const SNAPSHOT : &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots/labels.pdf");
+
+fn sender() -> Sender {
+	Sender {
+		name :      "Normanhurst Rover Crew".to_string(),
+		street :    "1 Example Road".to_string(),
+		town :      "HORNSBY".to_string(),
+		state :     "NSW".to_string(),
+		post_code : "2077".to_string(),
+	}
+}
+
+fn order(
+	number : &str,
+	quantity : i32,
+	user : Document,
+	address : Document,
+	postage : &str,
+) -> Order {
+	Order::from_doc(doc! {
+		"number": number,
+		"quantity": quantity,
+		"items": [{ "variant": "STANDARD", "quantity": quantity }],
+		"user": user,
+		"address": address,
+		"method": 0,
+		"postage": { "code": "POSTAGE", "name": postage, "price": 1000_i64 },
+		"status": "PAID",
+	})
+}
+
+fn orders() -> Vec<Order> {
+	vec![
+		order(
+			"RS-2024-00042",
+			3,
+			doc! { "name": "Alex Citizen", "email": "alex@example.com" },
+			doc! {
+				"apartment": "4",
+				"street": "12 Example Street",
+				"town": "Hornsby",
+				"state": "NSW",
+				"post_code": "2077",
+				"country": "AU",
+			},
+			"Parcel Post",
+		),
+		// Overseas, with accents and brackets that have to be escaped
+		order(
+			"RS-2024-00043",
+			1,
+			doc! { "name": "Zoë Müller", "email": "zoe@example.fr" },
+			doc! {
+				"street": "5 Rue de l'Exemple (Bâtiment B)",
+				"town": "Lyon",
+				"region": "Auvergne-Rhône-Alpes",
+				"post_code": "69001",
+				"country": "FR",
+			},
+			"International Standard",
+		),
+	]
+}
+
+#[test]
+fn labels_match_the_snapshot() {
+	let rendered = labels::render(&orders(), &sender());
+
+	if std::env::var("UPDATE_SNAPSHOTS").is_ok() {
+		std::fs::write(SNAPSHOT, &rendered).expect("Writing the snapshot failed");
+		return;
+	}
+
+	let snapshot = std::fs::read(SNAPSHOT).expect("Reading the snapshot failed");
+	assert!(
+		rendered == snapshot,
+		"The labels no longer match tests/snapshots/labels.pdf. Rendered:\n{}",
+		String::from_utf8_lossy(&rendered)
+	);
+}
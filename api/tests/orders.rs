@@ -0,0 +1,76 @@
+#[macro_use]
+extern crate bson;
+
+mod common;
+
+use common::TestDb;
+use librainbowapi::{
+	db::orders::{self as OrderQuery, OrderFilter, OrderSort},
+	models::OrderStatus,
+};
+use mongodb::bson::{oid::ObjectId, Document};
+
+fn order(number : &str, email : &str) -> Document {
+	doc! {
+		"_id": ObjectId::new(),
+		"number": number,
+		"quantity": 1,
+		"user": { "name": "Sam Citizen", "email": email },
+		"method": 1,
+		"status": OrderStatus::Unpaid.code(),
+		"history": [],
+	}
+}
+
+async fn numbers(db : &TestDb, email : &str) -> Vec<String> {
+	let filter = OrderFilter {
+		email : Some(email.to_string()),
+		..Default::default()
+	};
+	let page = OrderQuery::page(db.orders(), &filter, &OrderSort::default(), None, None)
+		.await
+		.unwrap_or_else(|_| panic!("Loading orders failed"));
+
+	let mut numbers : Vec<String> = page
+		.edges
+		.into_iter()
+		.filter_map(|edge| edge.node.number)
+		.collect();
+	numbers.sort();
+	numbers
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn email_filter_ignores_case_for_old_and_new_orders() {
+	let db = TestDb::new().await;
+
+	// Stored before lowercased emails were kept
+	db.orders()
+		.insert_one(order("RS-2024-00001", "Sam@Example.com"), None)
+		.await
+		.expect("Inserting the order failed");
+
+	let mut new = order("RS-2024-00002", "sam@example.com");
+	new.get_document_mut("user")
+		.unwrap()
+		.insert("email_lower", OrderQuery::email_key(" SAM@example.com"));
+	let other = order("RS-2024-00003", "sam@example.com.au");
+	db.orders()
+		.insert_many(vec![new, other], None)
+		.await
+		.expect("Inserting the orders failed");
+	OrderQuery::backfill_email_keys(&db.orders()).await;
+
+	assert_eq!(
+		numbers(&db, " SAM@EXAMPLE.COM ").await,
+		vec!["RS-2024-00001", "RS-2024-00002"]
+	);
+	assert_eq!(
+		numbers(&db, "sam@example.com.au").await,
+		vec!["RS-2024-00003"]
+	);
+	assert!(numbers(&db, "sam@example").await.is_empty());
+
+	db.drop().await;
+}
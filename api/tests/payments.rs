@@ -0,0 +1,251 @@
+//! Orders are marked as paid once Stripe says they have been, including
+//! orders from before statuses were stored, whether Stripe is asked or tells
+//! us through the webhook.
+
+#[macro_use]
+extern crate bson;
+
+mod common;
+
+use chrono::Utc;
+use common::{
+	stub::{Request, Response, Stub},
+	TestDb,
+};
+use hmac::{Hmac, Mac};
+use librainbowapi::{
+	checkout::{self, StripeSignature},
+	db::FromDoc,
+	models::{Order, OrderStatus, PaymentMethod},
+};
+use mongodb::bson::{oid::ObjectId, Document};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+const WEBHOOK_SECRET : &str = "whsec_test";
+
+static STUB : OnceLock<Stub> = OnceLock::new();
+
+/// Stripe, where payment intents ending in `_paid` have been paid and every
+/// other one is waiting for the customer. Every Checkout Session has been
+/// paid with `pi_checkout_paid`.
+fn stub() -> &'static Stub {
+	STUB.get_or_init(|| {
+		let stub = Stub::start(respond);
+		std::env::set_var("STRIPE_API_URL", format!("{}/", stub.url));
+		std::env::set_var("UPSTREAM_RETRIES", "0");
+		std::env::set_var("STRIPE_WEBHOOK_SECRET", WEBHOOK_SECRET);
+		stub
+	})
+}
+
+fn intent(id : &str, status : &str) -> Response {
+	let received = match status {
+		"succeeded" => 2500,
+		_ => 0,
+	};
+	let intent = json!({
+		"id": id,
+		"object": "payment_intent",
+		"amount": 2500,
+		"amount_capturable": 0,
+		"amount_received": received,
+		"capture_method": "automatic",
+		"confirmation_method": "automatic",
+		"created": 1709283600,
+		"currency": "aud",
+		"livemode": false,
+		"metadata": {},
+		"payment_method_types": ["card"],
+		"status": status,
+	});
+	Response::json(200, intent.to_string())
+}
+
+/// A Checkout Session for an order, paid for with `pi_checkout_paid`
+fn session(id : &str, order : &str) -> Value {
+	json!({
+		"id": id,
+		"object": "checkout.session",
+		"automatic_tax": { "enabled": false, "status": null },
+		"client_reference_id": order,
+		"created": 1709283600,
+		"custom_fields": [],
+		"custom_text": { "shipping_address": null, "submit": null },
+		"expires_at": 1709370000,
+		"livemode": false,
+		"metadata": {},
+		"mode": "payment",
+		"payment_intent": "pi_checkout_paid",
+		"payment_method_types": ["card"],
+		"payment_status": "paid",
+		"shipping_options": [],
+		"status": "complete",
+		"success_url": "https://example.com/paid",
+	})
+}
+
+fn respond(request : &Request, _ : usize) -> Response {
+	let path = request.path.as_str();
+	if let Some(id) = path.strip_prefix("/v1/checkout/sessions/") {
+		// The order is only needed by the webhook event
+		return Response::json(200, session(id, "").to_string());
+	}
+
+	let id = path
+		.trim_start_matches("/v1/payment_intents/")
+		.trim_end_matches("/cancel");
+	match request.method.as_str() {
+		"POST" if path.ends_with("/cancel") => intent(id, "canceled"),
+		"GET" if id.ends_with("_paid") => intent(id, "succeeded"),
+		"GET" if path.starts_with("/v1/payment_intents/") => intent(id, "requires_payment_method"),
+		_ => Response::json(500, "{}"),
+	}
+}
+
+/// A `checkout.session.completed` event and the signature Stripe would send
+/// it with
+fn completed_event(session : Value) -> (String, StripeSignature) {
+	let event = json!({
+		"id": "evt_test",
+		"object": "event",
+		"api_version": "2023-10-16",
+		"created": 1709283600,
+		"data": { "object": session },
+		"livemode": false,
+		"pending_webhooks": 1,
+		"request": null,
+		"type": "checkout.session.completed",
+	})
+	.to_string();
+
+	let timestamp = Utc::now().timestamp();
+	let mut mac = Hmac::<Sha256>::new_from_slice(WEBHOOK_SECRET.as_bytes())
+		.expect("The webhook secret is not a valid key");
+	mac.update(format!("{}.{}", timestamp, event).as_bytes());
+	let signature = hex::encode(mac.finalize().into_bytes());
+	(
+		event,
+		StripeSignature(format!("t={},v1={}", timestamp, signature)),
+	)
+}
+
+/// An order paid for by card from before statuses were stored
+fn legacy_order(pi : &str) -> Document {
+	doc! {
+		"_id": ObjectId::new(),
+		"quantity": 1,
+		"user": { "name": "Sam Citizen", "email": "sam@example.com" },
+		"method": 1,
+		"payment": {
+			"method": PaymentMethod::Card.code(),
+			"stripe": { "pi": pi },
+		},
+	}
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn orders_without_a_status_are_marked_paid() {
+	let db = TestDb::new().await;
+	stub();
+
+	let document = legacy_order("pi_legacy_paid");
+	let id = document.get_object_id("_id").unwrap();
+	db.orders()
+		.insert_one(document.clone(), None)
+		.await
+		.expect("Inserting the order failed");
+
+	let mut order = Order::from_doc(document);
+	assert_eq!(order.status, OrderStatus::Unpaid);
+	order.refresh_status(&db.orders()).await;
+	assert_eq!(order.status, OrderStatus::Paid);
+
+	let stored = db.order(id).await;
+	assert_eq!(
+		stored.get_str("status").ok(),
+		Some(OrderStatus::Paid.code())
+	);
+	assert_eq!(
+		stored
+			.get_document("payment")
+			.and_then(|payment| payment.get_i64("amount"))
+			.ok(),
+		Some(2500)
+	);
+
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn unpaid_orders_without_a_status_are_left_alone() {
+	let db = TestDb::new().await;
+	stub();
+
+	let document = legacy_order("pi_legacy_waiting");
+	let id = document.get_object_id("_id").unwrap();
+	db.orders()
+		.insert_one(document.clone(), None)
+		.await
+		.expect("Inserting the order failed");
+
+	let mut order = Order::from_doc(document);
+	order.refresh_status(&db.orders()).await;
+	assert_eq!(order.status, OrderStatus::Unpaid);
+	assert!(db.order(id).await.get("status").is_none());
+
+	db.drop().await;
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn checkout_webhook_pays_orders_without_a_status() {
+	let db = TestDb::new().await;
+	stub();
+
+	let mut document = legacy_order("pi_legacy_original");
+	document
+		.get_document_mut("payment")
+		.and_then(|payment| payment.get_document_mut("stripe"))
+		.unwrap()
+		.insert("checkout_session", "cs_legacy");
+	let id = document.get_object_id("_id").unwrap();
+	db.orders()
+		.insert_one(document, None)
+		.await
+		.expect("Inserting the order failed");
+
+	let (payload, signature) = completed_event(session("cs_legacy", &id.to_hex()));
+	if checkout::handle_event(&db.orders(), &payload, &signature)
+		.await
+		.is_err()
+	{
+		panic!("The webhook event was rejected");
+	}
+
+	let stored = db.order(id).await;
+	assert_eq!(
+		stored.get_str("status").ok(),
+		Some(OrderStatus::Paid.code())
+	);
+	assert_eq!(
+		stored
+			.get_document("payment")
+			.and_then(|payment| payment.get_document("stripe"))
+			.and_then(|stripe| stripe.get_str("pi"))
+			.ok(),
+		Some("pi_checkout_paid")
+	);
+
+	// The checkout payment is kept and the original intent is cancelled
+	let seen = stub().seen();
+	assert!(!seen.iter().any(|request| request.path == "/v1/refunds"));
+	assert!(seen
+		.iter()
+		.any(|request| request.path == "/v1/payment_intents/pi_legacy_original/cancel"));
+
+	db.drop().await;
+}
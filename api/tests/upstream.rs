@@ -0,0 +1,149 @@
+//! Retries, timeouts and the circuit breaker, against a local server standing
+//! in for an upstream service
+
+mod common;
+
+use common::stub::{Response, Stub};
+use librainbowapi::{
+	config,
+	upstream::{self, Breaker, CircuitState, Service, UpstreamError},
+};
+use std::time::Duration;
+
+fn settings(retries : u32) -> config::Upstream {
+	config::Upstream {
+		retries,
+		backoff : Duration::from_millis(1),
+		breaker_failures : 2,
+		breaker_cooldown : Duration::from_millis(200),
+	}
+}
+
+/// Fetch the stub's front page through the upstream layer
+async fn fetch(
+	stub : &Stub,
+	breaker : &Breaker,
+	settings : &config::Upstream,
+	idempotent : bool,
+) -> Result<String, UpstreamError> {
+	let client = reqwest::Client::new();
+	upstream::call_with(
+		Service::AusPost,
+		breaker,
+		settings,
+		Duration::from_millis(100),
+		idempotent,
+		|| {
+			let request = client.get(&stub.url);
+			async move { request.send().await?.error_for_status()?.text().await }
+		},
+	)
+	.await
+}
+
+#[rocket::async_test]
+async fn idempotent_requests_are_retried_until_they_work() {
+	let stub = Stub::start(|_, count| match count {
+		0 | 1 => Response::json(503, "{}"),
+		_ => Response::json(200, "ok"),
+	});
+	let breaker = Breaker::new();
+
+	let result = fetch(&stub, &breaker, &settings(2), true).await;
+
+	assert_eq!(result, Ok("ok".to_string()));
+	assert_eq!(stub.seen().len(), 3);
+	assert_eq!(breaker.state(), CircuitState::Closed);
+}
+
+#[rocket::async_test]
+async fn retries_stop_after_the_limit() {
+	let stub = Stub::start(|_, _| Response::json(503, "{}"));
+	let breaker = Breaker::new();
+
+	let result = fetch(&stub, &breaker, &settings(2), true).await;
+
+	assert_eq!(result, Err(UpstreamError::Unavailable));
+	assert_eq!(stub.seen().len(), 3);
+}
+
+#[rocket::async_test]
+async fn other_requests_are_only_sent_once() {
+	let stub = Stub::start(|_, _| Response::json(503, "{}"));
+	let breaker = Breaker::new();
+
+	let result = fetch(&stub, &breaker, &settings(2), false).await;
+
+	assert_eq!(result, Err(UpstreamError::Unavailable));
+	assert_eq!(stub.seen().len(), 1);
+}
+
+#[rocket::async_test]
+async fn refused_requests_are_not_retried_or_counted_against_the_service() {
+	let stub = Stub::start(|_, _| Response::json(400, "{}"));
+	let breaker = Breaker::new();
+
+	for _ in 0..3 {
+		let result = fetch(&stub, &breaker, &settings(2), true).await;
+		assert_eq!(result, Err(UpstreamError::Rejected));
+	}
+
+	assert_eq!(stub.seen().len(), 3);
+	assert_eq!(breaker.state(), CircuitState::Closed);
+}
+
+#[rocket::async_test]
+async fn slow_requests_time_out() {
+	let stub = Stub::start(|_, _| Response::json(200, "ok").after(Duration::from_millis(500)));
+	let breaker = Breaker::new();
+
+	let result = fetch(&stub, &breaker, &settings(0), true).await;
+
+	assert_eq!(result, Err(UpstreamError::TimedOut));
+}
+
+#[rocket::async_test]
+async fn breaker_opens_and_lets_one_request_through_after_the_cooldown() {
+	// Down for the first two requests, then back
+	let stub = Stub::start(|_, count| match count {
+		0 | 1 => Response::json(503, "{}"),
+		_ => Response::json(200, "ok"),
+	});
+	let breaker = Breaker::new();
+	let settings = settings(0);
+
+	for _ in 0..2 {
+		let result = fetch(&stub, &breaker, &settings, true).await;
+		assert_eq!(result, Err(UpstreamError::Unavailable));
+	}
+	assert_eq!(breaker.state(), CircuitState::Open);
+
+	// Nothing is sent while the circuit is open
+	let result = fetch(&stub, &breaker, &settings, true).await;
+	assert_eq!(result, Err(UpstreamError::CircuitOpen));
+	assert_eq!(stub.seen().len(), 2);
+
+	rocket::tokio::time::sleep(settings.breaker_cooldown).await;
+	assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+	let result = fetch(&stub, &breaker, &settings, true).await;
+	assert_eq!(result, Ok("ok".to_string()));
+	assert_eq!(breaker.state(), CircuitState::Closed);
+}
+
+#[rocket::async_test]
+async fn failed_trial_opens_the_breaker_again() {
+	let stub = Stub::start(|_, _| Response::json(503, "{}"));
+	let breaker = Breaker::new();
+	let settings = settings(0);
+
+	for _ in 0..2 {
+		fetch(&stub, &breaker, &settings, true).await.ok();
+	}
+	rocket::tokio::time::sleep(settings.breaker_cooldown).await;
+
+	let result = fetch(&stub, &breaker, &settings, true).await;
+	assert_eq!(result, Err(UpstreamError::Unavailable));
+	assert_eq!(breaker.state(), CircuitState::Open);
+	assert_eq!(stub.seen().len(), 3);
+}